@@ -0,0 +1,101 @@
+use ndarray::Array1;
+use rand::prelude::*;
+use std::time::{Duration, Instant};
+use trackfish::app::controller::{genres_dist_from_vec, INDEX_CHECKS_PER_CANDIDATE, RADIO_CANDIDATES};
+use trackfish::app::index::{feature_vector, KdTree};
+use trackfish::app::normalize::FeatureStats;
+use trackfish::app::settings::RadioSettings;
+use trackfish::app::track::{TrackId, TrackInfo};
+
+/// Compares picking radio candidates from the index against scoring every track
+/// Run with `cargo run --release --example radio_index -- <track count>`
+fn main() {
+    let count = std::env::args().nth(1).and_then(|c| c.parse().ok()).unwrap_or(60000);
+    let queries = 100;
+    let top = 50;
+
+    let mut rng = StdRng::seed_from_u64(1);
+    let tracks = fake_library(&mut rng, count);
+    let settings = RadioSettings::default();
+    let stats = FeatureStats::from_tracks(&tracks);
+
+    let started = Instant::now();
+    let tree = KdTree::new(
+        tracks
+            .iter()
+            .enumerate()
+            .map(|(id, info)| (id, feature_vector(info, &settings, &stats)))
+            .collect(),
+    );
+    println!("Built index over {count} tracks in {:?}", started.elapsed());
+
+    let mut brute_time = Duration::ZERO;
+    let mut index_time = Duration::ZERO;
+    let mut recall = 0.0;
+
+    for _ in 0..queries {
+        let space = &tracks[rng.gen_range(0..count)];
+
+        let started = Instant::now();
+        let brute = best_scored(&tracks, 0..count, space, &settings, &stats, top);
+        brute_time += started.elapsed();
+
+        let started = Instant::now();
+        let query = feature_vector(space, &settings, &stats);
+        let candidates = tree.nearest(&query, RADIO_CANDIDATES, RADIO_CANDIDATES * INDEX_CHECKS_PER_CANDIDATE);
+        let indexed = best_scored(&tracks, candidates.into_iter().map(|(id, _)| id), space, &settings, &stats, top);
+        index_time += started.elapsed();
+
+        recall += indexed.iter().filter(|id| brute.contains(id)).count() as f32 / top as f32;
+    }
+
+    println!("Brute force: {:?} per pick", brute_time / queries);
+    println!("Index:       {:?} per pick", index_time / queries);
+    println!("Top {top} found by the index: {:.1}%", recall / queries as f32 * 100.0);
+}
+
+/// Scores the given tracks the same way the radio does, returning the best ones
+fn best_scored(
+    tracks: &[TrackInfo],
+    ids: impl Iterator<Item = TrackId>,
+    space: &TrackInfo,
+    settings: &RadioSettings,
+    stats: &FeatureStats,
+    top: usize,
+) -> Vec<TrackId> {
+    let mut scores: Vec<(TrackId, f32)> =
+        ids.map(|id| (id, genres_dist_from_vec(&tracks[id], space, settings, stats))).collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scores.into_iter().take(top).map(|(id, _)| id).collect()
+}
+
+/// Makes up a library of tracks in loose clusters, like albums and genres give real ones
+fn fake_library(rng: &mut StdRng, count: usize) -> Vec<TrackInfo> {
+    let mut random = |len: usize, spread: f32| Array1::from_iter((0..len).map(|_| rng.gen_range(-spread..spread)));
+    let centres: Vec<TrackInfo> = (0..200)
+        .map(|_| TrackInfo {
+            mfcc: random(13, 50.0),
+            chroma: random(12, 1.0).mapv(f32::abs),
+            spectral: random(6, 1000.0).mapv(f32::abs),
+            energy: random(1, 0.3)[0].abs(),
+            bpm: 60.0 + random(1, 60.0)[0].abs() * 2.0,
+            zcr: random(1, 0.2)[0].abs(),
+            ..TrackInfo::default()
+        })
+        .collect();
+
+    (0..count)
+        .map(|i| {
+            let centre = &centres[i % centres.len()];
+            TrackInfo {
+                mfcc: &centre.mfcc + &random(13, 10.0),
+                chroma: (&centre.chroma + &random(12, 0.2)).mapv(f32::abs),
+                spectral: (&centre.spectral + &random(6, 200.0)).mapv(f32::abs),
+                energy: centre.energy,
+                bpm: centre.bpm + random(1, 5.0)[0],
+                zcr: centre.zcr,
+                ..TrackInfo::default()
+            }
+        })
+        .collect()
+}
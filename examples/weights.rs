@@ -1,8 +1,9 @@
 use trackfish::app::{load_tracks, settings::Settings, MusicController};
 
 fn main() {
-    let dir = Settings::load().directory;
-    let tracks = load_tracks(&dir).unwrap();
+    let settings = Settings::load();
+    let (tracks, _) = load_tracks(&settings.libraries).unwrap();
+    let dir = settings.main_directory();
     println!("{}", dir);
     let mut controller = MusicController::new(tracks, dir);
 
@@ -58,13 +58,14 @@ pub fn extract_chroma(audio_data: &[f32], sample_rate: u32) -> Array1<f32> {
     }
 
     let mut mean_chroma = Array1::zeros(12);
+    if chroma_vectors.is_empty() {
+        return mean_chroma;
+    }
 
-    for i in 0..12 {
-        for chroma in &chroma_vectors {
-            mean_chroma[i] += chroma[i]
-        }
-        mean_chroma /= chroma_vectors.len() as f32;
+    for chroma in &chroma_vectors {
+        mean_chroma += chroma;
     }
+    mean_chroma /= chroma_vectors.len() as f32;
 
     mean_chroma
 }
@@ -0,0 +1,121 @@
+use ndarray::Array1;
+
+/// Krumhansl-Kessler key profiles, starting from the tonic
+const MAJOR_PROFILE: [f32; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f32; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+const PITCH_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Estimates the key of a track by matching its average chroma against the profile of each key
+/// Keys are the pitch class of the tonic starting from C, plus 12 for minor keys
+/// The confidence is the correlation with the best matching profile, 0 if nothing matched
+pub fn estimate_key(chroma: &Array1<f32>) -> (i32, f32) {
+    if chroma.len() != 12 || chroma.iter().any(|c| !c.is_finite()) {
+        return (0, 0.0);
+    }
+
+    let chroma = chroma.to_vec();
+    let mut best = (0, 0.0);
+    for key in 0..24 {
+        let profile = if key < 12 { &MAJOR_PROFILE } else { &MINOR_PROFILE };
+        let rotated: Vec<f32> = (0..12).map(|pitch| profile[(pitch + 24 - key) % 12]).collect();
+        let correlation = correlation(&chroma, &rotated);
+
+        if correlation > best.1 {
+            best = (key as i32, correlation);
+        }
+    }
+
+    best
+}
+
+/// Pearson correlation between two equally long lists
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / a.len() as f32;
+    let mean_b = b.iter().sum::<f32>() / b.len() as f32;
+
+    let (mut covariance, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b) {
+        covariance += (a - mean_a) * (b - mean_b);
+        var_a += (a - mean_a).powi(2);
+        var_b += (b - mean_b).powi(2);
+    }
+
+    if var_a == 0.0 || var_b == 0.0 {
+        return 0.0;
+    }
+
+    covariance / (var_a * var_b).sqrt()
+}
+
+/// Name of a key, like "A minor"
+pub fn key_name(key: i32) -> String {
+    let mode = if key >= 12 { "minor" } else { "major" };
+    format!("{} {mode}", PITCH_NAMES[key.rem_euclid(12) as usize])
+}
+
+/// Position of a key on the circle of fifths, with minor keys sharing their relative major's
+fn fifths_position(key: i32) -> i32 {
+    let tonic = key.rem_euclid(12);
+    let major = if key >= 12 { (tonic + 3) % 12 } else { tonic };
+    (major * 7) % 12
+}
+
+/// Steps between two keys around the circle of fifths, plus one for changing between major
+/// and minor
+pub fn key_distance(a: i32, b: i32) -> i32 {
+    let steps = (fifths_position(a) - fifths_position(b)).rem_euclid(12);
+    steps.min(12 - steps) + ((a >= 12) != (b >= 12)) as i32
+}
+
+/// How well two keys go together, from 1 for the same key down to 0 for opposite keys
+pub fn key_similarity(a: i32, b: i32) -> f32 {
+    1.0 - key_distance(a, b) as f32 / 7.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chroma_of(notes: &[usize]) -> Array1<f32> {
+        let mut chroma = Array1::from_elem(12, 0.01);
+        for note in notes {
+            chroma[*note] += 1.0;
+        }
+        chroma
+    }
+
+    #[test]
+    fn detects_triads() {
+        // C E G
+        assert_eq!(estimate_key(&chroma_of(&[0, 4, 7])).0, 0);
+        // A C E
+        assert_eq!(estimate_key(&chroma_of(&[9, 0, 4])).0, 12 + 9);
+        // G B D
+        assert_eq!(estimate_key(&chroma_of(&[7, 11, 2])).0, 7);
+    }
+
+    #[test]
+    fn silence_has_no_confidence() {
+        assert_eq!(estimate_key(&Array1::zeros(12)), (0, 0.0));
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(key_name(0), "C major");
+        assert_eq!(key_name(12 + 9), "A minor");
+        assert_eq!(key_name(6), "F# major");
+    }
+
+    #[test]
+    fn distances() {
+        // Relative minor
+        assert_eq!(key_distance(0, 12 + 9), 1);
+        // Dominant and subdominant
+        assert_eq!(key_distance(0, 7), 1);
+        assert_eq!(key_distance(0, 5), 1);
+        // Tritone
+        assert_eq!(key_distance(0, 6), 6);
+        assert_eq!(key_distance(12 + 2, 12 + 2), 0);
+    }
+}
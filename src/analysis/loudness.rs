@@ -0,0 +1,318 @@
+use crate::app::audio::open_decoder;
+use crate::app::track::get_text;
+use rodio::Source;
+use std::f64::consts::PI;
+
+/// Loudness that ReplayGain 2.0 normalizes tracks to, in LUFS
+pub const REFERENCE_LOUDNESS: f32 = -18.0;
+
+/// Length of the gating blocks in 100ms steps
+const BLOCK_STEPS: usize = 4;
+/// Taps per phase of the true peak interpolation filter
+const PEAK_TAPS: usize = 12;
+/// How many times the signal is oversampled when looking for true peaks
+const OVERSAMPLE: usize = 4;
+
+/// Measured or tagged loudness of a track or album
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub integrated: f32,
+    /// True peak as a linear sample value, where 1.0 is full scale
+    pub peak: f32,
+}
+
+impl Loudness {
+    /// Gain in dB needed to bring this loudness to the reference level
+    pub fn gain_db(&self) -> f32 {
+        REFERENCE_LOUDNESS - self.integrated
+    }
+
+    /// Combines the loudness of several tracks into the loudness of them played back to back,
+    /// weighting each track by its length
+    pub fn combine(tracks: impl IntoIterator<Item = (Loudness, f64)>) -> Option<Loudness> {
+        let mut power = 0.0;
+        let mut total_len = 0.0;
+        let mut peak: f32 = 0.0;
+
+        for (loudness, len) in tracks {
+            power += 10f64.powf(loudness.integrated as f64 / 10.0) * len;
+            total_len += len;
+            peak = peak.max(loudness.peak);
+        }
+
+        if total_len <= 0.0 {
+            return None;
+        }
+
+        Some(Loudness {
+            integrated: (10.0 * (power / total_len).log10()) as f32,
+            peak,
+        })
+    }
+}
+
+/// Measures the integrated loudness (EBU R128 / ITU-R BS.1770) and true peak of a whole file
+pub fn measure_loudness(file_path: &str) -> anyhow::Result<Loudness> {
+    let source = open_decoder(file_path)?;
+    let channels = source.channels() as usize;
+    let sample_rate = source.sample_rate() as f64;
+
+    Ok(samples_loudness(source, channels, sample_rate))
+}
+
+/// Measures the loudness and true peak of interleaved samples
+fn samples_loudness(samples: impl IntoIterator<Item = f32>, channels: usize, sample_rate: f64) -> Loudness {
+    let mut filters: Vec<KWeighting> = (0..channels).map(|_| KWeighting::new(sample_rate)).collect();
+    let mut peaks: Vec<PeakMeter> = (0..channels).map(|_| PeakMeter::new()).collect();
+    let weights: Vec<f64> = (0..channels).map(|c| channel_weight(c, channels)).collect();
+
+    let step_len = (sample_rate / 10.0) as usize * channels;
+    let mut steps = Vec::new();
+    let mut step_power = 0.0;
+    let mut step_samples = 0;
+
+    for (i, sample) in samples.into_iter().enumerate() {
+        let channel = i % channels;
+        let filtered = filters[channel].process(sample as f64);
+        step_power += weights[channel] * filtered * filtered;
+        peaks[channel].process(sample);
+
+        step_samples += 1;
+        if step_samples == step_len {
+            steps.push(step_power / (step_len / channels) as f64);
+            step_power = 0.0;
+            step_samples = 0;
+        }
+    }
+
+    // Overlapping 400ms blocks, each made of four 100ms steps
+    let blocks: Vec<f64> = steps
+        .windows(BLOCK_STEPS)
+        .map(|w| w.iter().sum::<f64>() / BLOCK_STEPS as f64)
+        .collect();
+
+    let peak = peaks.iter().map(|p| p.peak).fold(0.0, f32::max);
+
+    Loudness {
+        integrated: gated_loudness(&blocks) as f32,
+        peak,
+    }
+}
+
+/// Reads the ReplayGain tags of a file when it has them, returning the track and album loudness
+pub fn tagged_loudness(file_path: &str) -> Option<(Loudness, Option<Loudness>)> {
+    let tag = id3::Tag::read_from_path(file_path).ok()?;
+
+    let read = |gain_key: &str, peak_key: &str| {
+        let gain = parse_gain(&get_text(&tag, gain_key)?)?;
+        let peak = get_text(&tag, peak_key)
+            .and_then(|peak| peak.trim().parse().ok())
+            .unwrap_or(1.0);
+
+        Some(Loudness {
+            integrated: REFERENCE_LOUDNESS - gain,
+            peak,
+        })
+    };
+
+    let track = read("REPLAYGAIN_TRACK_GAIN", "REPLAYGAIN_TRACK_PEAK")?;
+    let album = read("REPLAYGAIN_ALBUM_GAIN", "REPLAYGAIN_ALBUM_PEAK");
+
+    Some((track, album))
+}
+
+/// Parses a ReplayGain gain value such as "-6.52 dB"
+fn parse_gain(text: &str) -> Option<f32> {
+    let text = text.trim();
+    let number = text
+        .strip_suffix("dB")
+        .or_else(|| text.strip_suffix("db"))
+        .unwrap_or(text);
+    number.trim().parse().ok()
+}
+
+/// Applies the absolute and relative gates to the block powers, returning the loudness in LUFS
+fn gated_loudness(blocks: &[f64]) -> f64 {
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+    let absolute: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|power| loudness(*power) > -70.0)
+        .collect();
+
+    if absolute.is_empty() {
+        return -70.0;
+    }
+
+    let relative_gate = loudness(mean(&absolute)) - 10.0;
+    let relative: Vec<f64> = absolute
+        .into_iter()
+        .filter(|power| loudness(*power) > relative_gate)
+        .collect();
+
+    loudness(mean(&relative))
+}
+
+/// Channel weighting from BS.1770, with the LFE channel of 5.1 audio left out
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (5..=6, 4..=5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+/// Second order IIR filter
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The K-weighting curve, a high shelf followed by a high pass
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    /// Builds the filters for any sample rate, matching the 48kHz coefficients of the spec
+    fn new(sample_rate: f64) -> Self {
+        let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        };
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            z: [0.0; 2],
+        };
+
+        KWeighting { shelf, high_pass }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(x))
+    }
+}
+
+/// Finds the true peak of a channel by oversampling with a windowed sinc interpolator
+struct PeakMeter {
+    history: [f32; PEAK_TAPS],
+    kernel: [[f32; PEAK_TAPS]; OVERSAMPLE],
+    peak: f32,
+}
+
+impl PeakMeter {
+    fn new() -> Self {
+        let delay = (PEAK_TAPS / 2) as f32;
+        let mut kernel = [[0.0; PEAK_TAPS]; OVERSAMPLE];
+
+        for (phase, taps) in kernel.iter_mut().enumerate() {
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let x = k as f32 - delay + phase as f32 / OVERSAMPLE as f32;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (std::f32::consts::PI * x).sin() / (std::f32::consts::PI * x)
+                };
+                let window = 0.5 + 0.5 * (std::f32::consts::PI * x / (delay + 1.0)).cos();
+                *tap = sinc * window;
+            }
+        }
+
+        PeakMeter {
+            history: [0.0; PEAK_TAPS],
+            kernel,
+            peak: 0.0,
+        }
+    }
+
+    fn process(&mut self, sample: f32) {
+        self.history.rotate_right(1);
+        self.history[0] = sample;
+        self.peak = self.peak.max(sample.abs());
+
+        for taps in &self.kernel[1..] {
+            let value: f32 = taps.iter().zip(&self.history).map(|(t, h)| t * h).sum();
+            self.peak = self.peak.max(value.abs());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    /// A few seconds of a mono sine wave, starting at a phase in radians
+    fn sine(freq: f64, amplitude: f64, phase: f64) -> Vec<f32> {
+        (0..SAMPLE_RATE as usize * 3)
+            .map(|n| (amplitude * (2.0 * PI * freq * n as f64 / SAMPLE_RATE + phase).sin()) as f32)
+            .collect()
+    }
+
+    #[test]
+    fn sine_loudness() {
+        // A full scale 1kHz sine measures -3.01 LUFS, so half of it measures 6.02dB lower
+        let loudness = samples_loudness(sine(1000.0, 0.5, 0.0), 1, SAMPLE_RATE);
+        assert!((loudness.integrated + 9.03).abs() < 0.1, "{}", loudness.integrated);
+    }
+
+    #[test]
+    fn quiet_blocks_are_gated() {
+        let loud = 10f64.powf((-20.0 + 0.691) / 10.0);
+        let mut blocks = vec![loud; 10];
+        assert!((gated_loudness(&blocks) + 20.0).abs() < 1e-6);
+
+        // Near silence falls under the absolute gate, and quiet passages under the relative one
+        blocks.extend([1e-9; 10]);
+        blocks.extend([loud / 1000.0; 10]);
+        assert!((gated_loudness(&blocks) + 20.0).abs() < 1e-6);
+
+        assert_eq!(gated_loudness(&[1e-9; 10]), -70.0);
+    }
+
+    #[test]
+    fn true_peak_between_samples() {
+        // At a quarter of the sample rate, offset by 45 degrees, every sample misses the peak
+        let loudness = samples_loudness(sine(SAMPLE_RATE / 4.0, 0.9, PI / 4.0), 1, SAMPLE_RATE);
+        assert!(loudness.peak > 0.85 && loudness.peak < 0.95, "{}", loudness.peak);
+    }
+
+    #[test]
+    fn parses_gains() {
+        assert_eq!(parse_gain("-6.52 dB"), Some(-6.52));
+        assert_eq!(parse_gain("+1.0dB"), Some(1.0));
+        assert_eq!(parse_gain(" 2.5 db "), Some(2.5));
+        assert_eq!(parse_gain("3"), Some(3.0));
+        assert_eq!(parse_gain("loud"), None);
+    }
+}
@@ -1,44 +1,146 @@
 mod chroma;
+pub mod key;
+mod loudness;
 mod mfcc;
 mod spectral;
 mod tempo;
 pub mod utils;
+mod windows;
 mod zcr;
 pub use chroma::extract_chroma;
+pub use key::{estimate_key, key_distance, key_name, key_similarity};
+pub use loudness::{measure_loudness, tagged_loudness, Loudness};
 pub use mfcc::extract_mfcc;
 pub use spectral::extract_spectral;
 pub use tempo::extract_tempo;
+pub use windows::{analysis_windows, read_windows};
 pub use zcr::extract_zcr;
 
+use crate::app::audio::open_decoder;
+use crate::app::settings::AnalysisMode;
 use crate::app::track::{Track, TrackInfo};
 use ndarray::Array1;
-use rodio::{Decoder, Source};
-use std::io::BufReader;
-use std::{fs::File, time::Duration};
+use rodio::Source;
+use std::time::Duration;
+
+/// Version of the features each analysis mode produces, stored with them so that they get
+/// redone when the mode changes
+/// Bump these whenever the features themselves change
+/// Versions 1 to 3 came from before chroma was averaged properly and keys were detected
+pub fn analysis_version(mode: AnalysisMode) -> i32 {
+    match mode {
+        AnalysisMode::Window => 4,
+        AnalysisMode::Segments => 5,
+        AnalysisMode::Whole => 6,
+    }
+}
+
+pub fn generate_track_info(track: &Track, mode: AnalysisMode) -> anyhow::Result<TrackInfo> {
+    let mut track_info = analyse_features(track, mode)?;
+    (track_info.loudness, track_info.album_loudness) = analyse_loudness(track);
+    Ok(track_info)
+}
 
-pub fn generate_track_info(track: &Track) -> TrackInfo {
-    #[cfg(target_os="android")]
-    let duration_offset = (10.0, 10.0);
-    #[cfg(not(target_os="android"))]
-    let duration_offset = (10.0, 10.0);
+/// Brings the features of a track up to date, only redoing the parts that are missing or came
+/// from a different analysis mode
+pub fn update_track_info(track: &Track, cached: Option<TrackInfo>, mode: AnalysisMode) -> anyhow::Result<TrackInfo> {
+    let Some(mut track_info) = cached else {
+        return generate_track_info(track, mode);
+    };
 
-    let (samples, sample_rate) = load_samples(&track.file, Some(duration_offset));
+    // Features from a different analysis mode are redone, keeping the loudness
+    if track_info.analysis_version != analysis_version(mode) {
+        track_info = TrackInfo {
+            loudness: track_info.loudness,
+            album_loudness: track_info.album_loudness,
+            ..analyse_features(track, mode)?
+        };
+    }
 
-    let mfcc = extract_mfcc(&samples, sample_rate);
-    let chroma = extract_chroma(&samples, sample_rate);
-    let spectral = extract_spectral(&samples, sample_rate);
-    let energy = extract_energy(&samples).mean().unwrap_or_default();
-    let bpm = extract_tempo(&samples, sample_rate);
-    let zcr = extract_zcr(&samples, sample_rate);
+    // Tracks analysed before loudness was measured only need that part redone
+    if track_info.loudness.is_none() {
+        (track_info.loudness, track_info.album_loudness) = analyse_loudness(track);
+    }
+
+    Ok(track_info)
+}
+
+/// Analyses the audio features of a track without its loudness
+/// Features are taken from each window picked by the mode, and then averaged
+pub fn analyse_features(track: &Track, mode: AnalysisMode) -> anyhow::Result<TrackInfo> {
+    let mut len = track.len;
+    if len <= 0.0 {
+        len = open_decoder(&track.file)
+            .ok()
+            .and_then(|source| source.total_duration())
+            .map(|duration| duration.as_secs_f64())
+            .unwrap_or_default();
+    }
+
+    let mut segments = Vec::new();
+    read_windows(&track.file, &analysis_windows(mode, len), |samples, sample_rate| {
+        segments.push(window_features(samples, sample_rate));
+    })?;
+
+    if segments.is_empty() {
+        return Err(anyhow::anyhow!("No samples were read from the file"));
+    }
+
+    Ok(aggregate_features(segments, mode))
+}
+
+/// Features of a single window of audio
+fn window_features(samples: &Vec<f32>, sample_rate: u32) -> TrackInfo {
+    TrackInfo {
+        mfcc: extract_mfcc(samples, sample_rate),
+        chroma: extract_chroma(samples, sample_rate),
+        spectral: extract_spectral(samples, sample_rate),
+        energy: extract_energy(samples).mean().unwrap_or_default(),
+        bpm: extract_tempo(samples, sample_rate),
+        zcr: extract_zcr(samples, sample_rate),
+        ..TrackInfo::default()
+    }
+}
+
+/// Combines the features of each window into the features of a track
+/// Means are taken for most features, with the variances of the MFCCs and energy kept to show
+/// how much the track changes
+fn aggregate_features(segments: Vec<TrackInfo>, mode: AnalysisMode) -> TrackInfo {
+    let count = segments.len() as f32;
+    let mean = TrackInfo::average(segments.clone());
+
+    let mfcc_var = segments
+        .iter()
+        .fold(Array1::zeros(mean.mfcc.len()), |a, b| a + (&b.mfcc - &mean.mfcc).mapv(|d| d * d))
+        / count;
+    let energy_var = segments.iter().map(|s| (s.energy - mean.energy).powi(2)).sum::<f32>() / count;
+
+    // Tempo estimates can land on double or half time, so the middle one is the safest
+    let mut bpms: Vec<f32> = segments.iter().map(|s| s.bpm).collect();
+    bpms.sort_by(f32::total_cmp);
 
     TrackInfo {
-        mfcc,
-        chroma,
-        spectral,
-        bpm,
-        energy,
-        zcr,
-        key: 0,
+        bpm: bpms[bpms.len() / 2],
+        // A single window has no variance to go off
+        mfcc_var: if segments.len() > 1 { mfcc_var } else { Array1::zeros(0) },
+        energy_var,
+        analysis_version: analysis_version(mode),
+        ..mean
+    }
+}
+
+/// Gets the track and album loudness from ReplayGain tags, or measures the track if it has none
+pub fn analyse_loudness(track: &Track) -> (Option<Loudness>, Option<Loudness>) {
+    if let Some((loudness, album_loudness)) = tagged_loudness(&track.file) {
+        return (Some(loudness), album_loudness);
+    }
+
+    match measure_loudness(&track.file) {
+        Ok(loudness) => (Some(loudness), None),
+        Err(err) => {
+            log::error!("Could not measure loudness of {:?}: {err:?}", track.file);
+            (None, None)
+        }
     }
 }
 
@@ -46,8 +148,7 @@ pub fn load_samples(file_path: &str, duration: Option<(f64, f64)>) -> (Vec<f32>,
     if file_path.is_empty() {
         return (vec![0.0], 44000);
     }
-    let file = File::open(file_path).unwrap();
-    let source = Decoder::new(BufReader::new(file)).unwrap();
+    let source = open_decoder(file_path).unwrap();
 
     let channels = source.channels();
     let sample_rate = source.sample_rate();
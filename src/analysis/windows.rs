@@ -0,0 +1,145 @@
+use crate::app::audio::open_decoder;
+use crate::app::settings::AnalysisMode;
+use rodio::Source;
+
+/// Length in seconds of each window of audio that features are taken from
+pub const WINDOW_LEN: f64 = 10.0;
+/// Number of windows spread across a track in segmented mode
+const SEGMENTS: usize = 6;
+/// Windows shorter than this at the end of a track are dropped, as tempo can't be found in them
+const MIN_WINDOW_LEN: f64 = 3.0;
+
+/// Picks the windows of a track to analyse, as start and length in seconds
+pub fn analysis_windows(mode: AnalysisMode, len: f64) -> Vec<(f64, f64)> {
+    // Nothing to go off, so just read the start of the track
+    if len <= 0.0 {
+        return vec![(0.0, WINDOW_LEN)];
+    }
+
+    match mode {
+        AnalysisMode::Window => {
+            if len > WINDOW_LEN * 2.0 {
+                vec![(WINDOW_LEN, WINDOW_LEN)]
+            } else if len > WINDOW_LEN {
+                vec![(len - WINDOW_LEN, WINDOW_LEN)]
+            } else {
+                vec![(0.0, len)]
+            }
+        }
+        AnalysisMode::Segments => {
+            // Fades in and out aren't representative, so the edges of the track are skipped
+            let (start, end) = (len * 0.05, len * 0.95);
+            if end - start < WINDOW_LEN * SEGMENTS as f64 {
+                return analysis_windows(AnalysisMode::Whole, len);
+            }
+
+            let step = (end - start - WINDOW_LEN) / (SEGMENTS - 1) as f64;
+            (0..SEGMENTS)
+                .map(|i| (start + step * i as f64, WINDOW_LEN))
+                .collect()
+        }
+        AnalysisMode::Whole => {
+            let mut windows = Vec::new();
+            let mut start = 0.0;
+            while start < len {
+                let window_len = WINDOW_LEN.min(len - start);
+                if window_len >= MIN_WINDOW_LEN || windows.is_empty() {
+                    windows.push((start, window_len));
+                }
+                start += WINDOW_LEN;
+            }
+            windows
+        }
+    }
+}
+
+/// Decodes a file once, handing the mono samples of each window to `f` as soon as it has been
+/// read, so the whole file never has to be held in memory
+/// Windows have to be sorted and not overlap
+pub fn read_windows(
+    file_path: &str,
+    windows: &[(f64, f64)],
+    mut f: impl FnMut(&Vec<f32>, u32),
+) -> anyhow::Result<()> {
+    let source = open_decoder(file_path)?;
+    let channels = source.channels().max(1) as usize;
+    let sample_rate = source.sample_rate();
+
+    let to_frame = |secs: f64| (secs * sample_rate as f64) as usize;
+    let mut windows = windows
+        .iter()
+        .map(|(start, len)| (to_frame(*start), to_frame(start + len)));
+    let Some(mut window) = windows.next() else {
+        return Ok(());
+    };
+
+    let mut buffer = Vec::with_capacity(window.1 - window.0);
+    let mut frame = 0;
+    let mut samples = source.into_iter();
+
+    loop {
+        // Mix each frame down to mono
+        let mut sum = 0.0;
+        let mut read = 0;
+        for sample in samples.by_ref().take(channels) {
+            sum += sample;
+            read += 1;
+        }
+        if read == 0 {
+            break;
+        }
+
+        if frame >= window.0 {
+            buffer.push(sum / read as f32);
+        }
+        frame += 1;
+
+        if frame >= window.1 {
+            f(&buffer, sample_rate);
+            buffer.clear();
+
+            match windows.next() {
+                Some(next) => window = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // The file ended earlier than its length said, so use what was read of the last window
+    if !buffer.is_empty() {
+        f(&buffer, sample_rate);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_window_matches_old_offsets() {
+        assert_eq!(analysis_windows(AnalysisMode::Window, 200.0), vec![(10.0, 10.0)]);
+        assert_eq!(analysis_windows(AnalysisMode::Window, 15.0), vec![(5.0, 10.0)]);
+        assert_eq!(analysis_windows(AnalysisMode::Window, 4.0), vec![(0.0, 4.0)]);
+    }
+
+    #[test]
+    fn segments_spread_across_track() {
+        let windows = analysis_windows(AnalysisMode::Segments, 200.0);
+
+        assert_eq!(windows.len(), SEGMENTS);
+        assert!((windows[0].0 - 10.0).abs() < 1e-9);
+        assert!((windows[SEGMENTS - 1].0 + WINDOW_LEN - 190.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn short_tracks_are_read_whole() {
+        assert_eq!(
+            analysis_windows(AnalysisMode::Segments, 32.0),
+            vec![(0.0, 10.0), (10.0, 10.0), (20.0, 10.0)]
+        );
+        assert_eq!(analysis_windows(AnalysisMode::Whole, 25.0), vec![(0.0, 10.0), (10.0, 10.0), (20.0, 5.0)]);
+        assert_eq!(analysis_windows(AnalysisMode::Whole, 2.0), vec![(0.0, 2.0)]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Listens that stop before this much of the track count as skipping it
+const SKIP_BEFORE: f64 = 0.3;
+/// Listens that get past this much of the track count as playing it through
+const FINISHED_AFTER: f64 = 0.9;
+/// How much a single listen moves a track's affinity
+const LEARNING_RATE: f32 = 0.2;
+/// Days for an affinity to fade to half of what it was
+const HALF_LIFE_DAYS: f64 = 30.0;
+/// How far a skip or full listen moves the radio's space, for the most recent listen
+const SESSION_RATE: f32 = 0.15;
+/// How much less each older listen moves the radio's space than the one after it
+const SESSION_DECAY: f32 = 0.7;
+/// Only this many of the latest listens in a radio session move its space
+pub const SESSION_LISTENS: usize = 10;
+
+/// How much the user likes a track, learnt from how much of it they listen to
+/// Goes from -1 for tracks that always get skipped to 1 for ones that always get played through
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Affinity {
+    pub value: f32,
+    /// Unix time in seconds of the last listen that changed it
+    pub updated: i64,
+}
+
+impl Affinity {
+    /// The affinity as of a time, faded towards 0 since it was last changed
+    pub fn at(&self, now: i64) -> f32 {
+        let days = (now - self.updated).max(0) as f64 / (60.0 * 60.0 * 24.0);
+        self.value * 0.5f64.powf(days / HALF_LIFE_DAYS) as f32
+    }
+
+    /// Moves the affinity by a listen's signal
+    pub fn learn(&self, signal: f32, now: i64) -> Self {
+        Self {
+            value: (self.at(now) + signal * LEARNING_RATE).clamp(-1.0, 1.0),
+            updated: now,
+        }
+    }
+
+    /// How much more or less likely the radio is to pick the track, from a half to double
+    pub fn weight(&self, now: i64) -> f32 {
+        2f32.powf(self.at(now))
+    }
+}
+
+/// Turns how much of a track was listened to into a signal, -1 for a skip right at the start
+/// up to 1 for a full listen
+/// Listens somewhere in the middle don't say much either way, and ones that never got going
+/// say nothing, so they give None
+pub fn listen_signal(completion: f64) -> Option<f32> {
+    if completion <= 0.0 {
+        None
+    } else if completion >= FINISHED_AFTER {
+        Some(1.0)
+    } else if completion < SKIP_BEFORE {
+        // Skipping straight away says more than skipping a while in
+        Some(-(1.0 - completion / SKIP_BEFORE).max(0.25) as f32)
+    } else {
+        None
+    }
+}
+
+/// How far a listen moves a radio session's space, by how many listens ago it was
+pub fn session_rate(listens_ago: usize) -> f32 {
+    SESSION_RATE * SESSION_DECAY.powi(listens_ago as i32)
+}
+
+/// Current unix time in seconds
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 60 * 60 * 24;
+
+    #[test]
+    fn signals() {
+        assert_eq!(listen_signal(0.0), None);
+        assert!((listen_signal(0.03).unwrap() + 0.9).abs() < 1e-5);
+        assert_eq!(listen_signal(0.28), Some(-0.25));
+        assert_eq!(listen_signal(0.5), None);
+        assert_eq!(listen_signal(0.95), Some(1.0));
+    }
+
+    #[test]
+    fn fades_over_time() {
+        let affinity = Affinity { value: 0.8, updated: 0 };
+
+        assert_eq!(affinity.at(0), 0.8);
+        assert!((affinity.at(30 * DAY) - 0.4).abs() < 1e-5);
+        assert!((affinity.weight(0) - 2f32.powf(0.8)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn learning_is_clamped() {
+        let mut affinity = Affinity::default();
+        for _ in 0..20 {
+            affinity = affinity.learn(-1.0, 0);
+        }
+
+        assert_eq!(affinity.value, -1.0);
+        assert!((affinity.learn(1.0, 0).value + 0.8).abs() < 1e-5);
+    }
+}
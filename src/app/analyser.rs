@@ -0,0 +1,161 @@
+use once_cell::sync::Lazy;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+/// Lets the UI pause or cancel analysis running on other threads
+pub static ANALYSIS_CONTROL: Lazy<AnalysisControl> = Lazy::new(AnalysisControl::default);
+
+#[derive(Default)]
+pub struct AnalysisControl {
+    paused: AtomicBool,
+    /// Goes up every time analysis is cancelled, so runs started before then know to stop
+    generation: AtomicUsize,
+}
+
+impl AnalysisControl {
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Stops every run started before now, and unpauses so new runs can start
+    pub fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.set_paused(false);
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::Relaxed)
+    }
+}
+
+/// Progress of analysing the library, shown in the UI
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AnalysisStatus {
+    /// Tracks with features ready, including ones loaded from the database
+    pub analysed: usize,
+    pub total: usize,
+    /// Files that could not be analysed, with why
+    pub failed: Vec<(String, String)>,
+    pub running: bool,
+    pub paused: bool,
+}
+
+impl AnalysisStatus {
+    /// Whether there are still tracks left to analyse
+    pub fn in_progress(&self) -> bool {
+        self.running && self.analysed + self.failed.len() < self.total
+    }
+}
+
+/// Number of threads to analyse with when it's left up to the app
+/// One core is left free so playback and the UI keep up
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|threads| threads.get().saturating_sub(1))
+        .unwrap_or(1)
+        .max(1)
+}
+
+/// Runs `work` over each job on a pool of threads, handing each result to `on_result` on the
+/// calling thread as soon as it's ready
+/// A job that panics gives an error instead of taking the rest down with it
+/// Returns false if the run was cancelled before every job finished
+pub fn run_jobs<J: Sync, R: Send>(
+    jobs: &[J],
+    threads: usize,
+    work: impl Fn(&J) -> anyhow::Result<R> + Sync,
+    mut on_result: impl FnMut(&J, anyhow::Result<R>),
+) -> bool {
+    let generation = ANALYSIS_CONTROL.generation();
+    let cancelled = || ANALYSIS_CONTROL.generation() != generation;
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = channel();
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads.max(1).min(jobs.len()) {
+            let tx = tx.clone();
+            let (next, work, cancelled) = (&next, &work, &cancelled);
+
+            scope.spawn(move || loop {
+                while ANALYSIS_CONTROL.paused() && !cancelled() {
+                    std::thread::sleep(Duration::from_millis(250));
+                }
+                if cancelled() {
+                    return;
+                }
+
+                let Some(job) = jobs.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                    return;
+                };
+                let result = catch_unwind(AssertUnwindSafe(|| work(job)))
+                    .unwrap_or_else(|panic| Err(anyhow::anyhow!("{}", panic_message(&panic))));
+
+                if tx.send((job, result)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(tx);
+
+        for (job, result) in rx {
+            on_result(job, result);
+        }
+    });
+
+    !cancelled()
+}
+
+/// Gets the message out of a caught panic
+fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("analysis panicked")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_job_gets_a_result() {
+        let jobs: Vec<usize> = (0..50).collect();
+        let mut results = Vec::new();
+
+        let finished = run_jobs(&jobs, 4, |job| Ok(job * 2), |job, result| {
+            results.push((*job, result.unwrap()));
+        });
+
+        results.sort();
+        assert!(finished);
+        assert_eq!(results, jobs.iter().map(|j| (*j, j * 2)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn panics_become_errors() {
+        let jobs = vec![1, 2, 3];
+        let mut failed = Vec::new();
+
+        run_jobs(
+            &jobs,
+            2,
+            |job| if *job == 2 { panic!("bad file") } else { Ok(()) },
+            |job, result| {
+                if let Err(err) = result {
+                    failed.push((*job, err.to_string()));
+                }
+            },
+        );
+
+        assert_eq!(failed, vec![(2, String::from("bad file"))]);
+    }
+}
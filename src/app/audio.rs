@@ -1,14 +1,68 @@
-use log::info;
-use rodio::{Decoder, OutputStream, Sink, Source};
+use log::{error, info, warn};
+use super::equalizer::{EqBand, Equalizer, SharedEqualizer};
+use super::stretch::{StretchControl, TimeStretch};
+use rodio::cpal::traits::HostTrait;
+use rodio::source::Amplify;
+use rodio::{cpal, Decoder, DeviceTrait, OutputStream, OutputStreamBuilder, Sink, Source};
 use std::fs::File;
+use std::f32::consts::FRAC_PI_2;
 use std::io::BufReader;
-use std::time::Duration;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How many seconds before the end of a track the next track gets appended to the sink
+const PRELOAD_MARGIN: f64 = 2.0;
+/// How long to wait before trying to open an output stream again after it failed
+pub const STREAM_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+type FileSource = TimeStretch<Equalizer<Amplify<Decoder<BufReader<File>>>>>;
+
+/// A track for the audio player to load, along with how it should be played
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackLoad {
+    pub file: String,
+    /// Linear gain applied to the track, used for loudness normalization
+    pub gain: f32,
+    /// Playback rate, where 1.0 is normal speed
+    pub rate: f32,
+}
+
+/// A decoded track waiting to be appended to the sink after the current one
+struct PreparedTrack {
+    track: TrackLoad,
+    source: FileSource,
+    len: f64,
+    control: Arc<StretchControl>,
+    crossfade: bool,
+}
 
 pub struct AudioPlayer {
     sink: Sink,
     _stream_handle: OutputStream,
     current_song_len: f64,
     played_before: bool,
+    /// Track currently playing, kept so it can be resumed on another output device
+    current_track: Option<TrackLoad>,
+    next_track: Option<PreparedTrack>,
+    appended_track: Option<(TrackLoad, f64, Arc<StretchControl>)>,
+    /// Rate and file position of the playing track
+    control: Arc<StretchControl>,
+    /// Whether changing the rate keeps the pitch the same
+    preserve_pitch: Arc<AtomicBool>,
+    /// Sink of the previous track while it fades out under the current one
+    outgoing_sink: Option<Sink>,
+    crossfade: f32,
+    volume: f32,
+    /// Equalizer bands used by every source that gets played
+    equalizer: Arc<SharedEqualizer>,
+    /// Name of the chosen output device, None for the system default
+    device: Option<String>,
+    /// Set by the stream when its device stops being available
+    device_lost: Arc<AtomicBool>,
+    /// When to try opening the output stream again, if the last attempt failed
+    stream_retry: Option<Instant>,
 }
 
 impl PartialEq for AudioPlayer {
@@ -19,41 +73,64 @@ impl PartialEq for AudioPlayer {
 
 impl AudioPlayer {
     /// New audio player using the default device stream
-    pub fn new() -> Self {
-        let _stream_handle =
-            rodio::OutputStreamBuilder::open_default_stream().expect("open default audio stream");
+    /// Fails if there's no output device that can be opened
+    pub fn new() -> anyhow::Result<Self> {
+        let device_lost = Arc::new(AtomicBool::new(false));
+        let _stream_handle = open_stream(None, &device_lost)?;
         let sink = rodio::Sink::connect_new(&_stream_handle.mixer());
 
-        AudioPlayer {
+        Ok(AudioPlayer {
             sink,
             _stream_handle,
             current_song_len: 1.0,
             played_before: true,
-        }
+            current_track: None,
+            next_track: None,
+            appended_track: None,
+            control: StretchControl::new(1.0),
+            preserve_pitch: Arc::new(AtomicBool::new(true)),
+            outgoing_sink: None,
+            crossfade: 0.0,
+            volume: 1.0,
+            equalizer: Arc::new(SharedEqualizer::default()),
+            device: None,
+            device_lost,
+            stream_retry: None,
+        })
     }
 
-    /// Plays a new track from file into the audio sink
-    pub fn play_track(&mut self, file_path: &str) -> f64 {
-        info!("Playing track: {file_path:?}");
-        let f = File::open(file_path).unwrap();
-        let _len = f.metadata().unwrap().len();
-        let file = BufReader::new(f);
-
-        let source = Decoder::builder()
-            .with_data(file)
-            .with_seekable(true)
-            .build()
-            .unwrap();
-
-        self.current_song_len = source
-            .total_duration()
-            .unwrap_or(Duration::ZERO)
-            .as_secs_f64();
+    /// Plays a new track from file into the audio sink, returning its length
+    /// A track that can't be decoded leaves the sink empty, so it ends straight away and the
+    /// next track gets played instead
+    pub fn play_track(&mut self, track: &TrackLoad) -> anyhow::Result<f64> {
+        info!("Playing track: {:?} with gain {}", track.file, track.gain);
+        let (source, len, control) = match self.decode(track) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                let paused = self.sink.is_paused();
+                self.sink.clear();
+                // Clearing pauses the sink, which would start the next track paused
+                if !paused {
+                    self.sink.play();
+                }
+                self.current_track = None;
+                self.appended_track = None;
+                self.outgoing_sink = None;
+                return Err(err);
+            }
+        };
+        self.current_song_len = len;
+        self.control = control;
+        self.current_track = Some(track.clone());
 
         let was_paused = self.sink.is_paused() || self.played_before;
         info!("{was_paused}");
         self.played_before = false;
         self.sink.clear();
+        self.sink.set_volume(self.volume);
+        self.next_track = None;
+        self.appended_track = None;
+        self.outgoing_sink = None;
         self.sink.append(source);
 
         if was_paused {
@@ -65,24 +142,225 @@ impl AudioPlayer {
         // Seek to 0 to make sure the track starts from the beginning
         self.set_pos(0.0);
         info!("Track successfully played");
-        self.current_song_len
+        Ok(self.current_song_len)
+    }
+
+    /// Decodes the track that plays after the current one ahead of time
+    /// It gets appended to the sink shortly before the current track ends so there is no gap, or
+    /// crossfaded into if crossfading is enabled and allowed for this transition
+    pub fn prepare_next(&mut self, track: &TrackLoad, crossfade: bool) {
+        if let Some(next) = self.next_track.as_mut() {
+            if next.track == *track {
+                next.crossfade = crossfade;
+                return;
+            }
+        }
+
+        match self.decode(track) {
+            Ok((source, len, control)) => {
+                info!("Prepared next track: {:?}", track.file);
+                self.next_track = Some(PreparedTrack {
+                    track: track.clone(),
+                    source,
+                    len,
+                    control,
+                    crossfade,
+                });
+            }
+            Err(err) => {
+                error!("Could not prepare next track {:?}: {err:?}", track.file);
+                self.next_track = None;
+            }
+        }
+    }
+
+    /// Appends or crossfades into the prepared track once the current track is close to ending
+    /// Returns the file of the prepared track once playback has moved onto it
+    pub fn update(&mut self) -> Option<String> {
+        if self.device_lost.swap(false, Ordering::Relaxed) {
+            warn!("Output device {:?} is no longer available, using the default", self.device);
+            self.device = None;
+            self.reopen_stream(None);
+        }
+
+        if self.stream_retry.is_some_and(|at| Instant::now() >= at) {
+            info!("Retrying output device {:?}", self.device);
+            self.reopen_stream(self.device.clone().as_deref());
+        }
+
+        self.update_crossfade();
+
+        if self.appended_track.is_some() {
+            // The finished track has been removed from the sink, so the next one is playing
+            if self.sink.len() <= 1 {
+                let (track, len, control) = self.appended_track.take()?;
+                self.current_song_len = len;
+                self.control = control;
+                self.current_track = Some(track.clone());
+                return Some(track.file);
+            }
+            return None;
+        }
+
+        // Time left at the current rate, rather than in the file
+        let remaining = (self.current_song_len - self.progress_secs()) / self.control.rate() as f64;
+        let crossfading = self.crossfade > 0.0
+            && self.outgoing_sink.is_none()
+            && self.next_track.as_ref().is_some_and(|next| next.crossfade);
+
+        if crossfading && remaining <= self.crossfade as f64 {
+            return self.start_crossfade();
+        }
+
+        if !crossfading && remaining <= PRELOAD_MARGIN && self.sink.len() == 1 {
+            if let Some(next) = self.next_track.take() {
+                info!("Appending next track: {:?}", next.track.file);
+                self.sink.append(next.source);
+                self.appended_track = Some((next.track, next.len, next.control));
+            }
+        }
+
+        None
+    }
+
+    /// Starts the prepared track on a second sink, so both tracks get mixed together while the
+    /// current one fades out
+    fn start_crossfade(&mut self) -> Option<String> {
+        let next = self.next_track.take()?;
+        info!("Crossfading into track: {:?}", next.track.file);
+
+        let sink = Sink::connect_new(self._stream_handle.mixer());
+        sink.set_volume(0.0);
+        sink.append(next.source);
+
+        let outgoing = std::mem::replace(&mut self.sink, sink);
+        self.outgoing_sink = Some(outgoing);
+        self.current_song_len = next.len;
+        self.control = next.control;
+        self.current_track = Some(next.track.clone());
+
+        Some(next.track.file)
+    }
+
+    /// Ramps the volumes of the incoming and outgoing tracks during a crossfade
+    fn update_crossfade(&mut self) {
+        let Some(outgoing) = &self.outgoing_sink else {
+            return;
+        };
+
+        // Progress is taken from the incoming track so that pausing also pauses the fade
+        let progress = (self.progress_secs() as f32 / self.crossfade.max(0.01)).min(1.0);
+
+        if progress >= 1.0 || outgoing.empty() {
+            self.outgoing_sink = None;
+            self.sink.set_volume(self.volume);
+            return;
+        }
+
+        // Equal power curve keeps the loudness steady through the fade
+        self.sink.set_volume(self.volume * (progress * FRAC_PI_2).sin());
+        outgoing.set_volume(self.volume * (progress * FRAC_PI_2).cos());
+    }
+
+    /// Sets how many seconds tracks crossfade for, with 0 turning crossfading off
+    pub fn set_crossfade(&mut self, seconds: f32) {
+        self.crossfade = seconds.max(0.0);
+    }
+
+    /// Sets the equalizer bands, which are applied to the playing tracks straight away
+    pub fn set_equalizer(&mut self, bands: Vec<EqBand>) {
+        self.equalizer.set_bands(bands);
+    }
+
+    /// Sets the playback rate of the current track
+    pub fn set_rate(&mut self, rate: f32) {
+        self.control.set_rate(rate);
+        if let Some(track) = self.current_track.as_mut() {
+            track.rate = rate;
+        }
+    }
+
+    /// Sets whether the pitch stays the same when the rate is changed
+    pub fn set_preserve_pitch(&mut self, preserve_pitch: bool) {
+        self.preserve_pitch.store(preserve_pitch, Ordering::Relaxed);
+    }
+
+    /// Switches playback to another output device, None being the system default
+    pub fn set_output_device(&mut self, device: Option<String>) {
+        if device == self.device {
+            return;
+        }
+
+        info!("Switching output device to {device:?}");
+        self.device = device.clone();
+        self.reopen_stream(device.as_deref());
+    }
+
+    /// Opens a new stream on the given device and carries on playing the current track from the
+    /// same position
+    /// If no stream can be opened, the old one is kept and opening gets tried again later
+    fn reopen_stream(&mut self, device: Option<&str>) {
+        let stream = match open_stream(device, &self.device_lost) {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("Could not open an output stream, retrying in {STREAM_RETRY_INTERVAL:?}: {err:?}");
+                self.stream_retry = Some(Instant::now() + STREAM_RETRY_INTERVAL);
+                return;
+            }
+        };
+        self.stream_retry = None;
+
+        let pos = self.progress_secs();
+        let paused = self.sink.is_paused();
+        let appended = self.appended_track.take();
+        let next = self.next_track.take();
+        self.outgoing_sink = None;
+
+        self._stream_handle = stream;
+        self.sink = Sink::connect_new(self._stream_handle.mixer());
+        if paused {
+            self.sink.pause();
+        }
+
+        if let Some(current) = self.current_track.clone() {
+            match self.play_track(&current) {
+                Ok(_) => self.set_pos(pos),
+                Err(err) => error!("Could not resume track {:?}: {err:?}", current.file),
+            }
+        }
+
+        self.next_track = next;
+        // The appended track was lost with the old sink, so it gets decoded again
+        if let Some((track, _, _)) = appended {
+            self.prepare_next(&track, false);
+        }
+    }
+
+    fn decode(&self, track: &TrackLoad) -> anyhow::Result<(FileSource, f64, Arc<StretchControl>)> {
+        decode_file(track, &self.equalizer, &self.preserve_pitch)
     }
 
     /// Toggles the audio device from playing the current track
     pub fn toggle_playing(&mut self) {
         if self.sink.is_paused() {
-            self.sink.play();
+            self.play();
         } else {
-            self.sink.pause();
+            self.pause();
         }
     }
 
     pub fn play(&mut self) {
         self.sink.play();
+        if let Some(outgoing) = &self.outgoing_sink {
+            outgoing.play();
+        }
     }
 
     pub fn pause(&mut self) {
         self.sink.pause();
+        if let Some(outgoing) = &self.outgoing_sink {
+            outgoing.pause();
+        }
     }
 
     pub fn playing(&self) -> bool {
@@ -90,11 +368,12 @@ impl AudioPlayer {
     }
 
     pub fn progress_percent(&self) -> f64 {
-        self.sink.get_pos().as_secs_f64() / self.current_song_len
+        self.progress_secs() / self.current_song_len
     }
 
+    /// Position in the current file, which doesn't depend on the playback rate
     pub fn progress_secs(&self) -> f64 {
-        self.sink.get_pos().as_secs_f64()
+        self.control.position()
     }
 
     pub fn track_ended(&self) -> bool {
@@ -106,6 +385,11 @@ impl AudioPlayer {
     }
 
     pub fn set_pos(&mut self, pos: f64) {
+        // Seeking ends any fade out of the previous track
+        if self.outgoing_sink.take().is_some() {
+            self.sink.set_volume(self.volume);
+        }
+
         let try_seek = self.sink.try_seek(Duration::from_secs_f64(pos));
         if let Err(seek_error) = try_seek {
             info!("Recieved seek error: {seek_error:?}");
@@ -113,6 +397,94 @@ impl AudioPlayer {
     }
 
     pub fn set_volume(&mut self, volume: f32) {
-        self.sink.set_volume(volume)
+        self.volume = volume;
+        if self.outgoing_sink.is_none() {
+            self.sink.set_volume(volume)
+        }
+    }
+}
+
+/// Names of the output devices available on the default host
+pub fn output_devices() -> Vec<String> {
+    match cpal::default_host().output_devices() {
+        Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+        Err(err) => {
+            error!("Could not list output devices: {err:?}");
+            Vec::new()
+        }
+    }
+}
+
+/// Opens an output stream on the named device, falling back to the default device if it can't
+/// be found or opened
+/// Fails when there's no device that can be opened at all
+fn open_stream(device: Option<&str>, device_lost: &Arc<AtomicBool>) -> anyhow::Result<OutputStream> {
+    let lost = device_lost.clone();
+    let on_error = move |err: cpal::StreamError| {
+        error!("Output stream error: {err:?}");
+        if let cpal::StreamError::DeviceNotAvailable = err {
+            lost.store(true, Ordering::Relaxed);
+        }
+    };
+
+    if let Some(name) = device {
+        let found = cpal::default_host()
+            .output_devices()
+            .ok()
+            .and_then(|mut devices| devices.find(|d| d.name().is_ok_and(|n| n == name)));
+
+        match found.map(OutputStreamBuilder::from_device) {
+            Some(Ok(builder)) => {
+                match builder.with_error_callback(on_error.clone()).open_stream_or_fallback() {
+                    Ok(stream) => return Ok(stream),
+                    Err(err) => error!("Could not open output device {name:?}: {err:?}"),
+                }
+            }
+            Some(Err(err)) => error!("Could not open output device {name:?}: {err:?}"),
+            None => warn!("Output device {name:?} not found"),
+        }
     }
+
+    Ok(OutputStreamBuilder::from_default_device()?
+        .with_error_callback(on_error)
+        .open_stream_or_fallback()?)
+}
+
+/// Opens a decoder for an audio file
+/// The extension and length are passed along so that containers like MP4, which can keep their
+/// index at the end of the file, are recognised and read properly
+pub fn open_decoder(file: &str) -> anyhow::Result<Decoder<BufReader<File>>> {
+    let data = File::open(file)?;
+    let len = data.metadata()?.len();
+
+    let mut builder = Decoder::builder()
+        .with_data(BufReader::new(data))
+        .with_byte_len(len)
+        .with_seekable(true);
+    if let Some(extension) = Path::new(file).extension().and_then(|e| e.to_str()) {
+        builder = builder.with_hint(extension);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Opens and decodes an audio file, returning the source, its length in seconds and the control
+/// for its rate
+fn decode_file(
+    track: &TrackLoad,
+    equalizer: &Arc<SharedEqualizer>,
+    preserve_pitch: &Arc<AtomicBool>,
+) -> anyhow::Result<(FileSource, f64, Arc<StretchControl>)> {
+    let source = open_decoder(&track.file)?;
+
+    let len = source
+        .total_duration()
+        .unwrap_or(Duration::ZERO)
+        .as_secs_f64();
+
+    let source = Equalizer::new(source.amplify(track.gain), equalizer.clone());
+    let control = StretchControl::new(track.rate);
+    let source = TimeStretch::new(source, control.clone(), preserve_pitch.clone());
+
+    Ok((source, len, control))
 }
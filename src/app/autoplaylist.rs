@@ -1,5 +1,7 @@
 use super::utils::{strip_unnessecary, similar};
-use super::track::Track;
+use super::track::{Track, TrackId, TrackInfo};
+use crate::analysis::key_name;
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::ops::{Index, IndexMut};
 use std::path::PathBuf;
@@ -55,6 +57,7 @@ pub enum StrIdentifier {
     Genre,
     Album,
     Artist,
+    Key,
 }
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -138,6 +141,7 @@ impl StrIdentifier {
             "genre" => Some(Self::Genre),
             "artist" => Some(Self::Artist),
             "album" => Some(Self::Album),
+            "key" => Some(Self::Key),
             _ => None
         }
     }
@@ -150,6 +154,7 @@ impl Display for StrIdentifier {
             StrIdentifier::Genre => f.write_str("Genre"),
             StrIdentifier::Album => f.write_str("Album"),
             StrIdentifier::Artist => f.write_str("Artist"),
+            StrIdentifier::Key => f.write_str("Key"),
         }
     }
 }
@@ -206,19 +211,27 @@ impl Display for NumOperator {
 }
 
 impl Condition {
-    pub fn track_qualifies(&self, track: &Track) -> bool {
+    /// Checks a track against the condition, using its analysis info for conditions like key
+    pub fn track_qualifies(&self, track: &Track, info: Option<&TrackInfo>) -> bool {
         use Condition::*;
         use StrIdentifier::*;
         match self {
-            All(conditions) => conditions.iter().all(|a| a.track_qualifies(&track)),
-            Any(conditions) => conditions.iter().any(|a| a.track_qualifies(&track)),
+            All(conditions) => conditions.iter().all(|a| a.track_qualifies(&track, info)),
+            Any(conditions) => conditions.iter().any(|a| a.track_qualifies(&track, info)),
             StrCondition(ident, op, value) => {
                 let actual_value = match ident {
                     Title => vec![track.title.clone()],
                     Genre => track.genres.clone(),
                     Album => vec![track.album.clone()],
                     Artist => track.artists.clone(),
+                    // Keys that haven't been detected count as missing
+                    Key => match info {
+                        Some(info) if info.key_confidence > 0.0 => vec![sharp_to_word(&key_name(info.key))],
+                        _ => Vec::new(),
+                    },
                 };
+                // Sharps would otherwise be stripped as punctuation, making C# match C
+                let value = if *ident == Key { sharp_to_word(value) } else { value.clone() };
 
                 match op {
                     StrOperator::Is => actual_value.iter().any(|v| similar(&v, &value)),
@@ -259,12 +272,12 @@ impl Condition {
         }
     }
 
-    pub fn qualify_tracks(&self, tracks: &Vec<Track>) -> Vec<usize> {
+    pub fn qualify_tracks(&self, tracks: &BTreeMap<TrackId, Track>, track_info: &BTreeMap<TrackId, TrackInfo>) -> Vec<TrackId> {
         let mut results = Vec::new();
 
-        for i in 0..tracks.len() {
-            if self.track_qualifies(&tracks[i]) {
-                results.push(i);
+        for (id, track) in tracks {
+            if self.track_qualifies(track, track_info.get(id)) {
+                results.push(*id);
             }
         }
 
@@ -390,6 +403,11 @@ impl IndexMut<Vec<usize>> for Condition {
     }
 }
 
+/// Spells out sharps in a key name, so "C#" and "C sharp" are the same key
+fn sharp_to_word(key: &str) -> String {
+    key.replace('#', " sharp ")
+}
+
 impl Into<StrIdentifier> for String {
     fn into(self) -> StrIdentifier {
         match self.as_str() {
@@ -397,6 +415,7 @@ impl Into<StrIdentifier> for String {
             "Artist" => StrIdentifier::Artist,
             "Album" => StrIdentifier::Album,
             "Genre" => StrIdentifier::Genre,
+            "Key" => StrIdentifier::Key,
             _ => unreachable!(),
         }
     }
@@ -419,91 +438,114 @@ mod tests {
         track
     }
 
+    fn library(tracks: Vec<Track>) -> BTreeMap<TrackId, Track> {
+        tracks.into_iter().enumerate().collect()
+    }
+
     #[test]
     fn artist_query() {
-        let tracks = vec![simple_track("song 1", "john doe"),
+        let tracks = library(vec![simple_track("song 1", "john doe"),
             simple_track("song 2", "jimmy bob"),
-            simple_track("song 3", "Jane Doe")];
+            simple_track("song 3", "Jane Doe")]);
 
         let query = Condition::StrCondition(StrIdentifier::Artist, StrOperator::Is, "John Doe".to_string());
 
-        assert_eq!(query.qualify_tracks(&tracks), vec![0]);
+        assert_eq!(query.qualify_tracks(&tracks, &BTreeMap::new()), vec![0]);
     }
 
     #[test]
     fn artist_has_query() {
-        let tracks = vec![simple_track("song 1", "john doe"),
+        let tracks = library(vec![simple_track("song 1", "john doe"),
             simple_track("song 2", "jimmy bob"),
-            simple_track("song 3", "Jane Doe")];
+            simple_track("song 3", "Jane Doe")]);
 
         let query = Condition::StrCondition(StrIdentifier::Artist, StrOperator::Has, "Doe".to_string());
 
-        assert_eq!(query.qualify_tracks(&tracks), vec![0, 2]);
+        assert_eq!(query.qualify_tracks(&tracks, &BTreeMap::new()), vec![0, 2]);
     }
 
     #[test]
     fn not_query() {
-        let tracks = vec![simple_track("song 1", "john doe"),
+        let tracks = library(vec![simple_track("song 1", "john doe"),
             simple_track("song 2", "jimmy bob"),
-            simple_track("song 3", "Jane Doe")];
+            simple_track("song 3", "Jane Doe")]);
 
         let query = Condition::StrCondition(StrIdentifier::Artist, StrOperator::IsNot, "John Doe".to_string());
 
-        assert_eq!(query.qualify_tracks(&tracks), vec![1,2]);
+        assert_eq!(query.qualify_tracks(&tracks, &BTreeMap::new()), vec![1,2]);
     }
 
     #[test]
     fn missing_query() {
-        let tracks = vec![simple_track("song 1", "john doe"),
+        let tracks = library(vec![simple_track("song 1", "john doe"),
             simple_track("song 2", ""),
             simple_track("song 3", "  "),
             simple_track("song 4", "Jane Doe"),
-            simple_track("song 5", "    ")];
+            simple_track("song 5", "    ")]);
 
         let query = Condition::StrCondition(StrIdentifier::Artist, StrOperator::Missing, String::new());
 
-        assert_eq!(query.qualify_tracks(&tracks), vec![1,2,4]);
+        assert_eq!(query.qualify_tracks(&tracks, &BTreeMap::new()), vec![1,2,4]);
     }
 
     #[test]
     fn any_all_query() {
-        let tracks = vec![simple_track("song 1", "john doe"),
+        let tracks = library(vec![simple_track("song 1", "john doe"),
             simple_track("song 2", "Jane Doe"),
             simple_track("track 3", "Jimmy Bob"),
             simple_track("song 4", "Jane Doe"),
-            simple_track("track 5", "John Doe")];
+            simple_track("track 5", "John Doe")]);
 
         let query = Condition::All(vec![
             Condition::StrCondition(StrIdentifier::Artist, StrOperator::Is, "John Doe".to_string()),
             Condition::StrCondition(StrIdentifier::Title, StrOperator::Has, "track".to_string())]);
 
-        assert_eq!(query.qualify_tracks(&tracks), vec![4]);
+        assert_eq!(query.qualify_tracks(&tracks, &BTreeMap::new()), vec![4]);
 
         let query = Condition::Any(vec![
             Condition::StrCondition(StrIdentifier::Artist, StrOperator::Is, "John Doe".to_string()),
             Condition::StrCondition(StrIdentifier::Title, StrOperator::Has, "track".to_string())]);
 
-        assert_eq!(query.qualify_tracks(&tracks), vec![0,2,4]);
+        assert_eq!(query.qualify_tracks(&tracks, &BTreeMap::new()), vec![0,2,4]);
     }
 
     #[test]
     fn num_query() {
-        let tracks = vec![year_track(1980),
+        let tracks = library(vec![year_track(1980),
             year_track(1976),
             year_track(1969),
             year_track(2010),
-            year_track(2024)];
+            year_track(2024)]);
 
         let query = Condition::NumCondition(NumIdentifier::Year, NumOperator::Greater, 1980);
 
-        assert_eq!(query.qualify_tracks(&tracks), vec![3,4]);
+        assert_eq!(query.qualify_tracks(&tracks, &BTreeMap::new()), vec![3,4]);
 
         let query = Condition::NumCondition(NumIdentifier::Year, NumOperator::Lesser, 1980);
 
-        assert_eq!(query.qualify_tracks(&tracks), vec![1,2]);
+        assert_eq!(query.qualify_tracks(&tracks, &BTreeMap::new()), vec![1,2]);
 
         let query = Condition::NumCondition(NumIdentifier::Year, NumOperator::Equals, 1980);
 
-        assert_eq!(query.qualify_tracks(&tracks), vec![0]);
+        assert_eq!(query.qualify_tracks(&tracks, &BTreeMap::new()), vec![0]);
+    }
+
+    #[test]
+    fn key_query() {
+        let tracks = library(vec![year_track(0), year_track(0), year_track(0), year_track(0)]);
+        let key_info = |key, key_confidence| TrackInfo { key, key_confidence, ..TrackInfo::default() };
+        let track_info = BTreeMap::from([(0, key_info(0, 0.8)), (1, key_info(1, 0.8)), (2, key_info(0, 0.0))]);
+
+        let query = Condition::StrCondition(StrIdentifier::Key, StrOperator::Is, "C major".to_string());
+
+        assert_eq!(query.qualify_tracks(&tracks, &track_info), vec![0]);
+
+        let query = Condition::StrCondition(StrIdentifier::Key, StrOperator::Is, "C# Major".to_string());
+
+        assert_eq!(query.qualify_tracks(&tracks, &track_info), vec![1]);
+
+        let query = Condition::StrCondition(StrIdentifier::Key, StrOperator::Missing, String::new());
+
+        assert_eq!(query.qualify_tracks(&tracks, &track_info), vec![2, 3]);
     }
 }
@@ -18,7 +18,7 @@ impl Condition {
 
         match tokens.next().ok_or(anyhow!("Missing statement"))? {
             Token::Identifier(ident) => match strip_unnessecary(&ident).as_str() {
-                "title" | "album" | "artist" | "genre" => {
+                "title" | "album" | "artist" | "genre" | "key" => {
                     let op = strip_unnessecary(&tokens.next().ok_or(anyhow!("No matching operator for identifier"))?.as_identifier()?);
 
                     let value = tokens.next().ok_or(anyhow!("No matching operator for identifier"))?.as_string()?;
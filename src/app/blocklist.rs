@@ -0,0 +1,170 @@
+use super::track::Track;
+use super::utils::strip_unnessecary;
+use crate::database::hash_filename;
+use std::collections::HashMap;
+
+/// How much less often "play less often" tracks get picked
+pub const LESS_OFTEN_WEIGHT: f32 = 0.2;
+
+/// What a blocklist entry applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockKind {
+    Track,
+    Artist,
+    Genre,
+}
+
+/// How much a blocklist entry holds its tracks back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockLevel {
+    /// Never played unless the user picks it themselves
+    Never,
+    /// Still played, just not as often
+    LessOften,
+}
+
+/// Tracks, artists and genres the user has asked to never hear, or hear less of
+/// Tracks are keyed by file hash, while artists and genres are keyed loosely so that
+/// differences in capitalisation and punctuation don't matter
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Blocklist {
+    entries: HashMap<(BlockKind, String), BlockLevel>,
+}
+
+impl BlockKind {
+    /// Name used to store the kind in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockKind::Track => "track",
+            BlockKind::Artist => "artist",
+            BlockKind::Genre => "genre",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "track" => Some(BlockKind::Track),
+            "artist" => Some(BlockKind::Artist),
+            "genre" => Some(BlockKind::Genre),
+            _ => None,
+        }
+    }
+}
+
+impl BlockLevel {
+    /// Name used to store the level in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockLevel::Never => "never",
+            BlockLevel::LessOften => "less",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "never" => Some(BlockLevel::Never),
+            "less" => Some(BlockLevel::LessOften),
+            _ => None,
+        }
+    }
+}
+
+impl Blocklist {
+    /// Turns a track file, artist or genre into the key its entry is stored under
+    pub fn key(kind: BlockKind, name: &str) -> String {
+        match kind {
+            BlockKind::Track => hash_filename(name),
+            BlockKind::Artist | BlockKind::Genre => strip_unnessecary(name),
+        }
+    }
+
+    /// Adds an entry by its stored key, as loaded from the database
+    pub fn insert(&mut self, kind: BlockKind, key: String, level: BlockLevel) {
+        self.entries.insert((kind, key), level);
+    }
+
+    /// Sets or clears the entry for a track file, artist or genre
+    pub fn set(&mut self, kind: BlockKind, name: &str, level: Option<BlockLevel>) {
+        let key = (kind, Self::key(kind, name));
+        match level {
+            Some(level) => self.entries.insert(key, level),
+            None => self.entries.remove(&key),
+        };
+    }
+
+    /// The entry for a track file, artist or genre
+    pub fn get(&self, kind: BlockKind, name: &str) -> Option<BlockLevel> {
+        self.entries.get(&(kind, Self::key(kind, name))).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// How much to weigh a track by when picking what plays next
+    /// Blocked tracks give 0, and every "play less often" entry that applies to the track makes
+    /// it less likely again
+    pub fn weight(&self, track: &Track) -> f32 {
+        if self.is_empty() {
+            return 1.0;
+        }
+
+        let levels = std::iter::once(self.get(BlockKind::Track, &track.file))
+            .chain(track.artists.iter().map(|artist| self.get(BlockKind::Artist, artist)))
+            .chain(track.genres.iter().map(|genre| self.get(BlockKind::Genre, genre)))
+            .flatten();
+
+        let mut weight = 1.0;
+        for level in levels {
+            match level {
+                BlockLevel::Never => return 0.0,
+                BlockLevel::LessOften => weight *= LESS_OFTEN_WEIGHT,
+            }
+        }
+        weight
+    }
+
+    /// Whether a track should never be played unless the user picks it themselves
+    pub fn blocks(&self, track: &Track) -> bool {
+        self.weight(track) == 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(file: &str, artist: &str, genre: &str) -> Track {
+        Track {
+            file: file.to_string(),
+            artists: vec![artist.to_string()],
+            genres: vec![genre.to_string()],
+            ..Track::default()
+        }
+    }
+
+    #[test]
+    fn blocked_by_any_entry() {
+        let mut blocklist = Blocklist::default();
+        blocklist.set(BlockKind::Genre, "Christmas", Some(BlockLevel::Never));
+        blocklist.set(BlockKind::Track, "/music/lullaby.mp3", Some(BlockLevel::Never));
+
+        assert!(blocklist.blocks(&track("/music/carol.mp3", "Choir", "christmas")));
+        assert!(blocklist.blocks(&track("/music/lullaby.mp3", "Band", "Pop")));
+        assert!(!blocklist.blocks(&track("/music/song.mp3", "Band", "Pop")));
+    }
+
+    #[test]
+    fn less_often_entries_stack() {
+        let mut blocklist = Blocklist::default();
+        blocklist.set(BlockKind::Artist, "The Band", Some(BlockLevel::LessOften));
+        blocklist.set(BlockKind::Genre, "Pop", Some(BlockLevel::LessOften));
+
+        assert_eq!(blocklist.weight(&track("a.mp3", "the band", "Rock")), LESS_OFTEN_WEIGHT);
+        assert_eq!(blocklist.weight(&track("b.mp3", "The Band", "Pop")), LESS_OFTEN_WEIGHT * LESS_OFTEN_WEIGHT);
+        assert_eq!(blocklist.weight(&track("c.mp3", "Other", "Rock")), 1.0);
+
+        blocklist.set(BlockKind::Genre, "Pop", None);
+        assert_eq!(blocklist.weight(&track("b.mp3", "The Band", "Pop")), LESS_OFTEN_WEIGHT);
+    }
+}
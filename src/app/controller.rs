@@ -1,27 +1,53 @@
 use super::{
+    affinity::{listen_signal, now, session_rate, Affinity, SESSION_LISTENS},
+    analyser::{AnalysisStatus, ANALYSIS_CONTROL},
+    blocklist::{BlockKind, BlockLevel, Blocklist},
+    audio::TrackLoad,
+    index::{feature_vector, KdTree, SharedIndex},
+    normalize::FeatureStats,
+    equalizer::{EqBand, EqPreset},
     playlist::get_playlist_files,
     playlist::Playlist,
-    queue::{Listen, Queue, QueueType},
-    settings::{RadioSettings, Settings, WeightMode},
-    track::{Mood, Track, TrackInfo},
+    queue::{EndBehaviour, Listen, Queue, QueueType},
+    settings::{
+        AnalysisMode, LibraryRoot, RadioMode, RadioSettings, ReplayGainMode, SeedMode, Settings,
+        WeightMode,
+    },
+    state::SavedState,
+    track::{AlbumId, Mood, ScanReport, Track, TrackId, TrackInfo},
     utils::{similar, strip_unnessecary}, autoplaylist::AutoPlaylist,
 };
-use crate::database::{save_to_cache, init_db};
+use crate::database::{
+    clear_analysis_failures, hash_filename, init_db, load_blocklist, load_feature_stats,
+    load_track_affinities, load_track_rates, save_block, save_feature_stats, save_to_cache,
+    save_track_affinity, save_track_rate,
+};
 use crate::analysis::utils::cosine_similarity;
+use crate::analysis::{key_distance, key_similarity, Loudness};
 use log::{info, warn, error};
 use ndarray::Array1;
 use rand::distributions::WeightedIndex;
 use rand::prelude::*;
 use rand::thread_rng;
 use rustfft::num_traits::Zero;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 use std::sync::mpsc::Sender;
 use std::sync::Mutex;
 use once_cell::sync::Lazy;
 use dioxus::prelude::*;
 
+/// Tracks at least this long remember their own playback rate, for audiobooks and lectures
+const LONG_TRACK_SECS: f64 = 20.0 * 60.0;
+
+/// Libraries with more analysed tracks than this only score radio candidates from the index
+const INDEX_MIN_TRACKS: usize = 2000;
+/// Number of nearest tracks taken from the index for each radio pick
+pub const RADIO_CANDIDATES: usize = 200;
+/// How many tracks the index looks at for each candidate it returns
+pub const INDEX_CHECKS_PER_CANDIDATE: usize = 10;
+
 pub static MUSIC_PLAYER_ACTIONS: Lazy<Mutex<Option<Sender<MusicMsg>>>> =
     Lazy::new(|| Mutex::new(None));
 
@@ -32,10 +58,17 @@ pub enum MusicMsg {
     Pause,
     Play,
     Toggle,
-    PlayTrack(String),
+    PlayTrack(TrackLoad),
+    PrepareNext(TrackLoad, bool),
+    SetCrossfade(f32),
+    SetEqualizer(Vec<EqBand>),
+    SetOutputDevice(Option<String>),
+    SetRate(f32),
+    SetPreservePitch(bool),
     SetVolume(f32),
     SetPos(f64),
     UpdateInfo,
+    RescanLibrary,
 }
 
 // Send message to AudioPlayer in thread
@@ -51,16 +84,38 @@ pub fn send_music_msg(msg: MusicMsg) {
 
 #[derive(PartialEq, Clone, Store)]
 pub struct MusicController {
-    pub all_tracks: Vec<Track>,
-    pub track_info: Vec<TrackInfo>,
+    pub all_tracks: BTreeMap<TrackId, Track>,
+    pub track_info: BTreeMap<TrackId, TrackInfo>,
     pub artists: HashMap<String, (String, usize)>,
     pub genres: HashMap<String, usize>,
-    pub albums: HashMap<String, (usize, usize)>, // count, first track (for image purposes)
+    pub albums: HashMap<AlbumId, (usize, TrackId)>, // count, first track (for image purposes)
     pub listens: Vec<Listen>,
     pub shuffle: bool,
     pub playlists: Vec<Playlist>,
     pub autoplaylists: Vec<AutoPlaylist>,
+    /// Remembered playback rates of long tracks, keyed by file hash
+    pub track_rates: HashMap<String, f32>,
+    /// How much each track is liked, learnt from skips and full listens, keyed by file hash
+    pub track_affinity: HashMap<String, Affinity>,
+    /// Tracks, artists and genres to never play, or play less often in radio, shuffle and
+    /// autoplaylists
+    pub blocklist: Blocklist,
+    /// What changed in the most recent library scan
+    pub last_scan: Option<ScanReport>,
+    pub analysis: AnalysisStatus,
+    /// Spread of each feature across the library, for putting them on the same scale
+    pub feature_stats: FeatureStats,
+    /// Analysed tracks indexed by their features, for finding radio candidates quickly
+    radio_index: SharedIndex,
+    /// Radio settings and feature stats the index was built with, None if it hasn't been
+    /// built yet
+    radio_index_settings: Option<(RadioSettings, FeatureStats)>,
+    /// Loudness of each album combined from its measured tracks, for albums without tags
+    album_loudness_map: HashMap<AlbumId, Loudness>,
     current_started: Instant,
+    /// Track the audio player was last told to play, which a listen is recorded for once it
+    /// stops
+    playing_track: Option<TrackId>,
 
     pub current_queue: usize,
     pub queues: Vec<Queue>,
@@ -75,19 +130,29 @@ impl MusicController {
     /// Creates an empty controller with no tracks
     pub fn empty() -> Self {
         Self {
-            all_tracks: Vec::new(),
-            track_info: Vec::new(),
+            all_tracks: BTreeMap::new(),
+            track_info: BTreeMap::new(),
             artists: HashMap::new(),
             genres: HashMap::new(),
             albums: HashMap::new(),
             listens: Vec::new(),
             current_started: Instant::now(),
+            playing_track: None,
             current_queue: 0,
             queues: vec![Queue::all()],
             settings: Settings::load(),
             shuffle: false,
             playlists: Vec::new(),
             autoplaylists: Vec::new(),
+            track_rates: HashMap::new(),
+            track_affinity: HashMap::new(),
+            blocklist: Blocklist::default(),
+            last_scan: None,
+            analysis: AnalysisStatus::default(),
+            feature_stats: FeatureStats::default(),
+            radio_index: SharedIndex::default(),
+            radio_index_settings: None,
+            album_loudness_map: HashMap::new(),
             progress_secs: 0.0,
             song_length: 100.0,
             playing: false,
@@ -95,48 +160,27 @@ impl MusicController {
     }
 
     /// Creates and loads all tracks and weights into the controller
-    pub fn new(all_tracks: Vec<Track>, _directory: String) -> Self {
+    pub fn new(all_tracks: BTreeMap<TrackId, Track>) -> Self {
         let mut rng = thread_rng();
-        let current_playing = if all_tracks.len() > 0 {
-            rng.gen_range(0..all_tracks.len())
-        } else {
-            0
+        // An empty library starts with an empty queue, as there's no track to start a radio from
+        let queue = match all_tracks.iter().choose(&mut rng) {
+            Some((id, track)) => Queue::radio(*id, track.title.clone()),
+            None => Queue::all(),
         };
 
         let started = std::time::SystemTime::now();
 
-        let (mut albums, mut artists, mut genres) =
-            (HashMap::new(), HashMap::new(), HashMap::new());
-
-        for i in 0..all_tracks.len() {
-            for genre in all_tracks[i].genres.clone() {
-                *genres.entry(genre.clone()).or_insert(0) += 1;
-            }
-
-            for artist in all_tracks[i].artists.clone() {
-                // some artists names seem to change captalization grr
-                let stripped = strip_unnessecary(&artist);
-                artists.entry(stripped).or_insert((artist, 0)).1 += 1;
-            }
-
-            albums.entry(all_tracks[i].album.clone()).or_insert((0, i)).0 += 1;
-        }
+        let (albums, artists, genres) = library_counts(&all_tracks);
         info!("Calculated weights in {:?}", started.elapsed());
 
         let mut controller = MusicController {
             all_tracks: all_tracks.clone(),
             current_started: Instant::now(),
+            playing_track: None,
             listens: Vec::new(),
-            queues: vec![Queue::radio(
-                current_playing,
-                all_tracks
-                    .get(current_playing)
-                    .cloned()
-                    .unwrap_or_default()
-                    .title,
-            )],
+            queues: vec![queue],
             current_queue: 0,
-            track_info: Vec::new(),
+            track_info: BTreeMap::new(),
             artists,
             genres,
             albums,
@@ -144,11 +188,34 @@ impl MusicController {
             shuffle: false,
             playlists: Vec::new(),
             autoplaylists: Vec::new(),
+            track_rates: init_db()
+                .and_then(|conn| load_track_rates(&conn))
+                .unwrap_or_default(),
+            track_affinity: init_db()
+                .and_then(|conn| load_track_affinities(&conn))
+                .unwrap_or_default(),
+            blocklist: init_db()
+                .and_then(|conn| load_blocklist(&conn))
+                .unwrap_or_default(),
+            last_scan: None,
+            analysis: AnalysisStatus::default(),
+            feature_stats: init_db()
+                .and_then(|conn| load_feature_stats(&conn))
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+            radio_index: SharedIndex::default(),
+            radio_index_settings: None,
+            album_loudness_map: HashMap::new(),
             progress_secs: 0.0,
             song_length: 100.0,
             playing: false,
         };
+        send_music_msg(MusicMsg::SetOutputDevice(controller.settings.audio.output_device.clone()));
         send_music_msg(MusicMsg::SetVolume(controller.settings.volume));
+        send_music_msg(MusicMsg::SetPreservePitch(controller.settings.audio.preserve_pitch));
+        send_music_msg(MusicMsg::SetCrossfade(controller.settings.audio.crossfade));
+        send_music_msg(MusicMsg::SetEqualizer(controller.equalizer_bands()));
 
         info!("Loading playlists {:?}", started.elapsed());
         controller.load_playlists();
@@ -157,20 +224,112 @@ impl MusicController {
 
         info!("Loaded playlists in {:?}", started.elapsed());
 
+        if let Some(state) = SavedState::load() {
+            controller.restore_state(state);
+        }
+
+        // The audio player starts paused, so a restored track waits at its saved position
         if let Some(track) = controller.current_track().cloned() {
-            send_music_msg(MusicMsg::PlayTrack(track.file.clone()));
+            let load = controller.track_load(controller.current_track_idx());
+            send_music_msg(MusicMsg::PlayTrack(load));
+            controller.playing_track = Some(controller.current_track_idx());
+            if controller.progress_secs > 0.0 {
+                send_music_msg(MusicMsg::SetPos(controller.progress_secs));
+            }
             info!("Started track {track:?} in {:?}", started.elapsed());
         }
+        controller.prepare_next();
 
         controller
     }
 
-    /// Loads all playlists in the music directory (.m3u files)
+    /// Swaps in a rescanned library without interrupting playback
+    /// Removed tracks are taken out of queues, and playlists are matched up again
+    pub fn set_library(&mut self, all_tracks: BTreeMap<TrackId, Track>, report: ScanReport) {
+        (self.albums, self.artists, self.genres) = library_counts(&all_tracks);
+        let removed: Vec<TrackId> =
+            self.track_info.keys().filter(|id| !all_tracks.contains_key(id)).copied().collect();
+        for id in removed {
+            self.track_info.remove(&id);
+            self.radio_index.make_mut().remove(id);
+        }
+
+        self.retain_queues(|id| all_tracks.contains_key(id));
+        if self.queues.is_empty() {
+            self.queues.push(Queue::new(QueueType::AllTracks, all_tracks.keys().copied().collect()));
+            self.current_queue = 0;
+        }
+
+        self.all_tracks = all_tracks;
+        self.last_scan = Some(report);
+        self.update_album_loudness();
+
+        self.playlists.clear();
+        self.load_playlists();
+        self.prepare_next();
+        self.save_state();
+    }
+
+    /// Takes tracks out of every queue, dropping queues left empty while keeping the current
+    /// queue selected
+    fn retain_queues(&mut self, keep: impl Fn(&TrackId) -> bool) {
+        for queue in &mut self.queues {
+            queue.retain(&keep);
+        }
+
+        let emptied_before = self.queues[..self.current_queue.min(self.queues.len())]
+            .iter()
+            .filter(|queue| queue.len() == 0)
+            .count();
+        self.queues.retain(|queue| queue.len() > 0);
+        self.current_queue = (self.current_queue - emptied_before).min(self.queues.len().saturating_sub(1));
+    }
+
+    /// Scans the music directory again in the background
+    pub fn rescan_library(&self) {
+        send_music_msg(MusicMsg::RescanLibrary);
+    }
+
+    /// Restores queues and playback position saved from a previous launch
+    fn restore_state(&mut self, state: SavedState) {
+        let Some((queues, current_queue)) = state.restore_queues(&self.all_tracks) else {
+            return;
+        };
+
+        info!("Restored {} queues", queues.len());
+        self.queues = queues;
+        self.current_queue = current_queue;
+        self.shuffle = state.shuffle;
+        self.progress_secs = state.progress_secs;
+    }
+
+    /// Saves queues and playback position so they can be restored on the next launch
+    pub fn save_state(&self) {
+        // Don't overwrite the saved state before the library has loaded
+        if self.all_tracks.is_empty() {
+            return;
+        }
+
+        SavedState::new(
+            &self.queues,
+            &self.all_tracks,
+            self.current_queue,
+            self.shuffle,
+            self.progress_secs,
+        )
+        .save();
+    }
+
+    /// Loads all playlists in the library folders (.m3u files)
     pub fn load_playlists(&mut self) {
-        let files = get_playlist_files(&self.settings.directory).unwrap();
+        let files = get_playlist_files(&self.settings.libraries);
 
         for file in files {
-            let playlist = Playlist::load(&self.settings.directory, &file, &self.all_tracks);
+            let dir = match self.settings.root_of(&file) {
+                Some(root) => root.dir().display().to_string(),
+                None => self.settings.main_directory(),
+            };
+            let playlist = Playlist::load(&dir, &file, &self.all_tracks);
             self.playlists.push(playlist);
         }
     }
@@ -215,12 +374,12 @@ impl MusicController {
 
     /// Saves a playlist in the M3U format
     pub fn save_playlist(&mut self, playlist: usize) {
-        let playlist = self.playlists[playlist].clone();
-        let relative_paths: Vec<String> = playlist
+        let relative_paths: Vec<String> = self.playlists[playlist]
             .tracks
             .iter()
-            .map(|t| relative_path(&self.all_tracks[*t].file, &self.settings.directory))
+            .map(|t| self.playlist_entry(playlist, *t))
             .collect();
+        let playlist = &self.playlists[playlist];
 
         let file = String::from("#EXTM3U\n#PLAYLIST:")
             + &playlist.name
@@ -230,30 +389,267 @@ impl MusicController {
     }
 
     /// Plays a given track
-    pub fn play_track(&mut self, idx: usize) {
-        if let Some(current_track) = self.current_track() {
-            self.listens.push(Listen::new(
-                self.current_track_idx(),
-                self.current_started,
-                current_track.len,
-                self.progress_secs,
-            ));
+    pub fn play_track(&mut self, id: TrackId) {
+        if !self.all_tracks.contains_key(&id) {
+            warn!("Track {id} is not in the library");
+            return;
         }
 
+        // The queue has usually moved on by now, so the listen goes to what was playing instead
+        if let Some(playing) = self.playing_track {
+            if let Some(track) = self.all_tracks.get(&playing) {
+                let listen = Listen::new(playing, self.current_started, track.len, self.progress_secs);
+                self.record_listen(listen);
+            }
+        }
+
+        self.playing_track = Some(id);
         self.current_started = Instant::now();
         self.progress_secs = 0.0;
 
-        send_music_msg(MusicMsg::PlayTrack(self.all_tracks[idx].file.clone()));
+        send_music_msg(MusicMsg::PlayTrack(self.track_load(id)));
         info!("sent music msg to thread");
+        self.prepare_next();
+        self.save_state();
+    }
+
+    /// Returns the track that plays once the current one ends
+    /// Radio queues pick their next track here so that it can be loaded ahead of time
+    pub fn upcoming_track(&mut self) -> Option<TrackId> {
+        let current_queue = &self.queues[self.current_queue];
+
+        if current_queue.end_behaviour == EndBehaviour::RepeatOne {
+            return Some(current_queue.current());
+        }
+
+        if let Some(next) = self.next_playable(current_queue, current_queue.current_track + 1) {
+            return Some(current_queue.track(next));
+        }
+
+        match current_queue.queue_type {
+            QueueType::Radio(_) => {
+                // Radio picks from whichever tracks are analysed so far, but needs at least some
+                if self.track_info.is_empty() {
+                    return None;
+                }
+
+                let next = self.next_similar();
+                self.queues[self.current_queue].cached_order.push(next);
+                return Some(next);
+            }
+            _ => {}
+        }
+
+        match current_queue.end_behaviour {
+            // The reshuffled order isn't known until the queue wraps around, and the queue only
+            // turns into a radio once it actually ends
+            EndBehaviour::Stop | EndBehaviour::Reshuffle | EndBehaviour::Radio => None,
+            EndBehaviour::RepeatQueue | EndBehaviour::RepeatOne => {
+                current_queue.cached_order.get(self.first_playable(current_queue)).cloned()
+            }
+            EndBehaviour::NextQueue => self
+                .queues
+                .get(self.current_queue + 1)
+                .and_then(|queue| queue.cached_order.first().cloned()),
+        }
+    }
+
+    /// Sends the upcoming track to the audio player so it can play without a gap
+    pub fn prepare_next(&mut self) {
+        let Some(next) = self.upcoming_track() else {
+            return;
+        };
+
+        // Album transitions are kept intact, but crossfading out of an album is fine
+        let queue = self.current_queue();
+        let within_album = matches!(queue.queue_type, QueueType::Album(_))
+            && queue.current_track + 1 < queue.len();
+
+        if self.all_tracks.contains_key(&next) {
+            send_music_msg(MusicMsg::PrepareNext(self.track_load(next), !within_album));
+        }
+    }
+
+    /// Builds the message for the audio player to load a track, with its ReplayGain applied
+    pub fn track_load(&self, id: TrackId) -> TrackLoad {
+        TrackLoad {
+            file: self.all_tracks[&id].file.clone(),
+            gain: self.replaygain(id),
+            rate: self.track_rate(id),
+        }
+    }
+
+    /// Playback rate of a track, which is its own remembered rate for long tracks or the rate of
+    /// the current queue otherwise
+    pub fn track_rate(&self, id: TrackId) -> f32 {
+        if let Some(track) = self.all_tracks.get(&id) {
+            if track.len >= LONG_TRACK_SECS {
+                if let Some(rate) = self.track_rates.get(&hash_filename(&track.file)) {
+                    return *rate;
+                }
+            }
+        }
+
+        self.current_queue().rate
+    }
+
+    /// Playback rate of the current track
+    pub fn current_rate(&self) -> f32 {
+        self.track_rate(self.current_track_idx())
+    }
+
+    /// Sets the playback rate, remembering it for the current track if it is long or for the
+    /// current queue otherwise
+    pub fn set_rate(&mut self, rate: f32) {
+        let rate = rate.clamp(0.25, 4.0);
+
+        match self.current_track().filter(|track| track.len >= LONG_TRACK_SECS).cloned() {
+            Some(track) => {
+                if let Err(err) = init_db().and_then(|conn| save_track_rate(&conn, &track.file, rate)) {
+                    error!("Could not save track rate: {err:?}");
+                }
+                self.track_rates.insert(hash_filename(&track.file), rate);
+            }
+            None => self.queues[self.current_queue].rate = rate,
+        }
+
+        send_music_msg(MusicMsg::SetRate(rate));
+        self.prepare_next();
+    }
+
+    /// Sets whether the pitch stays the same at other playback rates, and saves it to storage
+    pub fn set_preserve_pitch(&mut self, preserve_pitch: bool) {
+        self.settings.audio.preserve_pitch = preserve_pitch;
+        send_music_msg(MusicMsg::SetPreservePitch(preserve_pitch));
+        self.settings.save();
+    }
+
+    /// Linear gain a track should be played at, following the ReplayGain settings
+    pub fn replaygain(&self, id: TrackId) -> f32 {
+        let audio = &self.settings.audio;
+        let Some(info) = self.track_info.get(&id) else {
+            return 1.0;
+        };
+
+        let loudness = match audio.replaygain {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => info.loudness,
+            ReplayGainMode::Album => self.album_loudness(id).or(info.loudness),
+        };
+
+        let Some(loudness) = loudness else {
+            return 1.0;
+        };
+
+        let mut gain_db = loudness.gain_db() + audio.preamp;
+        if audio.prevent_clipping && loudness.peak > 0.0 {
+            gain_db = gain_db.min(-20.0 * loudness.peak.log10());
+        }
+
+        10f32.powf(gain_db / 20.0)
+    }
+
+    /// Loudness of the album a track is on, taken from its tags or combined from every
+    /// measured track on the album
+    pub fn album_loudness(&self, id: TrackId) -> Option<Loudness> {
+        if let Some(loudness) = self.track_info.get(&id)?.album_loudness {
+            return Some(loudness);
+        }
+
+        let album = self.all_tracks.get(&id)?.album_id();
+        self.album_loudness_map.get(&album).copied()
+    }
+
+    /// Combines the loudness of every album from its measured tracks
+    fn update_album_loudness(&mut self) {
+        let mut albums: HashMap<AlbumId, Vec<(Loudness, f64)>> = HashMap::new();
+        for (id, track) in &self.all_tracks {
+            let Some(loudness) = self.track_info.get(id).and_then(|info| info.loudness) else {
+                continue;
+            };
+            let album = track.album_id();
+            if !album.title.is_empty() {
+                albums.entry(album).or_default().push((loudness, track.len));
+            }
+        }
+
+        self.album_loudness_map = albums
+            .into_iter()
+            .filter_map(|(album, tracks)| Some((album, Loudness::combine(tracks)?)))
+            .collect();
+    }
+
+    /// Called from the audio thread once a prepared track has started playing
+    /// Moves the queue onto that track without restarting playback
+    pub fn track_advanced(&mut self, file: &str) {
+        let finished = self.playing_track;
+        let Some(next) = self.advance_queue(true) else {
+            return;
+        };
+
+        // The queue was changed after the track was prepared, so play the right one instead
+        if self.all_tracks.get(&next).map_or(true, |track| track.file != file) {
+            self.play_track(next);
+            return;
+        }
+
+        if let Some(finished) = finished {
+            if let Some(track) = self.all_tracks.get(&finished) {
+                let listen = Listen::new(finished, self.current_started, track.len, self.progress_secs);
+                self.record_listen(listen);
+            }
+        }
+
+        self.playing_track = Some(next);
+        self.current_started = Instant::now();
+        self.progress_secs = 0.0;
+        // The track may have been prepared with the rate of the queue before it
+        send_music_msg(MusicMsg::SetRate(self.current_rate()));
+        self.prepare_next();
+        self.save_state();
+    }
+
+    /// Keeps a listen once the track stops, learning how much the track is liked from it
+    fn record_listen(&mut self, listen: Listen) {
+        if let Some(signal) = listen_signal(listen.completion()) {
+            if let Some(track) = self.all_tracks.get(&listen.id()) {
+                let hash = hash_filename(&track.file);
+                let affinity = self.track_affinity.get(&hash).copied().unwrap_or_default().learn(signal, now());
+                self.track_affinity.insert(hash, affinity);
+
+                if let Err(err) = init_db().and_then(|conn| save_track_affinity(&conn, &track.file, affinity)) {
+                    error!("Failed to save track affinity: {err}");
+                }
+            }
+        }
+
+        let queue = &mut self.queues[self.current_queue];
+        if queue.cached_order.contains(&listen.id()) {
+            queue.listens.push(listen.clone());
+        }
+        self.listens.push(listen);
     }
 
     /// Returns the current track weights, or average track weights accross the queue
+    /// Radios started from a set of tracks use those instead of the queue
+    /// Radios move this towards tracks played through this session and away from skipped ones
     pub fn get_space(&mut self) -> TrackInfo {
-        match self.settings.radio.weight_mode {
-            WeightMode::First => self.track_info[self.current_queue().cached_order[0]].clone(),
-            WeightMode::Last => {
-                self.track_info[*self.current_queue().cached_order.iter().last().unwrap()].clone()
-            }
+        if let Some(space) = self.seed_space() {
+            return self.with_session_feedback(space);
+        }
+
+        let space = match self.settings.radio.weight_mode {
+            // Seeds that haven't been analysed yet have no features to go off
+            WeightMode::First => self
+                .track_info
+                .get(&self.current_queue().cached_order[0])
+                .cloned()
+                .unwrap_or_default(),
+            WeightMode::Last => self
+                .track_info
+                .get(self.current_queue().cached_order.iter().last().unwrap())
+                .cloned()
+                .unwrap_or_default(),
             WeightMode::Average => {
                 let mut tracks = Vec::new();
 
@@ -262,7 +658,7 @@ impl MusicController {
                 for i in (count.max(10) - 10)..count {
                     tracks.push(
                         self.track_info
-                            .get(self.current_queue().cached_order[i])
+                            .get(&self.current_queue().cached_order[i])
                             .cloned()
                             .unwrap_or_default(),
                     );
@@ -270,22 +666,63 @@ impl MusicController {
 
                 TrackInfo::average(tracks)
             }
+        };
+
+        self.with_session_feedback(space)
+    }
+
+    /// Space of a radio started from a set of tracks, None if it wasn't or none of them have
+    /// been analysed yet
+    fn seed_space(&self) -> Option<TrackInfo> {
+        let seeds: Vec<&TrackInfo> = self
+            .current_queue()
+            .seeds
+            .iter()
+            .filter_map(|id| self.track_info.get(id))
+            .collect();
+        if seeds.is_empty() {
+            return None;
+        }
+
+        match self.settings.radio.seed_mode {
+            // Each pick goes around a different seed, so the radio moves between all of them
+            SeedMode::Mixture => seeds.choose(&mut thread_rng()).map(|info| (*info).clone()),
+            SeedMode::Centroid => Some(TrackInfo::average(seeds.into_iter().cloned().collect())),
+        }
+    }
+
+    /// Moves a radio's space by the latest listens in its session, the most recent moving it most
+    fn with_session_feedback(&self, mut space: TrackInfo) -> TrackInfo {
+        let queue = self.current_queue();
+        if !matches!(queue.queue_type, QueueType::Radio(_)) {
+            return space;
+        }
+
+        for (listens_ago, listen) in queue.listens.iter().rev().take(SESSION_LISTENS).enumerate() {
+            let Some(signal) = listen_signal(listen.completion()) else {
+                continue;
+            };
+            if let Some(info) = self.track_info.get(&listen.id()) {
+                space = space.moved_towards(info, signal * session_rate(listens_ago));
+            }
         }
+
+        space
     }
 
-    /// Returns all given weights for tracks in the player
+    /// Returns all given weights for tracks in the player, in the same order as `all_tracks`
     pub fn get_weights(&mut self) -> Array1<f32> {
         let space = self.get_space();
 
-        let mut weights = Array1::from_vec(vec![0.0; self.all_tracks.len()]);
-        let mut dists: Vec<(usize, f32)> = self
-            .track_info
-            .iter()
-            .map(|track| genres_dist_from_vec(&track, &space, &self.settings.radio))
-            .enumerate()
-            .collect();
+        let ids: Vec<TrackId> = self.all_tracks.keys().copied().collect();
+        let mut weights = Array1::from_vec(vec![0.0; ids.len()]);
+        let mut dists = self.radio_candidates(&space, &ids);
         dists.sort_by(|(_, a), (_, b)| b.total_cmp(a));
 
+        if self.settings.radio.mode == RadioMode::Harmonic {
+            self.keep_harmonic_matches(&mut dists, &ids);
+        }
+
         let mut count = 0;
         let amount = 20;
         let temperature = 10.0;
@@ -293,10 +730,11 @@ impl MusicController {
             if count == 50 {
                 break;
             }
-            if rank == 0 {
+            // The current track is in the queue too, so it's skipped along with the rest of it
+            if self.current_queue().cached_order.contains(&ids[*song]) {
                 continue;
             }
-            if self.current_queue().cached_order.contains(song) {
+            if self.blocklist.blocks(&self.all_tracks[&ids[*song]]) {
                 continue;
             }
 
@@ -315,17 +753,27 @@ impl MusicController {
             }
         }
 
-        for i in 0..self.all_tracks.len() {
-            let current_idx = self.current_queue().current();
-            if similar(
-                &self.all_tracks[current_idx].album,
-                &self.all_tracks[i].album,
-            ) {
-                weights *= self.settings.radio.album_penalty;
+        let current = self.all_tracks.get(&self.current_queue().current());
+        let now = now();
+        for (i, track) in self.all_tracks.values().enumerate() {
+            if weights[i] == 0.0 {
+                continue;
+            }
+
+            if let Some(affinity) = self.track_affinity.get(&hash_filename(&track.file)) {
+                weights[i] *= affinity.weight(now);
+            }
+            weights[i] *= self.blocklist.weight(track);
+
+            let Some(current) = current else {
+                continue;
+            };
+            if similar(&current.album, &track.album) {
+                weights[i] *= self.settings.radio.album_penalty;
             }
 
-            if self.all_tracks[current_idx].shared_artists(&self.all_tracks[i]) > 0 {
-                weights *= self.settings.radio.artist_penalty;
+            if current.shared_artists(track) > 0 {
+                weights[i] *= self.settings.radio.artist_penalty;
             }
         }
 
@@ -334,12 +782,112 @@ impl MusicController {
         weights
     }
 
+    /// Scores the tracks that could be picked next against the space, by their position in `ids`
+    /// Big libraries only score the nearest tracks found by the index, rather than every track
+    fn radio_candidates(&mut self, space: &TrackInfo, ids: &[TrackId]) -> Vec<(usize, f32)> {
+        let candidates: Vec<TrackId> = if self.track_info.len() <= INDEX_MIN_TRACKS {
+            self.track_info.keys().copied().collect()
+        } else {
+            self.refresh_radio_index();
+            // Tracks already in the queue get skipped, so more are needed to make up for them
+            let mut k = RADIO_CANDIDATES + self.current_queue().cached_order.len();
+            if self.settings.radio.mode == RadioMode::Harmonic {
+                k *= 4;
+            }
+
+            let query = feature_vector(space, &self.settings.radio, &self.feature_stats);
+            self.radio_index
+                .nearest(&query, k, k * INDEX_CHECKS_PER_CANDIDATE)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect()
+        };
+
+        candidates
+            .into_iter()
+            .filter_map(|id| {
+                let i = ids.binary_search(&id).ok()?;
+                let track = self.track_info.get(&id)?;
+                Some((i, genres_dist_from_vec(track, space, &self.settings.radio, &self.feature_stats)))
+            })
+            .collect()
+    }
+
+    /// Builds the radio index again if the radio settings or feature stats have changed since
+    /// it was built
+    fn refresh_radio_index(&mut self) {
+        if let Some((settings, stats)) = &self.radio_index_settings {
+            if *settings == self.settings.radio && *stats == self.feature_stats {
+                return;
+            }
+        }
+
+        let (settings, stats) = (&self.settings.radio, &self.feature_stats);
+        self.radio_index = SharedIndex::new(KdTree::new(
+            self.track_info
+                .iter()
+                .map(|(id, info)| (*id, feature_vector(info, settings, stats)))
+                .collect(),
+        ));
+        self.radio_index_settings = Some((settings.clone(), stats.clone()));
+    }
+
+    /// Adds analysed tracks, keeping the radio index up to date
+    pub fn add_track_info(&mut self, track_info: impl IntoIterator<Item = (TrackId, TrackInfo)>) {
+        let mut measured = false;
+        for (id, info) in track_info {
+            if let Some((settings, stats)) = &self.radio_index_settings {
+                self.radio_index.make_mut().insert(id, feature_vector(&info, settings, stats));
+            }
+            measured |= info.loudness.is_some() && info.album_loudness.is_none();
+            self.track_info.insert(id, info);
+        }
+
+        if measured {
+            self.update_album_loudness();
+        }
+    }
+
+    /// Works out the feature stats again from every analysed track and saves them
+    pub fn update_feature_stats(&mut self) {
+        self.feature_stats = FeatureStats::from_tracks(self.track_info.values());
+        if let Err(err) = init_db().and_then(|conn| save_feature_stats(&conn, &self.feature_stats)) {
+            error!("Could not save feature stats: {err}");
+        }
+    }
+
+    /// Narrows down radio candidates to tracks that mix well out of the last track in the queue
+    /// Candidates are left alone if none of them match, so the radio doesn't stop
+    fn keep_harmonic_matches(&self, dists: &mut Vec<(usize, f32)>, ids: &[TrackId]) {
+        let Some(last) = self.current_queue().cached_order.last() else {
+            return;
+        };
+        let Some(last) = self.track_info.get(last) else {
+            return;
+        };
+
+        let matches: Vec<(usize, f32)> = dists
+            .iter()
+            .filter(|(i, _)| harmonic_match(last, &self.track_info[&ids[*i]], &self.settings.radio))
+            .copied()
+            .collect();
+
+        if matches.is_empty() {
+            warn!("No harmonic matches, falling back to similar tracks");
+        } else {
+            *dists = matches;
+        }
+    }
+
     /// Returns the next 'similar' track to play
-    pub fn next_similar(&mut self) -> usize {
+    pub fn next_similar(&mut self) -> TrackId {
         log::info!("next");
         let mut weights = self.get_weights().to_vec();
         if weights.iter().all(|w| w.is_zero()) {
             warn!("All weights zero");
+            weights = self.all_tracks.values().map(|track| self.blocklist.weight(track)).collect();
+        }
+        if weights.iter().all(|w| w.is_zero()) {
             weights = vec![1.0; weights.len()];
         }
         let dist = WeightedIndex::new(weights.clone()).unwrap();
@@ -347,7 +895,7 @@ impl MusicController {
 
         let next = dist.sample(&mut rng);
         info!("chosen weight {}", weights[next]);
-        next
+        self.all_tracks.keys().nth(next).copied().unwrap_or_default()
     }
 
     /// Skips to the previous song in queue
@@ -370,36 +918,129 @@ impl MusicController {
             return;
         }
 
-        let current_queue = &mut self.queues[self.current_queue];
-
-        // next track exists in queue
-        if let Some(next) = current_queue
-            .cached_order
-            .get(current_queue.current_track + 1)
-            .cloned()
-        {
-            current_queue.current_track += 1;
+        if let Some(next) = self.advance_queue(false) {
             self.play_track(next);
+        }
+
+        info!("successfully skipped song");
+    }
+
+    /// Called once the current track has finished playing by itself
+    pub fn track_finished(&mut self) {
+        if self.all_tracks.is_empty() {
             return;
         }
 
+        match self.advance_queue(true) {
+            Some(next) => self.play_track(next),
+            None => {
+                // Nothing left to play, so rewind to the start of the queue ready to play again
+                self.pause();
+                let queue = &mut self.queues[self.current_queue];
+                queue.current_track = 0;
+                if let Some(first) = queue.cached_order.first().cloned() {
+                    self.play_track(first);
+                }
+            }
+        }
+    }
+
+    /// Moves the current queue onto its next track, following the queue's end behaviour once it
+    /// runs out of tracks
+    /// Returns the track that should now be playing
+    fn advance_queue(&mut self, finished: bool) -> Option<TrackId> {
+        let current_queue = &self.queues[self.current_queue];
+
+        // Only a track ending by itself gets repeated, skipping still moves on
+        if finished && current_queue.end_behaviour == EndBehaviour::RepeatOne {
+            return Some(current_queue.current());
+        }
+
+        // next track exists in queue
+        if let Some(next) = self.next_playable(current_queue, current_queue.current_track + 1) {
+            let current_queue = &mut self.queues[self.current_queue];
+            current_queue.current_track = next;
+            return Some(current_queue.current());
+        }
+
+        let first = self.first_playable(current_queue);
+        let current_queue = &mut self.queues[self.current_queue];
+
         match current_queue.queue_type {
             QueueType::Radio(_) => {
                 let next = self.next_similar();
                 self.queues[self.current_queue].current_track += 1;
                 self.queues[self.current_queue].cached_order.push(next);
-                self.play_track(next);
+                return Some(next);
+            }
+            _ => {}
+        }
+
+        match current_queue.end_behaviour {
+            EndBehaviour::Stop => None,
+            EndBehaviour::RepeatQueue | EndBehaviour::RepeatOne => {
+                current_queue.current_track = first;
+                current_queue.cached_order.get(first).cloned()
+            }
+            EndBehaviour::Reshuffle => {
+                let weight = |id: TrackId| self.blocklist.weight(&self.all_tracks[&id]);
+                let tracks = std::mem::take(&mut current_queue.cached_order);
+                current_queue.cached_order = weighted_shuffle(tracks, weight);
+                current_queue.current_track = 0;
+                current_queue.cached_order.first().cloned()
             }
-            _ => {
+            EndBehaviour::NextQueue => {
                 if self.queues.len() > self.current_queue + 1 {
                     self.current_queue += 1;
                     // TODO: shuffle next queue if needed
-                    self.play_track(self.current_queue().track(0))
+                    self.queues[self.current_queue].current_track = 0;
+                    Some(self.current_queue().track(0))
+                } else {
+                    None
                 }
             }
+            EndBehaviour::Radio => {
+                self.queue_to_radio(self.current_queue);
+                self.advance_queue(finished)
+            }
         }
+    }
 
-        info!("successfully skipped song");
+    /// Turns a queue into a radio seeded from the tracks already in it
+    fn queue_to_radio(&mut self, queue: usize) {
+        let queue = &mut self.queues[queue];
+        if !matches!(queue.queue_type, QueueType::Radio(_)) {
+            queue.queue_type = QueueType::Radio(queue.queue_type.to_string());
+            queue.seeds = queue.cached_order.clone();
+        }
+    }
+
+    /// Position of the first track from a given one on that is allowed to play
+    /// Blocked tracks are skipped over, so they only play when the user picks them themselves
+    /// Radios never pick blocked tracks in the first place
+    fn next_playable(&self, queue: &Queue, from: usize) -> Option<usize> {
+        let skip_blocked = !matches!(queue.queue_type, QueueType::Radio(_));
+        (from..queue.len()).find(|idx| {
+            !skip_blocked
+                || !self
+                    .all_tracks
+                    .get(&queue.track(*idx))
+                    .is_some_and(|track| self.blocklist.blocks(track))
+        })
+    }
+
+    /// Position a queue starts again from when it repeats, which is its first track unless
+    /// it's blocked
+    fn first_playable(&self, queue: &Queue) -> usize {
+        self.next_playable(queue, 0).unwrap_or(0)
+    }
+
+    /// Sets what a queue does once it runs out of tracks
+    pub fn set_end_behaviour(&mut self, queue: usize, end_behaviour: EndBehaviour) {
+        self.queues[queue].end_behaviour = end_behaviour;
+        if queue == self.current_queue {
+            self.prepare_next();
+        }
     }
 
     /// Sets the current queue playing and at which track
@@ -410,16 +1051,15 @@ impl MusicController {
     }
 
     /// Returns tracks matching a certain QueueType
-    pub fn get_matching(&self, queue_type: QueueType) -> Vec<usize> {
+    pub fn get_matching(&self, queue_type: QueueType) -> Vec<TrackId> {
         if queue_type == QueueType::AllTracks {
-            return (0..self.all_tracks.len()).collect();
+            return self.all_tracks.keys().copied().collect();
         }
 
         self.all_tracks
             .iter()
-            .enumerate()
             .filter(|(_, track)| track.matches(queue_type.clone()))
-            .map(|(index, _)| index)
+            .map(|(id, _)| *id)
             .collect()
     }
 
@@ -430,6 +1070,7 @@ impl MusicController {
             self.current_queue -= 1;
         }
         self.queues.remove(queue);
+        self.prepare_next();
     }
 
     /// Creates a playlist using tracks in a given queue
@@ -437,7 +1078,7 @@ impl MusicController {
         let queue = self.queues[queue].clone();
         let mut playlist = Playlist::new(
             format!("{}", queue.queue_type),
-            self.settings.directory.clone(),
+            self.settings.main_directory(),
         );
         playlist.tracks = queue.cached_order;
         self.playlists.push(playlist);
@@ -447,36 +1088,39 @@ impl MusicController {
     }
 
     /// Adds a list of tracks to a given queue
-    pub fn add_tracks_to_queue(&mut self, queue: usize, tracks: Vec<usize>) {
+    pub fn add_tracks_to_queue(&mut self, queue: usize, tracks: Vec<TrackId>) {
         self.queues[queue].cached_order.extend(tracks);
+        self.prepare_next();
     }
 
     /// Adds a list of tracks to a given playlist
-    pub fn add_tracks_to_playlist(&mut self, playlist: usize, tracks: Vec<usize>) {
+    pub fn add_tracks_to_playlist(&mut self, playlist: usize, tracks: Vec<TrackId>) {
         self.playlists[playlist].tracks.extend(tracks);
     }
 
     /// Updates track tag in memory and saves it to storage 
-    pub fn update_tag(&mut self, track: usize, tag: Track) {
-        if tag == self.all_tracks[track] {
+    pub fn update_tag(&mut self, track: TrackId, tag: Track) {
+        if tag == self.all_tracks[&track] {
             info!("Nothing to update with tag");
             return;
         }
 
-        let old_album = self.all_tracks[track].album.clone();
-        let old_artists = self.all_tracks[track].artists.clone();
+        let old_album = self.all_tracks[&track].album_id();
+        let new_album = tag.album_id();
+        let old_artists = self.all_tracks[&track].artists.clone();
+        let album_changed = old_album != new_album;
 
-        if old_album != tag.album {
+        if album_changed {
             if self.albums[&old_album].0 == 1 {
                 self.albums.remove(&old_album);
             } else {
                 if let Some(val) = self.albums.get_mut(&old_album) { val.0 -= 1; };
             }
 
-            if self.albums.contains_key(&tag.album) {
-                if let Some(val) = self.albums.get_mut(&tag.album) { val.0 += 1; };
+            if self.albums.contains_key(&new_album) {
+                if let Some(val) = self.albums.get_mut(&new_album) { val.0 += 1; };
             } else {
-                self.albums.insert(tag.album.clone(), (1, track));
+                self.albums.insert(new_album, (1, track));
             }
         }
 
@@ -500,6 +1144,9 @@ impl MusicController {
             }
         }
 
+        // Written to disk first so the cache holds the file's new stamp
+        tag.save_to_disk().unwrap();
+
         let db = init_db();
         if let Ok(ref database) = db {
             save_to_cache(&database, &tag).unwrap();
@@ -508,9 +1155,10 @@ impl MusicController {
         }
         drop(db);
 
-        tag.save_to_disk().unwrap();
-
-        self.all_tracks[track] = tag;
+        self.all_tracks.insert(track, tag);
+        if album_changed {
+            self.update_album_loudness();
+        }
     }
 }
 
@@ -525,31 +1173,62 @@ impl MusicController {
     }
     
     /// Starts an album queue starting with a specified track
-    pub fn play_album_at(&mut self, album: String, track: usize) {
-        let tracks = self.get_tracks_where(|track| track.album == album);
-        self.add_queue_at(tracks, QueueType::Album(album.clone()), track);
+    pub fn play_album_at(&mut self, album: AlbumId, track: TrackId) {
+        let mut tracks = self.get_tracks_where(|track| track.album_id() == album);
+        tracks.sort_by_key(|id| self.all_tracks[id].album_position());
+        self.add_queue_at(tracks, QueueType::Album(album), track);
     }
 
     /// Starts an genre queue starting with a specified track
-    pub fn play_genre_at(&mut self, genre: String, track: usize) {
+    pub fn play_genre_at(&mut self, genre: String, track: TrackId) {
         let tracks = self.get_tracks_where(|track| track.has_genre(&genre));
         self.add_queue_at(tracks, QueueType::Genre(genre.clone()), track);
     }
 
     /// Starts an artist queue starting with a specified track
-    pub fn play_artist_at(&mut self, artist: String, track: usize) {
+    pub fn play_artist_at(&mut self, artist: String, track: TrackId) {
         let tracks = self.get_tracks_where(|track| track.has_artist(&artist));
         self.add_queue_at(tracks, QueueType::Artist(artist.clone()), track);
     }
 
     /// Starts a radio queue with a specified starting track
-    pub fn start_radio(&mut self, track: usize) {
-        let track_name = self.all_tracks[track].title.clone();
+    pub fn start_radio(&mut self, track: TrackId) {
+        let track_name = self.all_tracks[&track].title.clone();
         self.add_queue_at(vec![track], QueueType::Radio(track_name), track);
     }
 
+    /// Starts a radio queue picking around a set of tracks, like an artist, album or playlist
+    pub fn start_radio_from(&mut self, seeds: Vec<TrackId>, name: String) {
+        let mut rng = thread_rng();
+        // Starts on one of the tracks that's allowed to play, if there are any
+        let start = seeds
+            .iter()
+            .filter(|id| self.all_tracks.get(*id).is_some_and(|track| !self.blocklist.blocks(track)))
+            .choose(&mut rng)
+            .or_else(|| seeds.choose(&mut rng))
+            .copied();
+        let Some(start) = start else {
+            return;
+        };
+
+        let queue = Queue::seeded_radio(start, name, seeds);
+        match self.queues.iter().position(|other| other.queue_type == queue.queue_type) {
+            Some(i) => {
+                self.queues[i] = queue;
+                self.current_queue = i;
+            }
+            None => {
+                self.queues.push(queue);
+                self.current_queue = self.queues.len() - 1;
+            }
+        }
+
+        self.play_track(start);
+        self.play();
+    }
+
     /// Starts a playlist, with a given track to start
-    pub fn play_playlist_at(&mut self, playlist: usize, track: usize) {
+    pub fn play_playlist_at(&mut self, playlist: usize, track: TrackId) {
         self.add_queue_at(
             self.playlists[playlist].tracks.clone(),
             QueueType::Playlist(self.playlists[playlist].name.clone(), playlist),
@@ -558,7 +1237,7 @@ impl MusicController {
     }
 
     /// Starts an autoplaylist, with a given track to start
-    pub fn play_autoplaylist_at(&mut self, tracks: Vec<usize>, autoplaylist: usize, track: usize) {
+    pub fn play_autoplaylist_at(&mut self, tracks: Vec<TrackId>, autoplaylist: usize, track: TrackId) {
         self.add_queue_at(
             tracks,
             QueueType::AutoPlaylist(self.autoplaylists[autoplaylist].name.clone(), autoplaylist),
@@ -567,9 +1246,9 @@ impl MusicController {
     }
 
     /// Starts a given queue with some tracks at a specific track
-    pub fn add_queue_at(&mut self, mut tracks: Vec<usize>, queue: QueueType, track: usize) {
+    pub fn add_queue_at(&mut self, mut tracks: Vec<TrackId>, queue: QueueType, track: TrackId) {
         if self.shuffle {
-            tracks = shuffle_with_first(tracks, track);
+            tracks = shuffle_with_first(tracks, track, |id| self.blocklist.weight(&self.all_tracks[&id]));
         }
 
         info!("{track}");
@@ -594,22 +1273,67 @@ impl MusicController {
         self.play();
     }
 
+    /// Tracks that fit an autoplaylist's conditions, leaving out ones that should never be played
+    pub fn autoplaylist_tracks(&self, autoplaylist: usize) -> Vec<TrackId> {
+        self.autoplaylists[autoplaylist]
+            .conditions
+            .qualify_tracks(&self.all_tracks, &self.track_info)
+            .into_iter()
+            .filter(|id| !self.blocklist.blocks(&self.all_tracks[id]))
+            .collect()
+    }
+
+    /// Sets or clears the blocklist entry for a track file, artist or genre and saves it
+    pub fn set_block(&mut self, kind: BlockKind, name: &str, level: Option<BlockLevel>) {
+        self.blocklist.set(kind, name, level);
+        if let Err(err) = init_db().and_then(|conn| save_block(&conn, kind, name, level)) {
+            error!("Failed to save blocklist entry: {err}");
+        }
+
+        let blocked = |id: &TrackId| self.all_tracks.get(id).is_some_and(|track| self.blocklist.blocks(track));
+        for queue in &mut self.queues {
+            let next = queue.current_track + 1;
+            if next >= queue.cached_order.len() {
+                continue;
+            }
+
+            match queue.queue_type {
+                // Radios pick their next track early, which may be one that shouldn't play anymore
+                QueueType::Radio(_) => {
+                    if blocked(&queue.cached_order[next]) {
+                        queue.cached_order.remove(next);
+                    }
+                }
+                // Shuffled queues keep newly blocked tracks, but move them to the end with the
+                // rest so they are skipped over
+                _ if self.shuffle => {
+                    let (allowed, held_back): (Vec<TrackId>, Vec<TrackId>) =
+                        queue.cached_order[next..].iter().copied().partition(|id| !blocked(id));
+                    queue.cached_order.truncate(next);
+                    queue.cached_order.extend(allowed);
+                    queue.cached_order.extend(held_back);
+                }
+                _ => {}
+            }
+        }
+        self.prepare_next();
+    }
+
     /// Add a queue containing all tracks, with a given track to start
-    pub fn add_all_queue(&mut self, track: usize) {
-        let tracks = (0..self.all_tracks.len()).collect();
+    pub fn add_all_queue(&mut self, track: TrackId) {
+        let tracks = self.all_tracks.keys().copied().collect();
         self.add_queue_at(tracks, QueueType::AllTracks, track);
     }
 
     /// Get tracks that fit a given conditional, using a supplied Fn
-    pub fn get_tracks_where<F>(&self, condition: F) -> Vec<usize>
+    pub fn get_tracks_where<F>(&self, condition: F) -> Vec<TrackId>
     where
         F: Fn(&Track) -> bool,
     {
         self.all_tracks
             .iter()
-            .enumerate()
             .filter(|(_, track)| condition(*track))
-            .map(|(idx, _)| idx)
+            .map(|(id, _)| *id)
             .collect()
     }
 
@@ -618,15 +1342,15 @@ impl MusicController {
         if self.shuffle {
             // unshuffle queues
             for queue in &mut self.queues {
-                let current = queue.cached_order[queue.current_track];
+                let Some(current) = queue.cached_order.get(queue.current_track).copied() else {
+                    continue;
+                };
 
                 match queue.queue_type {
                     QueueType::Radio(_) => {}
-                    QueueType::Album(_) => queue.cached_order.sort_by(|a, b| {
-                        self.all_tracks[*a]
-                            .trackno
-                            .cmp(&self.all_tracks[*b].trackno)
-                    }),
+                    QueueType::Album(_) => queue
+                        .cached_order
+                        .sort_by_key(|id| self.all_tracks[id].album_position()),
                     _ => queue.cached_order.sort_by(|a, b| a.cmp(b)),
                 }
 
@@ -635,37 +1359,83 @@ impl MusicController {
                 queue.current_track = new_idx.unwrap_or(0);
             }
         } else {
+            let weight = |id: TrackId| self.blocklist.weight(&self.all_tracks[&id]);
             for queue in &mut self.queues {
                 if let QueueType::Radio(_) = queue.queue_type {
                     // Painful to try and unshuffle radio queues
                     continue;
                 }
+                if queue.len() == 0 {
+                    continue;
+                }
 
                 queue.cached_order =
-                    shuffle_with_first(queue.cached_order.clone(), queue.current());
+                    shuffle_with_first(queue.cached_order.clone(), queue.current(), weight);
                 queue.current_track = 0;
             }
         }
 
-        self.shuffle = !self.shuffle
+        self.shuffle = !self.shuffle;
+        self.prepare_next();
     }
 
     /// Adds a track to the spot after the current track in queue
-    pub fn play_next(&mut self, track: usize) {
+    pub fn play_next(&mut self, track: TrackId) {
         let position = self.current_queue().current_track;
         self.mut_current_queue()
             .cached_order
             .insert(position + 1, track);
+        self.prepare_next();
     }
 
     /// Adds a track to a given playlist
-    pub fn add_to_playlist(&mut self, playlist: usize, track: usize) {
-        let file = relative_path(&self.all_tracks[track].file, &self.settings.directory);
+    pub fn add_to_playlist(&mut self, playlist: usize, track: TrackId) {
+        let file = self.playlist_entry(playlist, track);
         info!("{file}");
         self.playlists[playlist].tracks.push(track);
         self.playlists[playlist].track_paths.push(file);
         self.save_playlist(playlist);
     }
+
+    /// The path a playlist stores for a track, relative to the playlist's library folder
+    /// when the track is inside it, and absolute otherwise
+    fn playlist_entry(&self, playlist: usize, track: TrackId) -> String {
+        let file = &self.all_tracks[&track].file;
+        match self.settings.root_of(&self.playlists[playlist].file) {
+            Some(root) if root.contains(Path::new(file)) => {
+                relative_path(file, &root.dir().display().to_string())
+            }
+            _ => file.clone(),
+        }
+    }
+}
+
+/// Counts the tracks in each album, artist and genre of a library
+/// Albums also keep their first track, which is used for their artwork
+fn library_counts(
+    all_tracks: &BTreeMap<TrackId, Track>,
+) -> (
+    HashMap<AlbumId, (usize, TrackId)>,
+    HashMap<String, (String, usize)>,
+    HashMap<String, usize>,
+) {
+    let (mut albums, mut artists, mut genres) = (HashMap::new(), HashMap::new(), HashMap::new());
+
+    for (id, track) in all_tracks {
+        for genre in track.genres.clone() {
+            *genres.entry(genre.clone()).or_insert(0) += 1;
+        }
+
+        for artist in track.artists.clone() {
+            // some artists names seem to change captalization grr
+            let stripped = strip_unnessecary(&artist);
+            artists.entry(stripped).or_insert((artist, 0)).1 += 1;
+        }
+
+        albums.entry(track.album_id()).or_insert((0, *id)).0 += 1;
+    }
+
+    (albums, artists, genres)
 }
 
 /// Returns the part of two paths that they do not share
@@ -686,20 +1456,40 @@ pub fn relative_path(file: &str, dir: &str) -> String {
 
 /// Shuffles a list while keeping an item at the start
 /// Used so that the shuffle button does not immediately play a new track
-pub fn shuffle_with_first(mut tracks: Vec<usize>, start: usize) -> Vec<usize> {
+pub fn shuffle_with_first(
+    mut tracks: Vec<TrackId>,
+    start: TrackId,
+    weight: impl Fn(TrackId) -> f32,
+) -> Vec<TrackId> {
     if let Some(idx) = tracks.iter().position(|e| *e == start) {
         tracks.remove(idx);
     }
 
-    // Probably could use a nicer shuffle method later on
-    let mut rng = thread_rng();
-    tracks.shuffle(&mut rng);
-
+    let mut tracks = weighted_shuffle(tracks, weight);
     tracks.insert(0, start);
 
     tracks
 }
 
+/// Shuffles a list so that tracks with a lower weight tend to end up later on
+/// Ones weighted 0 are put at the very end, so that they are still there once the queue is
+/// unshuffled
+pub fn weighted_shuffle(tracks: Vec<TrackId>, weight: impl Fn(TrackId) -> f32) -> Vec<TrackId> {
+    let mut rng = thread_rng();
+    // Sorting by a random number raised to one over the weight gives a weighted shuffle, and
+    // weight 0 tracks get negative keys to sort after every other track
+    let mut keyed: Vec<(f32, TrackId)> = tracks
+        .iter()
+        .map(|id| match weight(*id) {
+            weight if weight > 0.0 => (rng.gen::<f32>().powf(1.0 / weight), *id),
+            _ => (-rng.gen::<f32>(), *id),
+        })
+        .collect();
+
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.into_iter().map(|(_, id)| id).collect()
+}
+
 // Settings Management
 impl MusicController {
     /// Sets the volume of the music player and saves it to storage
@@ -710,11 +1500,142 @@ impl MusicController {
         info!("Set volume to {volume}");
     }
 
-    /// Sets the music directory, and saves it to storage
-    pub fn set_directory(&mut self, new_dir: String) {
-        self.settings.directory = new_dir;
+    /// Sets the crossfade length between tracks and saves it to storage
+    pub fn set_crossfade(&mut self, seconds: f32) {
+        self.settings.audio.crossfade = seconds;
+        send_music_msg(MusicMsg::SetCrossfade(seconds));
+        self.settings.save();
+    }
+
+    /// Sets how radio queues pick their tracks and saves it to storage
+    pub fn set_radio_mode(&mut self, mode: RadioMode) {
+        self.settings.radio.mode = mode;
+        self.settings.save();
+    }
+
+    /// Sets how radios started from many tracks pick around them and saves it to storage
+    pub fn set_seed_mode(&mut self, mode: SeedMode) {
+        self.settings.radio.seed_mode = mode;
+        self.settings.save();
+    }
+
+    /// Sets which ReplayGain values are used and saves it to storage
+    /// The new gain is used from the next track onwards
+    pub fn set_replaygain(&mut self, mode: ReplayGainMode) {
+        self.settings.audio.replaygain = mode;
+        self.settings.save();
+        self.prepare_next();
+    }
+
+    /// Sets the gain in dB added on top of ReplayGain and saves it to storage
+    pub fn set_preamp(&mut self, preamp: f32) {
+        self.settings.audio.preamp = preamp;
         self.settings.save();
-        // Manage loading new tracks
+        self.prepare_next();
+    }
+
+    /// Sets whether ReplayGain is limited to avoid clipping and saves it to storage
+    pub fn set_prevent_clipping(&mut self, prevent_clipping: bool) {
+        self.settings.audio.prevent_clipping = prevent_clipping;
+        self.settings.save();
+        self.prepare_next();
+    }
+
+    /// Switches the output device, None being the system default, and saves it to storage
+    pub fn set_output_device(&mut self, device: Option<String>) {
+        self.settings.audio.output_device = device.clone();
+        send_music_msg(MusicMsg::SetOutputDevice(device));
+        self.settings.save();
+    }
+
+    /// Equalizer bands the audio player should use, none if the equalizer is off
+    pub fn equalizer_bands(&self) -> Vec<EqBand> {
+        let equalizer = &self.settings.audio.equalizer;
+        if equalizer.enabled {
+            equalizer.bands.clone()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Sends the equalizer to the audio player and saves it to storage
+    fn update_equalizer(&mut self) {
+        send_music_msg(MusicMsg::SetEqualizer(self.equalizer_bands()));
+        self.settings.save();
+    }
+
+    /// Turns the equalizer on or off
+    pub fn set_equalizer_enabled(&mut self, enabled: bool) {
+        self.settings.audio.equalizer.enabled = enabled;
+        self.update_equalizer();
+    }
+
+    /// Sets the gain of a single equalizer band in dB
+    pub fn set_equalizer_gain(&mut self, band: usize, gain: f32) {
+        if let Some(band) = self.settings.audio.equalizer.bands.get_mut(band) {
+            band.gain = gain;
+            self.update_equalizer();
+        }
+    }
+
+    /// Loads the bands of an equalizer preset by name
+    pub fn load_equalizer_preset(&mut self, name: &str) {
+        let equalizer = &mut self.settings.audio.equalizer;
+        if let Some(preset) = equalizer.presets.iter().find(|p| p.name == name) {
+            equalizer.bands = preset.bands.clone();
+            equalizer.preset = preset.name.clone();
+            self.update_equalizer();
+        }
+    }
+
+    /// Saves the current equalizer bands as a preset, replacing any preset with the same name
+    pub fn save_equalizer_preset(&mut self, name: String) {
+        let equalizer = &mut self.settings.audio.equalizer;
+        let preset = EqPreset { name: name.clone(), bands: equalizer.bands.clone() };
+
+        match equalizer.presets.iter_mut().find(|p| p.name == name) {
+            Some(existing) => *existing = preset,
+            None => equalizer.presets.push(preset),
+        }
+
+        equalizer.preset = name;
+        self.settings.save();
+    }
+
+    /// Sets the library folders, and saves them to storage
+    pub fn set_libraries(&mut self, libraries: Vec<LibraryRoot>) {
+        self.settings.libraries = libraries;
+        self.settings.save();
+        self.rescan_library();
+    }
+
+    /// Sets how much of each track is analysed and saves it to storage
+    /// Rescans the library so tracks analysed with the old mode get redone
+    pub fn set_analysis_mode(&mut self, mode: AnalysisMode) {
+        self.settings.analysis.mode = mode;
+        self.settings.save();
+        self.rescan_library();
+    }
+
+    /// Pauses or resumes analysing the library
+    pub fn pause_analysis(&mut self, paused: bool) {
+        ANALYSIS_CONTROL.set_paused(paused);
+        self.analysis.paused = paused;
+    }
+
+    /// Stops analysing the library, keeping the tracks that are already done
+    pub fn cancel_analysis(&mut self) {
+        ANALYSIS_CONTROL.cancel();
+        self.analysis.running = false;
+        self.analysis.paused = false;
+    }
+
+    /// Tries analysing the files that failed before again
+    pub fn retry_failed_analysis(&mut self) {
+        if let Err(err) = init_db().and_then(|conn| clear_analysis_failures(&conn)) {
+            error!("Could not clear analysis failures: {err}");
+        }
+        self.rescan_library();
     }
 
     /// Sets the 'temperature' of the reccomendation system
@@ -727,8 +1648,8 @@ impl MusicController {
 // Small functions
 impl MusicController {
     /// Returns the index of an album in the controller's inner list
-    pub fn get_album_index(&self, album: &str) -> usize {
-        self.albums.iter().position(|a| similar(album, a.0)).unwrap_or(0)
+    pub fn get_album_index(&self, album: &AlbumId) -> usize {
+        self.albums.iter().position(|a| album.similar(a.0)).unwrap_or(0)
     }
 
     /// Toggles between playing and paused
@@ -747,6 +1668,7 @@ impl MusicController {
     pub fn pause(&mut self) {
         send_music_msg(MusicMsg::Pause);
         self.playing = false;
+        self.save_state();
     }
 
     /// Is the music player currently playing a track?
@@ -754,19 +1676,19 @@ impl MusicController {
         self.playing
     }
 
-    /// Returns the index of the currently playing track
-    pub fn current_track_idx(&self) -> usize {
+    /// Returns the id of the currently playing track
+    pub fn current_track_idx(&self) -> TrackId {
         self.current_queue().current()
     }
 
     /// Gets a reference to the currently playing track
     pub fn current_track(&self) -> Option<&Track> {
-        self.all_tracks.get(self.current_queue().current())
+        self.all_tracks.get(&self.current_queue().current())
     }
 
     /// Gets a reference to a given track
-    pub fn get_track(&self, idx: usize) -> Option<&Track> {
-        self.all_tracks.get(idx)
+    pub fn get_track(&self, id: TrackId) -> Option<&Track> {
+        self.all_tracks.get(&id)
     }
 
     /// Returns the current track's title
@@ -796,8 +1718,8 @@ impl MusicController {
 
     /// Returns the index for the album of the currently playing track
     pub fn current_album_idx(&self) -> usize {
-        let album = &self.current_track().unwrap().album;
-        self.albums.iter().position(|e| *e.0 == *album).unwrap()
+        let album = self.current_track().unwrap().album_id();
+        self.albums.iter().position(|e| *e.0 == album).unwrap()
     }
 
     /// Tries to access the next track in the queue
@@ -806,7 +1728,7 @@ impl MusicController {
         let current_queue = &self.queues[self.current_queue];
         Some(
             self.all_tracks
-                .get(*current_queue.cached_order.get(0)?)?
+                .get(current_queue.cached_order.get(0)?)?
                 .clone(),
         )
     }
@@ -833,24 +1755,157 @@ impl MusicController {
     }
 }
 
-/// Applies setting weights to given features
-pub fn genres_dist_from_vec(lhs: &TrackInfo, rhs: &TrackInfo, settings: &RadioSettings) -> f32 {
-    let mfcc_sim = cosine_similarity(lhs.mfcc.clone(), rhs.mfcc.clone());
-    let chroma_sim = cosine_similarity(lhs.mfcc.clone(), rhs.mfcc.clone());
-    let spectral_sim = cosine_similarity(lhs.mfcc.clone(), rhs.mfcc.clone());
-    let energy_sim = relative_similarity(lhs.energy, rhs.energy).min(1.0);
-    let bpm_sim = relative_similarity(lhs.bpm, rhs.bpm).min(1.0);
-    let zcr_sim = relative_similarity(lhs.zcr, rhs.zcr);
-
-    (mfcc_sim * settings.mfcc_weight)
-        + (chroma_sim * settings.chroma_weight)
-        + (spectral_sim * settings.mfcc_weight)
-        + (energy_sim * settings.energy_weight)
-        + (bpm_sim * settings.bpm_weight)
-        + (zcr_sim * settings.zcr_weight)
+/// Scores how alike two tracks are from 0 to 1, using the weights in the radio settings
+/// Features are z-scored against the whole library first so they're all on the same scale,
+/// which makes each weight how much that feature matters compared to the others
+pub fn genres_dist_from_vec(
+    lhs: &TrackInfo,
+    rhs: &TrackInfo,
+    settings: &RadioSettings,
+    stats: &FeatureStats,
+) -> f32 {
+    let (lhs_z, rhs_z) = (stats.normalize(lhs), stats.normalize(rhs));
+    let vector_sim = |a: &Array1<f32>, b: &Array1<f32>| (cosine_similarity(a.clone(), b.clone()) + 1.0) / 2.0;
+    let scalar_sim = |a: f32, b: f32| (-(a - b).powi(2) / 2.0).exp();
+
+    let mut mfcc_sim = vector_sim(&lhs_z.mfcc, &rhs_z.mfcc);
+    let chroma_sim = vector_sim(&lhs_z.chroma, &rhs_z.chroma);
+    let spectral_sim = vector_sim(&lhs_z.spectral, &rhs_z.spectral);
+    let mut energy_sim = scalar_sim(lhs_z.energy, rhs_z.energy);
+    let bpm_sim = scalar_sim(lhs_z.bpm, rhs_z.bpm);
+    let zcr_sim = scalar_sim(lhs_z.zcr, rhs_z.zcr);
+    // Tracks analysed in several parts also get compared on how much they change
+    if !lhs_z.mfcc_var.is_empty() && !rhs_z.mfcc_var.is_empty() {
+        mfcc_sim = (mfcc_sim + vector_sim(&lhs_z.mfcc_var, &rhs_z.mfcc_var)) / 2.0;
+        energy_sim = (energy_sim + scalar_sim(lhs_z.energy_var, rhs_z.energy_var)) / 2.0;
+    }
+    // Tracks without a detected key are treated as neither matching nor clashing
+    let key_sim = if lhs.key_confidence > 0.0 && rhs.key_confidence > 0.0 {
+        key_similarity(lhs.key, rhs.key)
+    } else {
+        0.5
+    };
+
+    let weighted = [
+        (mfcc_sim, settings.mfcc_weight),
+        (chroma_sim, settings.chroma_weight),
+        (spectral_sim, settings.spectral_weight),
+        (energy_sim, settings.energy_weight),
+        (bpm_sim, settings.bpm_weight),
+        (zcr_sim, settings.zcr_weight),
+        (key_sim, settings.key_weight),
+    ];
+    let total_weight: f32 = weighted.iter().map(|(_, weight)| weight.max(0.0)).sum();
+    if total_weight <= 0.0 {
+        return 0.0;
+    }
+
+    weighted.iter().map(|(sim, weight)| sim * weight.max(0.0)).sum::<f32>() / total_weight
+}
+
+/// Whether one track can be mixed into another, by having a key next to it on the Camelot
+/// wheel (the same key, its relative key or one step either way) and a close enough tempo
+/// Tempos are also compared at half and double time
+pub fn harmonic_match(lhs: &TrackInfo, rhs: &TrackInfo, settings: &RadioSettings) -> bool {
+    if lhs.key_confidence <= 0.0 || rhs.key_confidence <= 0.0 || key_distance(lhs.key, rhs.key) > 1 {
+        return false;
+    }
+
+    if lhs.bpm <= 0.0 || rhs.bpm <= 0.0 {
+        return false;
+    }
+
+    let tolerance = settings.bpm_tolerance / 100.0;
+    [rhs.bpm, rhs.bpm * 2.0, rhs.bpm / 2.0]
+        .iter()
+        .any(|bpm| (bpm - lhs.bpm).abs() / lhs.bpm <= tolerance)
 }
 
-/// Implementation of cosine similarity
-pub fn relative_similarity(lhs: f32, rhs: f32) -> f32 {
-    1.0 - (((lhs + 0.01) / (rhs + 0.01)) / 2.0).abs()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removing_earlier_queue_keeps_current() {
+        let mut controller = MusicController::empty();
+        controller.queues = vec![
+            Queue::new(QueueType::AllTracks, vec![1, 2]),
+            Queue::new(QueueType::Genre(String::from("Gone")), vec![3]),
+            Queue::new(QueueType::Artist(String::from("Band")), vec![4, 5]),
+        ];
+        controller.current_queue = 2;
+
+        controller.retain_queues(|id| *id != 3);
+        assert_eq!(controller.queues.len(), 2);
+        assert_eq!(controller.current_queue, 1);
+        assert_eq!(controller.current_queue().cached_order, vec![4, 5]);
+
+        controller.retain_queues(|_| false);
+        assert!(controller.queues.is_empty());
+        assert_eq!(controller.current_queue, 0);
+    }
+
+    #[test]
+    fn never_played_tracks_are_skipped() {
+        let mut controller = MusicController::empty();
+        for id in 0..3 {
+            controller.all_tracks.insert(id, Track { file: format!("/music/{id}.mp3"), ..Track::default() });
+        }
+        controller.blocklist.set(BlockKind::Track, "/music/1.mp3", Some(BlockLevel::Never));
+        controller.queues = vec![Queue::new(QueueType::AllTracks, vec![0, 1, 2])];
+
+        assert_eq!(controller.upcoming_track(), Some(2));
+        assert_eq!(controller.advance_queue(false), Some(2));
+        assert_eq!(controller.current_queue().current_track, 2);
+    }
+
+    #[test]
+    fn ended_queue_seeds_its_radio() {
+        let mut controller = MusicController::empty();
+        controller.queues = vec![Queue::new(QueueType::Genre(String::from("Jazz")), vec![4, 7, 9])];
+
+        controller.queue_to_radio(0);
+        assert!(matches!(controller.queues[0].queue_type, QueueType::Radio(_)));
+        assert_eq!(controller.queues[0].seeds, vec![4, 7, 9]);
+    }
+
+    #[test]
+    fn album_loudness_combines_tracks() {
+        let mut controller = MusicController::empty();
+        for id in 0..2 {
+            let track = Track { album: String::from("Album"), len: 60.0, ..Track::default() };
+            controller.all_tracks.insert(id, track);
+        }
+
+        let loudness = Loudness { integrated: -12.0, peak: 0.5 };
+        controller.add_track_info([(0, TrackInfo { loudness: Some(loudness), ..TrackInfo::default() })]);
+        controller.add_track_info([(1, TrackInfo { loudness: Some(loudness), ..TrackInfo::default() })]);
+
+        let album = controller.album_loudness(1).unwrap();
+        assert!((album.integrated - loudness.integrated).abs() < 1e-3);
+    }
+
+    #[test]
+    fn empty_library_has_nothing_to_play() {
+        let mut controller = MusicController::empty();
+        controller.toggle_shuffle();
+        controller.toggle_shuffle();
+        controller.play_track(0);
+
+        assert!(controller.current_queue().cached_order.is_empty());
+        assert_eq!(controller.current_track(), None);
+        assert_eq!(controller.upcoming_track(), None);
+    }
+
+    #[test]
+    fn shuffle_keeps_blocked_tracks_last() {
+        let tracks: Vec<TrackId> = (0..20).collect();
+        let shuffled = shuffle_with_first(tracks.clone(), 5, |id| if id % 4 == 0 { 0.0 } else { 1.0 });
+
+        assert_eq!(shuffled[0], 5);
+        assert!(shuffled[15..].iter().all(|id| id % 4 == 0));
+        let mut unshuffled = shuffled.clone();
+        unshuffled.sort();
+        assert_eq!(unshuffled, tracks);
+    }
 }
@@ -0,0 +1,297 @@
+use rodio::source::SeekError;
+use rodio::{ChannelCount, SampleRate, Source};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub enum BandKind {
+    Peak,
+    LowShelf,
+    HighShelf,
+}
+
+/// A single filter of the equalizer
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct EqBand {
+    pub kind: BandKind,
+    /// Centre or corner frequency in Hz
+    pub frequency: f32,
+    /// Boost or cut in dB
+    pub gain: f32,
+    pub q: f32,
+}
+
+impl EqBand {
+    pub fn new(kind: BandKind, frequency: f32, gain: f32) -> Self {
+        Self { kind, frequency, gain, q: 0.707 }
+    }
+}
+
+/// A named set of equalizer bands
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct EqPreset {
+    pub name: String,
+    pub bands: Vec<EqBand>,
+}
+
+impl EqPreset {
+    /// Frequencies used by the built in presets
+    const FREQUENCIES: [f32; 5] = [60.0, 250.0, 1000.0, 4000.0, 12000.0];
+
+    /// Builds a preset from the gains of the five default bands, the outer two being shelves
+    fn from_gains(name: &str, gains: [f32; 5]) -> Self {
+        let bands = Self::FREQUENCIES
+            .iter()
+            .zip(gains)
+            .enumerate()
+            .map(|(i, (frequency, gain))| {
+                let kind = match i {
+                    0 => BandKind::LowShelf,
+                    4 => BandKind::HighShelf,
+                    _ => BandKind::Peak,
+                };
+                EqBand::new(kind, *frequency, gain)
+            })
+            .collect();
+
+        Self { name: name.to_string(), bands }
+    }
+
+    /// Presets that come with the app
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::from_gains("Flat", [0.0, 0.0, 0.0, 0.0, 0.0]),
+            Self::from_gains("Bass Boost", [6.0, 3.0, 0.0, 0.0, 0.0]),
+            Self::from_gains("Treble Boost", [0.0, 0.0, 0.0, 3.0, 6.0]),
+            Self::from_gains("Vocal", [-2.0, -1.0, 3.0, 2.0, 0.0]),
+            Self::from_gains("Loudness", [5.0, 1.0, -1.0, 1.0, 4.0]),
+        ]
+    }
+}
+
+/// Biquad filter coefficients, normalized so that a0 is 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Coefficients {
+    b: [f32; 3],
+    a: [f32; 2],
+}
+
+impl Coefficients {
+    /// Coefficients of a band from the Audio EQ Cookbook
+    fn new(band: &EqBand, sample_rate: f32) -> Self {
+        let frequency = band.frequency.clamp(10.0, sample_rate * 0.49);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let a = 10f32.powf(band.gain / 40.0);
+        let alpha = sin / (2.0 * band.q.max(0.01));
+
+        let (b, a0, a) = match band.kind {
+            BandKind::Peak => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                1.0 + alpha / a,
+                [-2.0 * cos, 1.0 - alpha / a],
+            ),
+            BandKind::LowShelf => {
+                let sq = 2.0 * a.sqrt() * alpha;
+                (
+                    [
+                        a * ((a + 1.0) - (a - 1.0) * cos + sq),
+                        2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                        a * ((a + 1.0) - (a - 1.0) * cos - sq),
+                    ],
+                    (a + 1.0) + (a - 1.0) * cos + sq,
+                    [
+                        -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                        (a + 1.0) + (a - 1.0) * cos - sq,
+                    ],
+                )
+            }
+            BandKind::HighShelf => {
+                let sq = 2.0 * a.sqrt() * alpha;
+                (
+                    [
+                        a * ((a + 1.0) + (a - 1.0) * cos + sq),
+                        -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                        a * ((a + 1.0) + (a - 1.0) * cos - sq),
+                    ],
+                    (a + 1.0) - (a - 1.0) * cos + sq,
+                    [
+                        2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                        (a + 1.0) - (a - 1.0) * cos - sq,
+                    ],
+                )
+            }
+        };
+
+        Self {
+            b: [b[0] / a0, b[1] / a0, b[2] / a0],
+            a: [a[0] / a0, a[1] / a0],
+        }
+    }
+}
+
+/// Equalizer bands shared between the audio player and every source it has playing
+/// The version is bumped on each change so sources know to recalculate their filters
+#[derive(Default)]
+pub struct SharedEqualizer {
+    version: AtomicUsize,
+    bands: Mutex<Vec<EqBand>>,
+}
+
+impl SharedEqualizer {
+    /// Replaces the bands, which playing sources pick up on their next sample
+    pub fn set_bands(&self, bands: Vec<EqBand>) {
+        *self.bands.lock().unwrap() = bands;
+        self.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Source that runs its input through the shared equalizer bands
+pub struct Equalizer<I> {
+    input: I,
+    shared: Arc<SharedEqualizer>,
+    version: usize,
+    filters: Vec<Coefficients>,
+    /// Filter state for each band and channel
+    state: Vec<[f32; 2]>,
+    channel: usize,
+}
+
+impl<I: Source> Equalizer<I> {
+    pub fn new(input: I, shared: Arc<SharedEqualizer>) -> Self {
+        let mut equalizer = Self {
+            input,
+            shared,
+            // Forces the filters to be built on the first sample
+            version: usize::MAX,
+            filters: Vec::new(),
+            state: Vec::new(),
+            channel: 0,
+        };
+        equalizer.update_filters();
+        equalizer
+    }
+
+    /// Rebuilds the filters if the shared bands have changed
+    /// Filter state is kept when the number of bands stays the same so live changes don't click
+    fn update_filters(&mut self) {
+        let version = self.shared.version.load(Ordering::Acquire);
+        if version == self.version {
+            return;
+        }
+
+        // Try again on the next sample rather than blocking the audio thread
+        let Ok(bands) = self.shared.bands.try_lock() else {
+            return;
+        };
+
+        let sample_rate = self.input.sample_rate() as f32;
+        let filters: Vec<Coefficients> = bands
+            .iter()
+            .filter(|band| band.gain != 0.0)
+            .map(|band| Coefficients::new(band, sample_rate))
+            .collect();
+
+        let channels = self.input.channels() as usize;
+        if filters.len() * channels != self.state.len() {
+            self.state = vec![[0.0; 2]; filters.len() * channels];
+        }
+
+        self.filters = filters;
+        self.version = version;
+    }
+}
+
+impl<I: Source> Iterator for Equalizer<I> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let mut sample = self.input.next()?;
+        let channels = self.input.channels().max(1) as usize;
+
+        if self.channel == 0 {
+            self.update_filters();
+        }
+
+        for (i, filter) in self.filters.iter().enumerate() {
+            if let Some(z) = self.state.get_mut(i * channels + self.channel) {
+                let y = filter.b[0] * sample + z[0];
+                z[0] = filter.b[1] * sample - filter.a[0] * y + z[1];
+                z[1] = filter.b[2] * sample - filter.a[1] * y;
+                sample = y;
+            }
+        }
+
+        self.channel = (self.channel + 1) % channels;
+        Some(sample)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<I: Source> Source for Equalizer<I> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.input.current_span_len()
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.input.channels()
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+        self.state.iter_mut().for_each(|z| *z = [0.0; 2]);
+        self.channel = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn run(bands: Vec<EqBand>, samples: Vec<f32>) -> Vec<f32> {
+        let shared = Arc::new(SharedEqualizer::default());
+        shared.set_bands(bands);
+        Equalizer::new(SamplesBuffer::new(1, 44100, samples), shared).collect()
+    }
+
+    #[test]
+    fn flat_is_identity() {
+        let samples: Vec<f32> = (0..512).map(|i| (i as f32 * 0.1).sin()).collect();
+        let bands = EqPreset::defaults()[0].bands.clone();
+
+        assert_eq!(run(bands, samples.clone()), samples);
+    }
+
+    #[test]
+    fn low_shelf_boosts_dc() {
+        let bands = vec![EqBand::new(BandKind::LowShelf, 200.0, 6.0)];
+        let output = run(bands, vec![0.5; 44100]);
+
+        // A 6dB boost roughly doubles the level of a constant signal once settled
+        assert!((output.last().unwrap() - 0.5 * 10f32.powf(6.0 / 20.0)).abs() < 0.01);
+    }
+
+    #[test]
+    fn peak_leaves_dc_alone() {
+        let bands = vec![EqBand::new(BandKind::Peak, 2000.0, 6.0)];
+        let output = run(bands, vec![0.5; 44100]);
+
+        assert!((output.last().unwrap() - 0.5).abs() < 0.01);
+    }
+}
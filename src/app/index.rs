@@ -0,0 +1,331 @@
+use super::normalize::FeatureStats;
+use super::settings::RadioSettings;
+use super::track::{TrackId, TrackInfo};
+use ndarray::Array1;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Most points kept in a leaf of the tree
+const LEAF_SIZE: usize = 16;
+
+/// Index over track feature vectors for finding the tracks closest to a point, without
+/// comparing against every track in the library
+/// New points are kept to the side and searched one by one until there are enough of them to
+/// be worth building the tree again
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct KdTree {
+    points: Vec<(TrackId, Vec<f32>)>,
+    nodes: Vec<Node>,
+    /// Point indices, in the order leaves refer to them
+    order: Vec<usize>,
+    /// Points added since the tree was last built
+    pending: Vec<usize>,
+    /// Points of tracks that were removed or replaced, left in place until the next build
+    dead: HashSet<usize>,
+    index_of: HashMap<TrackId, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Split { dim: usize, value: f32, left: usize, right: usize },
+    Leaf { start: usize, end: usize },
+}
+
+/// A distance that can be kept in a heap
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Dist(f32);
+
+impl Eq for Dist {}
+
+impl PartialOrd for Dist {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Dist {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// An index that copies of the controller share instead of copying, only being copied when one
+/// of them changes it
+/// It's left out of comparisons, as it only ever holds what the track features already do
+#[derive(Debug, Clone, Default)]
+pub struct SharedIndex(Arc<KdTree>);
+
+impl SharedIndex {
+    pub fn new(tree: KdTree) -> Self {
+        Self(Arc::new(tree))
+    }
+
+    /// The index to change, copied first if another controller still shares it
+    pub fn make_mut(&mut self) -> &mut KdTree {
+        Arc::make_mut(&mut self.0)
+    }
+}
+
+impl Deref for SharedIndex {
+    type Target = KdTree;
+
+    fn deref(&self) -> &KdTree {
+        &self.0
+    }
+}
+
+impl PartialEq for SharedIndex {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl KdTree {
+    pub fn new(points: Vec<(TrackId, Vec<f32>)>) -> Self {
+        let mut tree = Self { points, ..Self::default() };
+        tree.build();
+        tree
+    }
+
+    /// Number of points in the index
+    pub fn len(&self) -> usize {
+        self.index_of.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds a point, replacing the old one if the track is already in the index
+    pub fn insert(&mut self, id: TrackId, point: Vec<f32>) {
+        if let Some(old) = self.index_of.insert(id, self.points.len()) {
+            self.dead.insert(old);
+        }
+        self.pending.push(self.points.len());
+        self.points.push((id, point));
+        self.rebuild_if_stale();
+    }
+
+    /// Takes a track out of the index
+    pub fn remove(&mut self, id: TrackId) {
+        if let Some(old) = self.index_of.remove(&id) {
+            self.dead.insert(old);
+            self.rebuild_if_stale();
+        }
+    }
+
+    /// Builds the tree again once enough has changed that searching gets slow
+    fn rebuild_if_stale(&mut self) {
+        let limit = LEAF_SIZE.max(self.points.len() / 4);
+        if self.pending.len() > limit || self.dead.len() > limit {
+            self.build();
+        }
+    }
+
+    /// Builds the tree from scratch over every point that is still in the index
+    fn build(&mut self) {
+        let dead = std::mem::take(&mut self.dead);
+        let mut index = 0;
+        self.points.retain(|_| {
+            index += 1;
+            !dead.contains(&(index - 1))
+        });
+        self.index_of = self.points.iter().enumerate().map(|(i, (id, _))| (*id, i)).collect();
+
+        self.nodes.clear();
+        self.pending.clear();
+        self.order = (0..self.points.len()).collect();
+
+        if !self.points.is_empty() {
+            let mut order = std::mem::take(&mut self.order);
+            self.build_node(&mut order, 0);
+            self.order = order;
+        }
+    }
+
+    /// Splits a range of points on the dimension they're most spread out in, returning the node
+    fn build_node(&mut self, order: &mut [usize], start: usize) -> usize {
+        let index = self.nodes.len();
+        if order.len() <= LEAF_SIZE {
+            self.nodes.push(Node::Leaf { start, end: start + order.len() });
+            return index;
+        }
+
+        let dims = self.points[order[0]].1.len();
+        let dim = (0..dims)
+            .max_by(|a, b| self.spread(order, *a).total_cmp(&self.spread(order, *b)))
+            .unwrap_or(0);
+
+        let middle = order.len() / 2;
+        order.select_nth_unstable_by(middle, |a, b| {
+            self.points[*a].1[dim].total_cmp(&self.points[*b].1[dim])
+        });
+        let value = self.points[order[middle]].1[dim];
+
+        // Filled in once both sides are built
+        self.nodes.push(Node::Leaf { start, end: start });
+        let (left_order, right_order) = order.split_at_mut(middle);
+        let left = self.build_node(left_order, start);
+        let right = self.build_node(right_order, start + middle);
+        self.nodes[index] = Node::Split { dim, value, left, right };
+
+        index
+    }
+
+    fn spread(&self, order: &[usize], dim: usize) -> f32 {
+        let (min, max) = order.iter().fold((f32::MAX, f32::MIN), |(min, max), i| {
+            let value = self.points[*i].1[dim];
+            (min.min(value), max.max(value))
+        });
+        max - min
+    }
+
+    /// Finds up to `k` tracks closest to a point, closest first, along with their squared
+    /// distances
+    /// Searching stops after looking at `max_checks` points, which makes the result approximate
+    /// but keeps the time spent down on big libraries
+    pub fn nearest(&self, query: &[f32], k: usize, max_checks: usize) -> Vec<(TrackId, f32)> {
+        let mut best: BinaryHeap<(Dist, usize)> = BinaryHeap::new();
+        let consider = |index: usize, best: &mut BinaryHeap<(Dist, usize)>| {
+            if self.dead.contains(&index) {
+                return;
+            }
+
+            let dist = squared_distance(query, &self.points[index].1);
+            if best.len() < k {
+                best.push((Dist(dist), index));
+            } else if best.peek().is_some_and(|(worst, _)| dist < worst.0) {
+                best.pop();
+                best.push((Dist(dist), index));
+            }
+        };
+
+        for index in &self.pending {
+            consider(*index, &mut best);
+        }
+
+        let mut checks = 0;
+        let mut branches = BinaryHeap::new();
+        if !self.nodes.is_empty() {
+            branches.push(Reverse((Dist(0.0), 0)));
+        }
+
+        while let Some(Reverse((bound, node))) = branches.pop() {
+            let full = best.len() >= k;
+            if k == 0 || (full && (checks >= max_checks || best.peek().is_some_and(|(worst, _)| bound.0 > worst.0))) {
+                break;
+            }
+
+            // Go down to the leaf on the query's side, keeping the other sides for later
+            let mut node = node;
+            loop {
+                match self.nodes[node] {
+                    Node::Split { dim, value, left, right } => {
+                        let diff = query[dim] - value;
+                        let (near, far) = if diff < 0.0 { (left, right) } else { (right, left) };
+                        branches.push(Reverse((Dist(bound.0.max(diff * diff)), far)));
+                        node = near;
+                    }
+                    Node::Leaf { start, end } => {
+                        for index in &self.order[start..end] {
+                            consider(*index, &mut best);
+                        }
+                        checks += end - start;
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut best: Vec<(TrackId, f32)> = best
+            .into_iter()
+            .map(|(dist, index)| (self.points[index].0, dist.0))
+            .collect();
+        best.sort_by(|a, b| a.1.total_cmp(&b.1));
+        best
+    }
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// Turns the features of a track into a single point, so that tracks close together are ones
+/// the radio would rate as similar
+/// Features are z-scored, then each list of them is made unit length, as distances between
+/// unit vectors follow their cosine similarity, and scaled by how much the radio weighs it
+pub fn feature_vector(info: &TrackInfo, settings: &RadioSettings, stats: &FeatureStats) -> Vec<f32> {
+    let info = stats.normalize(info);
+    let mut point = Vec::new();
+
+    let mut push_unit = |features: &Array1<f32>, weight: f32| {
+        let length = features.dot(features).sqrt();
+        let scale = if length > 0.0 { weight.max(0.0).sqrt() / length } else { 0.0 };
+        point.extend(features.iter().map(|f| f * scale));
+    };
+    push_unit(&info.mfcc, settings.mfcc_weight);
+    push_unit(&info.chroma, settings.chroma_weight);
+    push_unit(&info.spectral, settings.spectral_weight);
+
+    for (value, weight) in [
+        (info.energy, settings.energy_weight),
+        (info.bpm, settings.bpm_weight),
+        (info.zcr, settings.zcr_weight),
+    ] {
+        point.push(value * weight.max(0.0).sqrt());
+    }
+
+    point
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    fn random_points(count: usize, dims: usize) -> Vec<(TrackId, Vec<f32>)> {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..count)
+            .map(|id| (id, (0..dims).map(|_| rng.gen_range(-1.0..1.0)).collect()))
+            .collect()
+    }
+
+    fn brute_force(points: &[(TrackId, Vec<f32>)], query: &[f32], k: usize) -> Vec<TrackId> {
+        let mut dists: Vec<(TrackId, f32)> =
+            points.iter().map(|(id, p)| (*id, squared_distance(query, p))).collect();
+        dists.sort_by(|a, b| a.1.total_cmp(&b.1));
+        dists.into_iter().take(k).map(|(id, _)| id).collect()
+    }
+
+    fn ids(results: Vec<(TrackId, f32)>) -> Vec<TrackId> {
+        results.into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn unbounded_search_is_exact() {
+        let points = random_points(500, 5);
+        let tree = KdTree::new(points.clone());
+
+        for (_, query) in points.iter().take(20) {
+            assert_eq!(ids(tree.nearest(query, 10, usize::MAX)), brute_force(&points, query, 10));
+        }
+    }
+
+    #[test]
+    fn inserted_and_removed_points() {
+        let points = random_points(300, 4);
+        let mut tree = KdTree::new(points[..100].to_vec());
+        for (id, point) in &points[100..] {
+            tree.insert(*id, point.clone());
+        }
+        for id in 0..50 {
+            tree.remove(id);
+        }
+
+        assert_eq!(tree.len(), 250);
+        let query = &points[0].1;
+        assert_eq!(ids(tree.nearest(query, 5, usize::MAX)), brute_force(&points[50..], query, 5));
+    }
+}
@@ -1,11 +1,21 @@
+pub mod affinity;
+pub mod analyser;
 pub mod audio;
+pub mod blocklist;
 pub mod controller;
+pub mod equalizer;
+pub mod index;
+pub mod normalize;
+pub mod oggtag;
 pub mod playlist;
 pub mod queue;
 pub mod settings;
+pub mod state;
+pub mod stretch;
 pub mod track;
 pub mod utils;
+pub mod watcher;
 pub mod autoplaylist;
 
 pub use controller::MusicController;
-pub use track::{load_tracks, Track};
+pub use track::{load_tracks, ScanReport, Track, TrackId};
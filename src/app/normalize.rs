@@ -0,0 +1,160 @@
+use super::track::TrackInfo;
+use ndarray::{s, Array1};
+
+/// Number of values in each track's features once flattened, being the MFCCs, chroma,
+/// spectral features, energy, tempo and zero crossing rate
+const FEATURE_LEN: usize = 13 + 12 + 6 + 3;
+
+/// Number of values in each track's variances once flattened, being the MFCC variances and the
+/// energy variance
+const VAR_LEN: usize = 13 + 1;
+
+/// Mean and standard deviation of every feature across the library, used to put features on
+/// the same scale before they're compared
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FeatureStats {
+    pub mean: Array1<f32>,
+    pub std: Array1<f32>,
+    /// Mean and standard deviation of the variances, taken only from tracks that have them
+    pub var_mean: Array1<f32>,
+    pub var_std: Array1<f32>,
+    /// Number of tracks the stats were taken from
+    pub count: usize,
+}
+
+impl FeatureStats {
+    pub fn from_tracks<'a>(tracks: impl IntoIterator<Item = &'a TrackInfo>) -> Self {
+        let tracks: Vec<&TrackInfo> = tracks.into_iter().collect();
+        let features: Vec<Array1<f32>> = tracks.iter().filter_map(|info| flatten(info)).collect();
+        let count = features.len();
+        if count == 0 {
+            return Self::default();
+        }
+
+        let (mean, std) = mean_std(&features, FEATURE_LEN);
+        let variances: Vec<Array1<f32>> = tracks.iter().filter_map(|info| flatten_var(info)).collect();
+        let (var_mean, var_std) = if variances.is_empty() {
+            (Array1::zeros(0), Array1::zeros(0))
+        } else {
+            mean_std(&variances, VAR_LEN)
+        };
+
+        Self { mean, std, var_mean, var_std, count }
+    }
+
+    /// Z-scores the features of a track, leaving them as they are if there aren't any stats
+    pub fn normalize(&self, info: &TrackInfo) -> TrackInfo {
+        let Some(features) = flatten(info) else {
+            return info.clone();
+        };
+        if self.mean.len() != FEATURE_LEN || self.std.len() != FEATURE_LEN {
+            return info.clone();
+        }
+
+        let z = z_score(features, &self.mean, &self.std);
+
+        // Variances that can't be put on the same scale are dropped, so they aren't compared raw
+        let (mfcc_var, energy_var) = match flatten_var(info) {
+            Some(variances) if self.var_mean.len() == VAR_LEN && self.var_std.len() == VAR_LEN => {
+                let z = z_score(variances, &self.var_mean, &self.var_std);
+                (z.slice(s![0..13]).to_owned(), z[13])
+            }
+            _ => (Array1::zeros(0), 0.0),
+        };
+
+        TrackInfo {
+            mfcc: z.slice(s![0..13]).to_owned(),
+            chroma: z.slice(s![13..25]).to_owned(),
+            spectral: z.slice(s![25..31]).to_owned(),
+            energy: z[31],
+            bpm: z[32],
+            zcr: z[33],
+            mfcc_var,
+            energy_var,
+            ..info.clone()
+        }
+    }
+}
+
+/// Puts every feature of a track into one list, None if the track's features are the wrong size
+fn flatten(info: &TrackInfo) -> Option<Array1<f32>> {
+    if info.mfcc.len() != 13 || info.chroma.len() != 12 || info.spectral.len() != 6 {
+        return None;
+    }
+
+    let mut features = Vec::with_capacity(FEATURE_LEN);
+    features.extend(info.mfcc.iter());
+    features.extend(info.chroma.iter());
+    features.extend(info.spectral.iter());
+    features.extend([info.energy, info.bpm, info.zcr]);
+    Some(Array1::from_vec(features))
+}
+
+/// Puts the variances of a track into one list, None if the track wasn't analysed in parts
+fn flatten_var(info: &TrackInfo) -> Option<Array1<f32>> {
+    if info.mfcc_var.len() != 13 {
+        return None;
+    }
+
+    let mut variances = Vec::with_capacity(VAR_LEN);
+    variances.extend(info.mfcc_var.iter());
+    variances.push(info.energy_var);
+    Some(Array1::from_vec(variances))
+}
+
+fn mean_std(values: &[Array1<f32>], len: usize) -> (Array1<f32>, Array1<f32>) {
+    let count = values.len() as f32;
+    let mean = values.iter().fold(Array1::zeros(len), |a, b| a + b) / count;
+    let var = values.iter().fold(Array1::zeros(len), |a, b| a + (b - &mean).mapv(|d| d * d)) / count;
+    (mean, var.mapv(f32::sqrt))
+}
+
+/// Features that never change can't tell tracks apart, so they're all left at 0
+fn z_score(values: Array1<f32>, mean: &Array1<f32>, std: &Array1<f32>) -> Array1<f32> {
+    (values - mean) / std.mapv(|std| if std > 0.0 { std } else { f32::INFINITY })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(bpm: f32, energy: f32) -> TrackInfo {
+        TrackInfo { bpm, energy, ..TrackInfo::default() }
+    }
+
+    #[test]
+    fn z_scores() {
+        let tracks = vec![track(100.0, 0.1), track(120.0, 0.3), track(140.0, 0.2)];
+        let stats = FeatureStats::from_tracks(&tracks);
+
+        let normalized = stats.normalize(&tracks[2]);
+        assert!((normalized.bpm - 1.2247).abs() < 1e-3);
+        assert!(normalized.energy.abs() < 1e-5);
+        // Every track has the same MFCCs
+        assert_eq!(normalized.mfcc, Array1::<f32>::zeros(13));
+    }
+
+    #[test]
+    fn z_scores_variances() {
+        let with_var = |energy_var: f32| TrackInfo {
+            mfcc_var: Array1::from_elem(13, energy_var * 10.0),
+            energy_var,
+            ..track(120.0, 0.5)
+        };
+        let tracks = vec![with_var(0.1), with_var(0.3), track(100.0, 0.2)];
+        let stats = FeatureStats::from_tracks(&tracks);
+        assert_eq!(stats.count, 3);
+
+        let normalized = stats.normalize(&tracks[1]);
+        assert!((normalized.energy_var - 1.0).abs() < 1e-4);
+        assert!((normalized.mfcc_var[0] - 1.0).abs() < 1e-4);
+        // Tracks without variances are left without them
+        assert!(stats.normalize(&tracks[2]).mfcc_var.is_empty());
+    }
+
+    #[test]
+    fn no_stats_leaves_features() {
+        let info = track(120.0, 0.5);
+        assert_eq!(FeatureStats::default().normalize(&info), info);
+    }
+}
@@ -0,0 +1,233 @@
+use anyhow::anyhow;
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+
+const VORBIS_IDENT: &[u8] = b"\x01vorbis";
+const VORBIS_COMMENT: &[u8] = b"\x03vorbis";
+const OPUS_IDENT: &[u8] = b"OpusHead";
+const OPUS_TAGS: &[u8] = b"OpusTags";
+
+/// Opus always counts its granule positions at 48kHz, whatever the input rate was
+const OPUS_RATE: u64 = 48000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OggCodec {
+    Vorbis,
+    Opus,
+}
+
+/// Vorbis comments of an Ogg Vorbis or Opus file, along with its length
+#[derive(Debug, Clone, PartialEq)]
+pub struct OggTag {
+    pub codec: OggCodec,
+    pub vendor: String,
+    pub comments: HashMap<String, Vec<String>>,
+    /// Length in seconds
+    pub len: f64,
+}
+
+impl OggTag {
+    /// Reads the comment header of the first stream in a file
+    pub fn read_from_path(file: &str) -> anyhow::Result<Self> {
+        let mut reader = PacketReader::new(BufReader::new(File::open(file)?));
+        let mut serial = None;
+        let mut headers = Vec::new();
+        let mut granule = 0;
+
+        while let Some(packet) = reader.read_packet()? {
+            // Any other streams multiplexed into the file are ignored
+            if *serial.get_or_insert(packet.stream_serial()) != packet.stream_serial() {
+                continue;
+            }
+
+            // The length comes from the granule position at the end of the stream
+            granule = packet.absgp_page();
+            if headers.len() < 2 {
+                headers.push(packet.data);
+            }
+        }
+
+        let [ident, comments] = &headers[..] else {
+            return Err(anyhow!("{file} is missing its Ogg headers"));
+        };
+        let (codec, rate, pre_skip) =
+            parse_ident(ident).ok_or_else(|| anyhow!("{file} is not Ogg Vorbis or Opus"))?;
+        let (vendor, comments) =
+            parse_comments(codec, comments).ok_or_else(|| anyhow!("{file} has a broken comment header"))?;
+
+        Ok(Self {
+            codec,
+            vendor,
+            comments,
+            len: granule.saturating_sub(pre_skip) as f64 / rate as f64,
+        })
+    }
+
+    /// Writes the comments into a file, copying every other packet as it is
+    /// The new file is written next to the old one and moved over it, so a failure can't leave
+    /// it half written
+    pub fn write_to_path(&self, file: &str) -> anyhow::Result<()> {
+        let temp = format!("{file}.tmp");
+
+        if let Err(err) = self.copy_with_comments(file, &temp) {
+            let _ = fs::remove_file(&temp);
+            return Err(err);
+        }
+
+        fs::rename(temp, file)?;
+        Ok(())
+    }
+
+    fn copy_with_comments(&self, file: &str, temp: &str) -> anyhow::Result<()> {
+        let mut reader = PacketReader::new(BufReader::new(File::open(file)?));
+        let mut writer = PacketWriter::new(BufWriter::new(File::create(temp)?));
+        let mut serial = None;
+        let mut index = 0;
+
+        while let Some(packet) = reader.read_packet()? {
+            let end_info = if packet.last_in_stream() {
+                PacketWriteEndInfo::EndStream
+            } else if packet.last_in_page() {
+                PacketWriteEndInfo::EndPage
+            } else {
+                PacketWriteEndInfo::NormalPacket
+            };
+            let (stream, absgp) = (packet.stream_serial(), packet.absgp_page());
+
+            let mut data = packet.data;
+            if *serial.get_or_insert(stream) == stream {
+                // The comment header is always the second packet of the stream
+                if index == 1 {
+                    data = encode_comments(self.codec, &self.vendor, &self.comments);
+                }
+                index += 1;
+            }
+
+            writer.write_packet(data, stream, end_info, absgp)?;
+        }
+
+        writer.into_inner().flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the codec, sample rate and pre-skip from an identification header
+fn parse_ident(data: &[u8]) -> Option<(OggCodec, u64, u64)> {
+    if data.starts_with(VORBIS_IDENT) {
+        let rate = u32::from_le_bytes(data.get(12..16)?.try_into().ok()?);
+        Some((OggCodec::Vorbis, rate.max(1) as u64, 0))
+    } else if data.starts_with(OPUS_IDENT) {
+        let pre_skip = u16::from_le_bytes(data.get(10..12)?.try_into().ok()?);
+        Some((OggCodec::Opus, OPUS_RATE, pre_skip as u64))
+    } else {
+        None
+    }
+}
+
+/// Reads the vendor and comments from a comment header
+/// Keys keep their case, with repeated keys collected into one list
+fn parse_comments(codec: OggCodec, data: &[u8]) -> Option<(String, HashMap<String, Vec<String>>)> {
+    let magic = match codec {
+        OggCodec::Vorbis => VORBIS_COMMENT,
+        OggCodec::Opus => OPUS_TAGS,
+    };
+    let mut data = data.strip_prefix(magic)?;
+
+    let vendor_len = read_u32(&mut data)? as usize;
+    let vendor = String::from_utf8_lossy(read_bytes(&mut data, vendor_len)?).to_string();
+    let mut comments: HashMap<String, Vec<String>> = HashMap::new();
+
+    for _ in 0..read_u32(&mut data)? {
+        let len = read_u32(&mut data)? as usize;
+        let comment = String::from_utf8_lossy(read_bytes(&mut data, len)?).to_string();
+
+        if let Some((key, value)) = comment.split_once('=') {
+            comments.entry(key.to_string()).or_default().push(value.to_string());
+        }
+    }
+
+    Some((vendor, comments))
+}
+
+/// Takes some bytes off the front of a slice
+fn read_bytes<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let bytes = data.get(..len)?;
+    *data = &data[len..];
+    Some(bytes)
+}
+
+/// Takes a little endian u32 off the front of a slice
+fn read_u32(data: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(read_bytes(data, 4)?.try_into().ok()?))
+}
+
+/// Builds a comment header, with keys sorted so unchanged tags write the same bytes
+fn encode_comments(codec: OggCodec, vendor: &str, comments: &HashMap<String, Vec<String>>) -> Vec<u8> {
+    let mut data = match codec {
+        OggCodec::Vorbis => VORBIS_COMMENT.to_vec(),
+        OggCodec::Opus => OPUS_TAGS.to_vec(),
+    };
+
+    let mut keys: Vec<&String> = comments.keys().collect();
+    keys.sort();
+    let entries: Vec<String> = keys
+        .into_iter()
+        .flat_map(|key| comments[key].iter().map(move |value| format!("{key}={value}")))
+        .collect();
+
+    data.extend((vendor.len() as u32).to_le_bytes());
+    data.extend(vendor.as_bytes());
+    data.extend((entries.len() as u32).to_le_bytes());
+    for entry in entries {
+        data.extend((entry.len() as u32).to_le_bytes());
+        data.extend(entry.as_bytes());
+    }
+
+    // Vorbis headers end with a framing bit, Opus ones don't
+    if codec == OggCodec::Vorbis {
+        data.push(1);
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comments() -> HashMap<String, Vec<String>> {
+        HashMap::from([
+            (String::from("TITLE"), vec![String::from("Song")]),
+            (String::from("ARTIST"), vec![String::from("One"), String::from("Two")]),
+            (String::from("comment"), vec![String::from("a=b")]),
+        ])
+    }
+
+    #[test]
+    fn comments_round_trip() {
+        for codec in [OggCodec::Vorbis, OggCodec::Opus] {
+            let data = encode_comments(codec, "vendor", &comments());
+            assert_eq!(
+                parse_comments(codec, &data),
+                Some((String::from("vendor"), comments()))
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_comments_fail() {
+        let data = encode_comments(OggCodec::Opus, "vendor", &comments());
+        assert_eq!(parse_comments(OggCodec::Opus, &data[..data.len() - 3]), None);
+        assert_eq!(parse_comments(OggCodec::Vorbis, &data), None);
+    }
+
+    #[test]
+    fn opus_length_skips_pre_skip() {
+        let mut ident = OPUS_IDENT.to_vec();
+        ident.extend([1, 2, 0x38, 0x01, 0x80, 0xbb, 0, 0]);
+
+        assert_eq!(parse_ident(&ident), Some((OggCodec::Opus, OPUS_RATE, 312)));
+    }
+}
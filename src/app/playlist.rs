@@ -1,14 +1,16 @@
+use super::settings::LibraryRoot;
+use super::track::get_library_files;
 use super::utils::strip_unnessecary;
-use crate::app::Track;
+use crate::app::{Track, TrackId};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use std::{fs, io};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Playlist {
     pub name: String,
     pub file: String,
     pub track_paths: Vec<String>,
-    pub tracks: Vec<usize>,
+    pub tracks: Vec<TrackId>,
 }
 
 impl Playlist {
@@ -16,14 +18,17 @@ impl Playlist {
     pub fn new(name: String, dir: String) -> Self {
         Playlist {
             name: name.clone(),
-            file: dir + &strip_unnessecary(&name) + ".m3u",
+            file: PathBuf::from(dir)
+                .join(strip_unnessecary(&name) + ".m3u")
+                .display()
+                .to_string(),
             tracks: Vec::new(),
             track_paths: Vec::new(),
         }
     }
 
     /// Loads a playlist from device and matches it to all loaded tracks
-    pub fn load(dir: &str, playlist_file: &str, all_tracks: &Vec<Track>) -> Self {
+    pub fn load(dir: &str, playlist_file: &str, all_tracks: &BTreeMap<TrackId, Track>) -> Self {
         let path = PathBuf::from(playlist_file);
         let file = playlist_file.to_string();
         let mut name = path.file_stem().unwrap().to_str().unwrap().to_string();
@@ -49,11 +54,11 @@ impl Playlist {
                 };
 
                 if path.is_file() {
-                    let idx = all_tracks
+                    let id = all_tracks
                         .iter()
-                        .position(|track: &Track| PathBuf::from(&track.file) == path);
-                    if let Some(idx) = idx {
-                        tracks.push(idx);
+                        .find(|(_, track)| PathBuf::from(&track.file) == path);
+                    if let Some((id, _)) = id {
+                        tracks.push(*id);
                     }
                 } else {
                     // TODO file playlists or not idk
@@ -70,7 +75,7 @@ impl Playlist {
     }
 
     /// Removes a track from a playlist
-    pub fn remove(&mut self, track: usize) {
+    pub fn remove(&mut self, track: TrackId) {
         let position = self.tracks.iter().position(|t| *t == track);
 
         if let Some(position) = position {
@@ -79,28 +84,9 @@ impl Playlist {
     }
 }
 
-/// Returns all files mentioned in a playlist file
-pub fn get_playlist_files(dir: &str) -> Result<Vec<String>, io::Error> {
-    let mut files = Vec::new();
-
-    for entry in fs::read_dir(dir)? {
-        let path = entry?.path();
-        let filename = path.to_str().unwrap().to_string();
-
-        match path.is_file() {
-            true => {
-                if path_is_playlist(path) {
-                    files.push(filename.into());
-                }
-            }
-            false => {
-                let mut dir_files = get_playlist_files(&filename)?;
-                files.append(&mut dir_files);
-            }
-        }
-    }
-
-    Ok(files)
+/// Returns all playlist files in the library folders
+pub fn get_playlist_files(roots: &[LibraryRoot]) -> Vec<String> {
+    get_library_files(roots, path_is_playlist)
 }
 
 /// Is a file of the type M3U
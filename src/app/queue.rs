@@ -1,4 +1,6 @@
+use super::track::{AlbumId, TrackId};
 use log::info;
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::time::Instant;
 
@@ -7,17 +9,26 @@ pub struct Queue {
     pub queue_type: QueueType,
     pub current_track: usize,
     pub listens: Vec<Listen>,
-    pub cached_order: Vec<usize>,
+    pub cached_order: Vec<TrackId>,
+    /// Tracks a radio picks around, like an artist or playlist it was started from
+    /// Radios without any pick around the tracks already in them
+    pub seeds: Vec<TrackId>,
+    /// Playback rate of tracks in the queue, where 1.0 is normal speed
+    pub rate: f32,
+    pub end_behaviour: EndBehaviour,
 }
 
 impl Queue {
     /// Creates a new queue with a type and tracks
-    pub fn new(queue_type: QueueType, tracks: Vec<usize>) -> Self {
+    pub fn new(queue_type: QueueType, tracks: Vec<TrackId>) -> Self {
         Self {
             queue_type,
             current_track: 0,
             listens: Vec::new(),
             cached_order: tracks,
+            seeds: Vec::new(),
+            rate: 1.0,
+            end_behaviour: EndBehaviour::default(),
         }
     }
 
@@ -28,6 +39,9 @@ impl Queue {
             current_track,
             listens: Vec::new(),
             cached_order: Vec::new(),
+            seeds: Vec::new(),
+            rate: 1.0,
+            end_behaviour: EndBehaviour::default(),
         }
     }
 
@@ -38,36 +52,50 @@ impl Queue {
             current_track: 0,
             listens: Vec::new(),
             cached_order: Vec::new(),
+            seeds: Vec::new(),
+            rate: 1.0,
+            end_behaviour: EndBehaviour::default(),
         }
     }
 
     /// Creates a new queue with all tracks, with a starting track
-    pub fn all_pos(idx: usize) -> Self {
+    pub fn all_pos(id: TrackId) -> Self {
         Queue {
             queue_type: QueueType::AllTracks,
             current_track: 0,
             listens: Vec::new(),
-            cached_order: vec![idx],
+            cached_order: vec![id],
+            seeds: Vec::new(),
+            rate: 1.0,
+            end_behaviour: EndBehaviour::default(),
         }
     }
 
     /// Creates a new radio queue
-    pub fn radio(idx: usize, name: String) -> Self {
+    pub fn radio(id: TrackId, name: String) -> Self {
         Queue {
             queue_type: QueueType::Radio(name),
             current_track: 0,
             listens: Vec::new(),
-            cached_order: vec![idx],
+            cached_order: vec![id],
+            seeds: Vec::new(),
+            rate: 1.0,
+            end_behaviour: EndBehaviour::default(),
         }
     }
 
+    /// Creates a new radio queue picking around a set of tracks, starting with one of them
+    pub fn seeded_radio(id: TrackId, name: String, seeds: Vec<TrackId>) -> Self {
+        Queue { seeds, ..Queue::radio(id, name) }
+    }
+
     /// The currently playing track in a queue
-    pub fn current(&self) -> usize {
+    pub fn current(&self) -> TrackId {
         *self.cached_order.get(self.current_track).unwrap_or(&0)
     }
 
-    /// The track index at a given index in the queue
-    pub fn track(&self, idx: usize) -> usize {
+    /// The track id at a given index in the queue
+    pub fn track(&self, idx: usize) -> TrackId {
         self.cached_order[idx]
     }
 
@@ -76,6 +104,16 @@ impl Queue {
         self.cached_order.len()
     }
 
+    /// Keeps only the tracks matching a condition, staying on the same track if it is kept
+    pub fn retain<F: Fn(&TrackId) -> bool>(&mut self, keep: F) {
+        let removed_before = self.cached_order[..self.current_track.min(self.len())]
+            .iter()
+            .filter(|id| !keep(id))
+            .count();
+        self.cached_order.retain(|id| keep(id));
+        self.current_track = (self.current_track - removed_before).min(self.len().saturating_sub(1));
+    }
+
     /// Swaps two tracks inside a queue
     /// Used in the queue dragging code
     pub fn swap(&mut self, index_to_move: usize, position: usize) {
@@ -117,17 +155,53 @@ impl Queue {
     }
 }
 
-#[derive(PartialEq, Clone)]
+#[derive(PartialEq, Clone, Serialize, Deserialize)]
 pub enum QueueType {
     AllTracks,
     Radio(String),
     Artist(String),
-    Album(String),
+    Album(AlbumId),
     Genre(String),
     Playlist(String, usize),
     AutoPlaylist(String, usize),
 }
 
+/// What a queue does once its last track has played
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum EndBehaviour {
+    Stop,
+    RepeatQueue,
+    RepeatOne,
+    Reshuffle,
+    #[default]
+    NextQueue,
+    Radio,
+}
+
+impl EndBehaviour {
+    pub const ALL: [EndBehaviour; 6] = [
+        Self::Stop,
+        Self::RepeatQueue,
+        Self::RepeatOne,
+        Self::Reshuffle,
+        Self::NextQueue,
+        Self::Radio,
+    ];
+}
+
+impl Display for EndBehaviour {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stop => f.write_str("Stop"),
+            Self::RepeatQueue => f.write_str("Repeat queue"),
+            Self::RepeatOne => f.write_str("Repeat track"),
+            Self::Reshuffle => f.write_str("Reshuffle and repeat"),
+            Self::NextQueue => f.write_str("Continue to next queue"),
+            Self::Radio => f.write_str("Continue as radio"),
+        }
+    }
+}
+
 impl Display for QueueType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -136,7 +210,7 @@ impl Display for QueueType {
             Self::Playlist(name, _) => write!(f, "{name}"),
             Self::AutoPlaylist(name, _) => write!(f, "{name}"),
             Self::Artist(artist) => f.write_str(artist),
-            Self::Album(album) => f.write_str(album),
+            Self::Album(album) => write!(f, "{album}"),
             Self::Genre(genre) => f.write_str(genre),
         }
     }
@@ -144,16 +218,17 @@ impl Display for QueueType {
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Listen {
-    id: usize, // make better than an index
+    id: TrackId,
     start: Instant,
     progress: f64,
     percentage: f64,
 }
 
 impl Listen {
-    pub fn new(id: usize, start: Instant, total_len: f64, progress: f64) -> Self {
-        let time = start.elapsed();
-        let percentage = time.as_secs_f64() / total_len;
+    pub fn new(id: TrackId, start: Instant, total_len: f64, progress: f64) -> Self {
+        // Taken from the position rather than the time it played for, so pausing, seeking and
+        // playback rate don't throw it off
+        let percentage = if total_len > 0.0 { (progress / total_len).clamp(0.0, 1.0) } else { 0.0 };
         Self {
             id,
             start,
@@ -161,4 +236,13 @@ impl Listen {
             percentage,
         }
     }
+
+    pub fn id(&self) -> TrackId {
+        self.id
+    }
+
+    /// How much of the track was played, from 0 to 1
+    pub fn completion(&self) -> f64 {
+        self.percentage
+    }
 }
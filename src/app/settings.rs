@@ -1,13 +1,101 @@
+use super::analyser::default_threads;
+use super::equalizer::{EqBand, EqPreset};
+use super::track::expand_directory;
+use glob::Pattern;
 use log::info;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct Settings {
     pub volume: f32,
-    pub directory: String,
+    /// Single music directory from older settings files, moved into `libraries` when loaded
+    #[serde(default, skip_serializing)]
+    directory: String,
+    /// Folders music is read from, the first one is where new playlists are saved
+    #[serde(default)]
+    pub libraries: Vec<LibraryRoot>,
     pub radio: RadioSettings,
     pub ui: UiSettings,
+    #[serde(default)]
+    pub audio: AudioSettings,
+    #[serde(default)]
+    pub analysis: AnalysisSettings,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct LibraryRoot {
+    pub path: String,
+    /// Glob patterns for the files to read, everything is read if empty
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Glob patterns for files and folders to skip
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct AudioSettings {
+    /// Length of crossfades between tracks in seconds, 0 for gapless playback
+    pub crossfade: f32,
+    pub replaygain: ReplayGainMode,
+    /// Extra gain in dB applied on top of ReplayGain
+    pub preamp: f32,
+    /// Lowers the gain of tracks that would otherwise clip
+    pub prevent_clipping: bool,
+    pub equalizer: EqualizerSettings,
+    /// Name of the output device to play through, None for the system default
+    pub output_device: Option<String>,
+    /// Keeps the pitch the same when tracks are played faster or slower
+    pub preserve_pitch: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct EqualizerSettings {
+    pub enabled: bool,
+    /// Name of the preset the bands were last loaded from
+    pub preset: String,
+    pub bands: Vec<EqBand>,
+    pub presets: Vec<EqPreset>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+pub enum ReplayGainMode {
+    Off,
+    #[default]
+    Track,
+    Album,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+#[serde(default)]
+pub struct AnalysisSettings {
+    pub mode: AnalysisMode,
+    /// Number of tracks analysed at once, 0 to pick based on the device
+    pub threads: usize,
+}
+
+impl AnalysisSettings {
+    /// Number of threads to analyse tracks on
+    pub fn threads(&self) -> usize {
+        match self.threads {
+            0 => default_threads(),
+            threads => threads,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+pub enum AnalysisMode {
+    /// A single window near the start of each track, which is quick but can miss a lot
+    #[default]
+    Window,
+    /// A few windows spread across each track
+    Segments,
+    /// Every part of each track
+    Whole,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
@@ -17,9 +105,14 @@ pub struct UiSettings {
 
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
+#[serde(default)]
 pub struct RadioSettings {
     pub temp: f32,
+    pub mode: RadioMode,
+    /// How far apart in percent the tempos of tracks can be in harmonic mode
+    pub bpm_tolerance: f32,
     pub weight_mode: WeightMode,
+    pub seed_mode: SeedMode,
     pub album_penalty: f32,
     pub artist_penalty: f32,
 
@@ -29,6 +122,26 @@ pub struct RadioSettings {
     pub energy_weight: f32,
     pub bpm_weight: f32,
     pub zcr_weight: f32,
+    pub key_weight: f32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+pub enum RadioMode {
+    /// Picks tracks that sound alike
+    #[default]
+    Similar,
+    /// Only picks tracks with a compatible key and tempo, like a DJ mixing between them
+    Harmonic,
+}
+
+/// How a radio started from many tracks, like an artist or playlist, picks around them
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+pub enum SeedMode {
+    /// Picks around one of the tracks at a time, so different sides of them all get played
+    #[default]
+    Mixture,
+    /// Picks around the average of all the tracks
+    Centroid,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
@@ -43,9 +156,12 @@ impl Default for Settings {
     fn default() -> Self {
         Self {
             volume: 1.0,
-            directory: Self::default_audio_dir(),
+            directory: String::new(),
+            libraries: vec![LibraryRoot::new(Self::default_audio_dir())],
             radio: RadioSettings::default(),
-            ui: UiSettings::default()
+            ui: UiSettings::default(),
+            audio: AudioSettings::default(),
+            analysis: AnalysisSettings::default(),
         }
     }
 }
@@ -54,15 +170,45 @@ impl Default for RadioSettings {
     fn default() -> Self {
         Self {
             temp: 0.7,
+            mode: RadioMode::default(),
+            bpm_tolerance: 6.0,
             album_penalty: 0.2,
             artist_penalty: 0.7,
             weight_mode: WeightMode::default(),
+            seed_mode: SeedMode::default(),
             mfcc_weight: 1.0,
             chroma_weight: 1.0,
             spectral_weight: 0.7,
             energy_weight: 0.0,
             bpm_weight: 0.0,
             zcr_weight: 0.0,
+            key_weight: 0.0,
+        }
+    }
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            crossfade: 0.0,
+            replaygain: ReplayGainMode::default(),
+            preamp: 0.0,
+            prevent_clipping: true,
+            equalizer: EqualizerSettings::default(),
+            output_device: None,
+            preserve_pitch: true,
+        }
+    }
+}
+
+impl Default for EqualizerSettings {
+    fn default() -> Self {
+        let presets = EqPreset::defaults();
+        EqualizerSettings {
+            enabled: false,
+            preset: presets[0].name.clone(),
+            bands: presets[0].bands.clone(),
+            presets,
         }
     }
 }
@@ -107,8 +253,15 @@ impl Settings {
         let dir = Self::dir().join("settings.toml");
         info!("loading settings from {dir:?}");
         let file = std::fs::read_to_string(dir).unwrap_or_default();
-        match toml::from_str(&file) {
-            Ok(config) => config,
+        match toml::from_str::<Self>(&file) {
+            Ok(mut config) => {
+                if !config.directory.is_empty() {
+                    let directory = std::mem::take(&mut config.directory);
+                    config.libraries.insert(0, LibraryRoot::new(directory));
+                    config.save();
+                }
+                config
+            }
             Err(_) => {
                 let config = Self::default();
                 config.save();
@@ -117,6 +270,19 @@ impl Settings {
         }
     }
 
+    /// The first library folder, which new playlists are saved in
+    pub fn main_directory(&self) -> String {
+        match self.libraries.first() {
+            Some(root) => root.dir().display().to_string(),
+            None => expand_directory(&Self::default_audio_dir()),
+        }
+    }
+
+    /// Finds the library folder a file is in
+    pub fn root_of(&self, file: &str) -> Option<&LibraryRoot> {
+        self.libraries.iter().find(|root| root.contains(Path::new(file)))
+    }
+
     /// Checks if settings exists
     pub fn exists() -> bool {
         let dir = Self::dir().join("settings.toml");
@@ -132,3 +298,98 @@ impl Settings {
         std::fs::write(Self::dir().join("settings.toml"), file).unwrap();
     }
 }
+
+impl LibraryRoot {
+    pub fn new(path: String) -> Self {
+        Self { path, include: Vec::new(), exclude: Vec::new() }
+    }
+
+    /// The folder with the tilde expanded
+    pub fn dir(&self) -> PathBuf {
+        PathBuf::from(expand_directory(&self.path))
+    }
+
+    /// Whether the folder is there, which it might not be for unmounted drives
+    pub fn exists(&self) -> bool {
+        self.dir().is_dir()
+    }
+
+    /// Is a path inside this folder
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(self.dir())
+    }
+
+    /// Should a path inside this folder be skipped
+    pub fn excludes(&self, path: &Path) -> bool {
+        match path.strip_prefix(self.dir()) {
+            Ok(relative) => self.exclude.iter().any(|p| pattern_matches(p, relative)),
+            Err(_) => true,
+        }
+    }
+
+    /// Should a file be read as part of this folder
+    pub fn allows(&self, file: &Path) -> bool {
+        let Ok(relative) = file.strip_prefix(self.dir()) else {
+            return false;
+        };
+
+        !self.exclude.iter().any(|p| pattern_matches(p, relative))
+            && (self.include.is_empty() || self.include.iter().any(|p| pattern_matches(p, relative)))
+    }
+}
+
+/// Matches a path relative to a library folder against a glob pattern, similar to gitignore
+/// Patterns without a slash match any file or folder name, like `*.wav` or `Samples`,
+/// while ones with a slash match from the start of the folder, like `Podcasts/**`
+fn pattern_matches(pattern: &str, relative: &Path) -> bool {
+    let pattern = pattern.trim().trim_end_matches('/');
+    let Ok(glob) = Pattern::new(pattern) else {
+        return false;
+    };
+
+    if pattern.contains('/') {
+        relative.ancestors().any(|path| !path.as_os_str().is_empty() && glob.matches_path(path))
+    } else {
+        relative
+            .components()
+            .any(|name| glob.matches(&name.as_os_str().to_string_lossy()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(include: &[&str], exclude: &[&str]) -> LibraryRoot {
+        LibraryRoot {
+            path: String::from("/music"),
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn everything_allowed_without_patterns() {
+        let root = root(&[], &[]);
+        assert!(root.allows(Path::new("/music/Artist/Album/01.flac")));
+        assert!(!root.allows(Path::new("/elsewhere/01.flac")));
+    }
+
+    #[test]
+    fn name_patterns_match_at_any_depth() {
+        let root = root(&[], &["Samples", "*.wav"]);
+        assert!(root.allows(Path::new("/music/Artist/01.flac")));
+        assert!(!root.allows(Path::new("/music/Artist/Samples/kick.flac")));
+        assert!(!root.allows(Path::new("/music/Artist/take.wav")));
+        assert!(root.excludes(Path::new("/music/Producer/Samples")));
+    }
+
+    #[test]
+    fn path_patterns_match_from_the_root() {
+        let root = root(&["Albums/**"], &["Albums/Demos/"]);
+        assert!(root.allows(Path::new("/music/Albums/Artist/01.mp3")));
+        assert!(!root.allows(Path::new("/music/Podcasts/01.mp3")));
+        assert!(!root.allows(Path::new("/music/Albums/Demos/01.mp3")));
+        assert!(!root.excludes(Path::new("/music/Other/Albums/Demos")));
+    }
+}
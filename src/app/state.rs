@@ -0,0 +1,179 @@
+use super::queue::{EndBehaviour, Queue, QueueType};
+use super::settings::Settings;
+use super::{Track, TrackId};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Last state written to disk, so unchanged state isn't written again
+static LAST_SAVED: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::new()));
+
+/// Playback state kept between launches of the app
+/// Tracks are stored by path so the state still lines up after the library changes
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct SavedState {
+    pub queues: Vec<SavedQueue>,
+    pub current_queue: usize,
+    pub shuffle: bool,
+    pub progress_secs: f64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+pub struct SavedQueue {
+    pub queue_type: QueueType,
+    pub tracks: Vec<String>,
+    pub current_track: usize,
+    #[serde(default = "default_rate")]
+    pub rate: f32,
+    #[serde(default)]
+    pub end_behaviour: EndBehaviour,
+    /// Tracks a seeded radio picks around
+    #[serde(default)]
+    pub seeds: Vec<String>,
+}
+
+fn default_rate() -> f32 {
+    1.0
+}
+
+impl SavedState {
+    fn file() -> PathBuf {
+        Settings::dir().join("state.toml")
+    }
+
+    /// Captures the queues and playback position
+    pub fn new(
+        queues: &[Queue],
+        all_tracks: &BTreeMap<TrackId, Track>,
+        current_queue: usize,
+        shuffle: bool,
+        progress_secs: f64,
+    ) -> Self {
+        let queues = queues
+            .iter()
+            .map(|queue| SavedQueue {
+                queue_type: queue.queue_type.clone(),
+                tracks: queue
+                    .cached_order
+                    .iter()
+                    .filter_map(|id| all_tracks.get(id))
+                    .map(|track| track.file.clone())
+                    .collect(),
+                current_track: queue.current_track,
+                rate: queue.rate,
+                end_behaviour: queue.end_behaviour,
+                seeds: queue
+                    .seeds
+                    .iter()
+                    .filter_map(|id| all_tracks.get(id))
+                    .map(|track| track.file.clone())
+                    .collect(),
+            })
+            .collect();
+
+        Self {
+            queues,
+            current_queue,
+            shuffle,
+            progress_secs,
+        }
+    }
+
+    /// Loads the saved state, if there is one
+    pub fn load() -> Option<Self> {
+        let file = std::fs::read_to_string(Self::file()).ok()?;
+        match toml::from_str(&file) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                error!("Could not read saved state: {err}");
+                None
+            }
+        }
+    }
+
+    /// Writes the state to the settings directory
+    pub fn save(&self) {
+        let file = match toml::to_string(&self) {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Could not serialize state: {err}");
+                return;
+            }
+        };
+
+        let mut last_saved = LAST_SAVED.lock().unwrap();
+        if *last_saved == file {
+            return;
+        }
+
+        if let Err(err) = std::fs::write(Self::file(), &file) {
+            error!("Could not save state: {err}");
+            return;
+        }
+
+        info!("Saved playback state");
+        *last_saved = file;
+    }
+
+    /// Matches the saved queues back up to the loaded tracks, returning them along with the
+    /// index of the current queue
+    /// Tracks that no longer exist are dropped, along with any queues left empty
+    pub fn restore_queues(
+        &self,
+        all_tracks: &BTreeMap<TrackId, Track>,
+    ) -> Option<(Vec<Queue>, usize)> {
+        let ids: HashMap<&str, TrackId> = all_tracks
+            .iter()
+            .map(|(id, track)| (track.file.as_str(), *id))
+            .collect();
+
+        let mut current_queue = 0;
+        let mut queues = Vec::new();
+
+        for (i, saved) in self.queues.iter().enumerate() {
+            if let Some(queue) = saved.restore(&ids) {
+                if i == self.current_queue {
+                    current_queue = queues.len();
+                }
+                queues.push(queue);
+            }
+        }
+
+        if queues.is_empty() {
+            return None;
+        }
+
+        Some((queues, current_queue))
+    }
+}
+
+impl SavedQueue {
+    /// Rebuilds the queue from track paths, or None if none of its tracks exist anymore
+    fn restore(&self, ids: &HashMap<&str, TrackId>) -> Option<Queue> {
+        let mut current_track = self.current_track;
+        let mut tracks = Vec::new();
+
+        for (i, path) in self.tracks.iter().enumerate() {
+            match ids.get(path.as_str()) {
+                Some(id) => tracks.push(*id),
+                // Keep pointing at the same track when ones before it are missing
+                None if i < self.current_track => current_track -= 1,
+                None => {}
+            }
+        }
+
+        if tracks.is_empty() {
+            return None;
+        }
+
+        let mut queue = Queue::new(self.queue_type.clone(), tracks);
+        queue.current_track = current_track.min(queue.len() - 1);
+        queue.rate = self.rate;
+        queue.end_behaviour = self.end_behaviour;
+        queue.seeds = self.seeds.iter().filter_map(|path| ids.get(path.as_str()).copied()).collect();
+        Some(queue)
+    }
+}
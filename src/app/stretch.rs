@@ -0,0 +1,341 @@
+use rodio::source::SeekError;
+use rodio::{ChannelCount, SampleRate, Source};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Length of the windows used when stretching audio, in seconds
+const WINDOW_SECS: f64 = 0.04;
+
+/// Playback rate and file position of a single source, shared with the audio player
+pub struct StretchControl {
+    rate: AtomicU32,
+    /// Position in the file in seconds, stored as the bits of an f64
+    position: AtomicU64,
+}
+
+impl StretchControl {
+    pub fn new(rate: f32) -> Arc<Self> {
+        let control = StretchControl {
+            rate: AtomicU32::new(0),
+            position: AtomicU64::new(0),
+        };
+        control.set_rate(rate);
+        Arc::new(control)
+    }
+
+    pub fn rate(&self) -> f32 {
+        f32::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+
+    pub fn set_rate(&self, rate: f32) {
+        self.rate.store(rate.clamp(0.25, 4.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Position in the file in seconds, which is independent of the playback rate
+    pub fn position(&self) -> f64 {
+        f64::from_bits(self.position.load(Ordering::Relaxed))
+    }
+
+    fn set_position(&self, seconds: f64) {
+        self.position.store(seconds.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Source that plays its input at a different rate
+/// With pitch preservation on the audio is time stretched using WSOLA, otherwise it is simply
+/// resampled, which changes the pitch along with the speed
+pub struct TimeStretch<I> {
+    input: I,
+    control: Arc<StretchControl>,
+    preserve_pitch: Arc<AtomicBool>,
+    channels: usize,
+    sample_rate: f64,
+    /// Interleaved input samples, the first of which is frame `buffer_start`
+    buffer: VecDeque<f32>,
+    buffer_start: usize,
+    /// Read position in input frames since the start or last seek
+    read_pos: f64,
+    /// File position in seconds of frame 0, moved by seeking
+    offset: f64,
+    output: VecDeque<f32>,
+    window: Vec<f32>,
+    /// Windowed tail of the previous segment, added onto the start of the next one
+    overlap: Vec<f32>,
+    /// First frame of the previous segment
+    last_segment: Option<usize>,
+}
+
+impl<I: Source> TimeStretch<I> {
+    pub fn new(input: I, control: Arc<StretchControl>, preserve_pitch: Arc<AtomicBool>) -> Self {
+        let channels = input.channels().max(1) as usize;
+        let sample_rate = input.sample_rate() as f64;
+
+        // Periodic Hann window, which sums to one when overlapped by half
+        let len = ((sample_rate * WINDOW_SECS) as usize / 2 * 2).max(2);
+        let window = (0..len)
+            .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / len as f32).cos())
+            .collect();
+
+        Self {
+            input,
+            control,
+            preserve_pitch,
+            channels,
+            sample_rate,
+            buffer: VecDeque::new(),
+            buffer_start: 0,
+            read_pos: 0.0,
+            offset: 0.0,
+            output: VecDeque::new(),
+            window,
+            overlap: Vec::new(),
+            last_segment: None,
+        }
+    }
+
+    /// Reads input until the buffer holds every frame before `end`
+    /// Returns false if the input ran out first
+    fn fill_to(&mut self, end: usize) -> bool {
+        while self.buffer_start + self.buffer.len() / self.channels < end {
+            match self.input.next() {
+                Some(sample) => self.buffer.push_back(sample),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    fn sample(&self, frame: usize, channel: usize) -> Option<f32> {
+        let idx = frame.checked_sub(self.buffer_start)? * self.channels + channel;
+        self.buffer.get(idx).copied()
+    }
+
+    fn mono(&self, frame: usize) -> f32 {
+        (0..self.channels)
+            .map(|c| self.sample(frame, c).unwrap_or(0.0))
+            .sum()
+    }
+
+    /// Drops buffered frames before the given frame
+    fn trim_to(&mut self, frame: usize) {
+        let frames = frame.saturating_sub(self.buffer_start);
+        let samples = (frames * self.channels).min(self.buffer.len() / self.channels * self.channels);
+        self.buffer.drain(..samples);
+        self.buffer_start += samples / self.channels;
+    }
+
+    /// Copies the next frame straight to the output
+    fn passthrough(&mut self) -> Option<()> {
+        let frame = self.read_pos as usize;
+        if !self.fill_to(frame + 1) {
+            return None;
+        }
+
+        for c in 0..self.channels {
+            self.output.push_back(self.sample(frame, c)?);
+        }
+
+        self.read_pos = (frame + 1) as f64;
+        self.trim_to(frame + 1);
+        Some(())
+    }
+
+    /// Linearly interpolates one output frame, stepping through the input at the given rate
+    fn resample(&mut self, rate: f32) -> Option<()> {
+        let frame = self.read_pos as usize;
+        let frac = (self.read_pos - frame as f64) as f32;
+        if !self.fill_to(frame + 1) {
+            return None;
+        }
+        self.fill_to(frame + 2);
+
+        for c in 0..self.channels {
+            let a = self.sample(frame, c)?;
+            let b = self.sample(frame + 1, c).unwrap_or(a);
+            self.output.push_back(a + (b - a) * frac);
+        }
+
+        self.read_pos += rate as f64;
+        self.trim_to(self.read_pos as usize);
+        Some(())
+    }
+
+    /// Outputs half a window of time stretched audio
+    /// Each segment is taken from near the read position, at the offset that lines up best with
+    /// where the previous segment would have carried on, then overlapped onto it
+    fn stretch(&mut self, rate: f32) -> Option<()> {
+        let len = self.window.len();
+        let hop = len / 2;
+        let tolerance = hop / 2;
+        let target = self.read_pos as usize;
+
+        let start = match self.last_segment {
+            None => target,
+            Some(last) => {
+                let natural = last + hop;
+                self.fill_to(target + tolerance + len);
+
+                let lowest = target.saturating_sub(tolerance).max(self.buffer_start);
+                (lowest..=target + tolerance)
+                    .step_by(2)
+                    .map(|candidate| {
+                        let similarity: f32 = (0..hop)
+                            .step_by(2)
+                            .map(|n| self.mono(natural + n) * self.mono(candidate + n))
+                            .sum();
+                        (candidate, similarity)
+                    })
+                    .max_by(|a, b| a.1.total_cmp(&b.1))
+                    .map(|(candidate, _)| candidate)
+                    .unwrap_or(target)
+            }
+        };
+
+        if !self.fill_to(start + 1) {
+            // Input is finished, so only the tail of the last segment is left
+            if self.overlap.is_empty() {
+                return None;
+            }
+            self.output.extend(self.overlap.drain(..));
+            return Some(());
+        }
+        self.fill_to(start + len);
+
+        let mut overlap = Vec::with_capacity(hop * self.channels);
+        for n in 0..len {
+            for c in 0..self.channels {
+                let sample = self.sample(start + n, c).unwrap_or(0.0) * self.window[n];
+                if n < hop {
+                    let previous = self.overlap.get(n * self.channels + c).unwrap_or(&0.0);
+                    self.output.push_back(previous + sample);
+                } else {
+                    overlap.push(sample);
+                }
+            }
+        }
+
+        self.overlap = overlap;
+        self.last_segment = Some(start);
+        self.read_pos += hop as f64 * rate as f64;
+
+        let keep_from = (self.read_pos as usize).saturating_sub(tolerance).min(start + hop);
+        self.trim_to(keep_from);
+        Some(())
+    }
+}
+
+impl<I: Source> Iterator for TimeStretch<I> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(sample) = self.output.pop_front() {
+            return Some(sample);
+        }
+
+        let rate = self.control.rate();
+        let stretching = self.preserve_pitch.load(Ordering::Relaxed) && (rate - 1.0).abs() > 0.001;
+
+        if !stretching && (self.last_segment.take().is_some() || !self.overlap.is_empty()) {
+            // Leaving the stretcher, so the tail of its last segment is played first
+            self.output.extend(self.overlap.drain(..));
+            if let Some(sample) = self.output.pop_front() {
+                return Some(sample);
+            }
+        }
+
+        if stretching {
+            self.stretch(rate)?;
+        } else if (rate - 1.0).abs() > 0.001 {
+            self.resample(rate)?;
+        } else {
+            self.passthrough()?;
+        }
+
+        self.control
+            .set_position(self.offset + self.read_pos / self.sample_rate);
+        self.output.pop_front()
+    }
+}
+
+impl<I: Source> Source for TimeStretch<I> {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> ChannelCount {
+        self.channels as ChannelCount
+    }
+
+    fn sample_rate(&self) -> SampleRate {
+        self.sample_rate as SampleRate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.input.try_seek(pos)?;
+
+        self.buffer.clear();
+        self.buffer_start = 0;
+        self.read_pos = 0.0;
+        self.offset = pos.as_secs_f64();
+        self.output.clear();
+        self.overlap.clear();
+        self.last_segment = None;
+        self.control.set_position(self.offset);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn stretch(rate: f32, preserve_pitch: bool, samples: Vec<f32>) -> (Vec<f32>, f64) {
+        let control = StretchControl::new(rate);
+        let source = SamplesBuffer::new(2, 1000, samples);
+        let preserve = Arc::new(AtomicBool::new(preserve_pitch));
+        let output = TimeStretch::new(source, control.clone(), preserve).collect();
+        (output, control.position())
+    }
+
+    #[test]
+    fn normal_rate_is_identity() {
+        let samples: Vec<f32> = (0..2000).map(|i| (i as f32 * 0.01).sin()).collect();
+        let (output, position) = stretch(1.0, true, samples.clone());
+
+        assert_eq!(output, samples);
+        assert_eq!(position, 1.0);
+    }
+
+    #[test]
+    fn resampling_changes_length() {
+        let (output, _) = stretch(2.0, false, vec![0.5; 2000]);
+        assert_eq!(output.len(), 1000);
+    }
+
+    #[test]
+    fn stretching_changes_length() {
+        let (output, position) = stretch(0.5, true, vec![0.5; 20000]);
+
+        let expected = 40000.0;
+        assert!((output.len() as f32 - expected).abs() / expected < 0.05);
+        assert!((position - 10.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn stretching_keeps_level() {
+        let (output, _) = stretch(1.5, true, vec![0.5; 20000]);
+
+        // Past the fade in of the first window the overlapped windows add back up to the input
+        let middle = &output[output.len() / 4..output.len() / 2];
+        assert!(middle.iter().all(|s| (s - 0.5).abs() < 0.01));
+    }
+}
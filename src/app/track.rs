@@ -1,32 +1,72 @@
+use super::audio::open_decoder;
+use super::oggtag::OggTag;
 use super::queue::QueueType;
+use super::settings::LibraryRoot;
 use super::utils::similar;
-use crate::database::init_db;
+use crate::analysis::{estimate_key, Loudness};
+use crate::database::{hash_filename, init_db};
+use base64::prelude::{Engine, BASE64_STANDARD_NO_PAD};
 use id3::Tag;
 use id3::TagLike;
-use log::info;
-use crate::database::{get_from_cache, save_to_cache};
+use log::{error, info, warn};
+use crate::database::{
+    get_from_cache, load_cached_files, load_track_ids, new_track_id, remove_from_cache,
+    save_to_cache,
+};
 use ndarray::Array1;
 use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fs;
 use std::io;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+/// Persistent id of a track, which stays the same across rescans and directory changes
+pub type TrackId = usize;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Track {
     pub file: String,
     pub title: String,
     pub album: String,
+    /// Album artist from the tags, empty if the file doesn't have one
+    pub album_artist: String,
     pub artists: Vec<String>,
     pub genres: Vec<String>,
     pub mood: Option<Mood>,
     pub trackno: usize,
+    pub disc_no: usize,
+    /// Is the track part of a various artists compilation
+    pub compilation: bool,
     pub year: String,
     pub len: f64,
 }
 
+/// Artist that untagged compilations are filed under
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// An album, told apart from others with the same title by the artist it is filed under
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Serialize, Deserialize)]
+pub struct AlbumId {
+    pub artist: String,
+    pub title: String,
+}
+
+impl AlbumId {
+    /// Loosely matches two albums, ignoring case and punctuation
+    pub fn similar(&self, other: &AlbumId) -> bool {
+        similar(&self.title, &other.title) && similar(&self.artist, &other.artist)
+    }
+}
+
+impl fmt::Display for AlbumId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.title)
+    }
+}
+
 impl Track {
     /// Does the track match the given queuetype
     pub fn matches(&self, queue_type: QueueType) -> bool {
@@ -37,7 +77,7 @@ impl Track {
                 .artists
                 .iter()
                 .any(|artist| similar(artist, &target_artist)),
-            QueueType::Album(album) => similar(&album, &self.album),
+            QueueType::Album(album) => album.similar(&self.album_id()),
             QueueType::Genre(target_genre) => self
                 .genres
                 .iter()
@@ -47,6 +87,26 @@ impl Track {
         }
     }
 
+    /// The album this track is on
+    /// Albums are filed under the album artist, so compilations show up once rather than
+    /// once per artist, falling back on the first artist for files without one
+    pub fn album_id(&self) -> AlbumId {
+        let artist = if !self.album_artist.is_empty() {
+            self.album_artist.clone()
+        } else if self.compilation {
+            VARIOUS_ARTISTS.to_string()
+        } else {
+            self.artists.first().cloned().unwrap_or_default()
+        };
+
+        AlbumId { artist, title: self.album.clone() }
+    }
+
+    /// Where the track comes on its album, for sorting by disc and then track number
+    pub fn album_position(&self) -> (usize, usize) {
+        (self.disc_no, self.trackno)
+    }
+
     /// Artists shared between two tracks
     pub fn shared_artists(&self, other: &Self) -> usize {
         self.artists
@@ -78,7 +138,98 @@ impl Track {
             .is_some()
     }
 
+    /// Writes the editable tags back into the file
     pub fn save_to_disk(&self) -> anyhow::Result<()> {
+        match file_extension(&self.file).as_str() {
+            "flac" => self.save_flac_tags(),
+            "ogg" | "opus" => self.save_ogg_tags(),
+            "m4a" | "mp4" | "alac" => self.save_mp4_tags(),
+            // Detected the same way as when loading, so MP4 containers don't get an ID3 tag
+            "aac" if mp4ameta::Tag::read_from_path(&self.file).is_ok() => self.save_mp4_tags(),
+            _ => self.save_id3_tags(),
+        }
+    }
+
+    fn save_flac_tags(&self) -> anyhow::Result<()> {
+        let mut tag = metaflac::Tag::read_from_path(&self.file)?;
+        let comments = &mut tag.vorbis_comments_mut().comments;
+        self.write_comments(comments);
+        tag.write_to_path(&self.file)?;
+
+        Ok(())
+    }
+
+    fn save_ogg_tags(&self) -> anyhow::Result<()> {
+        let mut tag = OggTag::read_from_path(&self.file)?;
+        self.write_comments(&mut tag.comments);
+        tag.write_to_path(&self.file)?;
+
+        Ok(())
+    }
+
+    fn save_mp4_tags(&self) -> anyhow::Result<()> {
+        let mut tag = mp4ameta::Tag::read_from_path(&self.file)?;
+
+        tag.set_title(self.title.clone());
+        tag.set_album(self.album.clone());
+        tag.set_artists(self.artists.clone());
+        tag.set_genres(self.genres.clone());
+        tag.set_track_number(self.trackno as u16);
+        tag.set_disc_number(self.disc_no as u16);
+
+        // Years are usually stored as a full timestamp, which is kept if its year wasn't edited
+        match self.year.is_empty() {
+            true => tag.remove_year(),
+            false if !tag.year().is_some_and(|date| date.starts_with(&self.year)) => {
+                tag.set_year(self.year.clone());
+            }
+            false => {}
+        }
+        match self.album_artist.is_empty() {
+            true => tag.remove_album_artists(),
+            false => tag.set_album_artist(self.album_artist.clone()),
+        }
+        match self.compilation {
+            true => tag.set_compilation(),
+            false => tag.remove_compilation(),
+        }
+
+        tag.write_to_path(&self.file)?;
+
+        Ok(())
+    }
+
+    /// Writes the editable tags into Vorbis comments
+    fn write_comments(&self, comments: &mut HashMap<String, Vec<String>>) {
+        set_comment(comments, "TITLE", vec![self.title.clone()]);
+        set_comment(comments, "ALBUM", vec![self.album.clone()]);
+        set_comment(comments, "ARTIST", self.artists.clone());
+        set_comment(comments, "GENRE", self.genres.clone());
+        set_comment(comments, "ALBUMARTIST", non_empty(&self.album_artist));
+        set_comment(comments, "DISCNUMBER", vec![self.disc_no.to_string()]);
+
+        // Numbers like "3/12" and full dates are kept unless what they're read as was edited
+        let first = |key: &str| get_comment(comments, key).into_iter().next();
+        let trackno = first("TRACKNUMBER").and_then(|n| n.split('/').next()?.trim().parse().ok());
+        let date = first("ORIGINALDATE").or_else(|| first("DATE"));
+        if trackno != Some(self.trackno) {
+            set_comment(comments, "TRACKNUMBER", vec![self.trackno.to_string()]);
+        }
+        if self.year.is_empty() || !date.is_some_and(|date| date.starts_with(&self.year)) {
+            // The original date is read first, so it can't be left behind with the old year
+            set_comment(comments, "ORIGINALDATE", Vec::new());
+            set_comment(comments, "DATE", non_empty(&self.year));
+        }
+        let compilation = if self.compilation { vec![String::from("1")] } else { Vec::new() };
+        set_comment(comments, "COMPILATION", compilation);
+
+        // Would otherwise be read over the edited artists
+        if !get_comment(comments, "ARTISTS").is_empty() {
+            set_comment(comments, "ARTISTS", self.artists.clone());
+        }
+    }
+
+    fn save_id3_tags(&self) -> anyhow::Result<()> {
         let mut tag = match Tag::read_from_path(&self.file) {
             Ok(tag) => tag,
             Err(id3::Error{kind: id3::ErrorKind::NoTag, ..}) => Tag::new(),
@@ -89,6 +240,40 @@ impl Track {
         tag.set_title(self.title.clone());
         tag.set_artist(self.artists.clone().join("\0"));
         tag.set_genre(self.genres.clone().join("\0"));
+        tag.set_track(self.trackno as u32);
+        tag.set_disc(self.disc_no as u32);
+
+        let stored_year = tag.original_date_released().or_else(|| tag.date_released()).map(|date| date.year);
+        match self.year.parse::<i32>() {
+            Ok(year) if stored_year == Some(year) => {}
+            year => {
+                // The original release date is read first, so it can't keep the old year
+                tag.remove_original_date_released();
+                match year {
+                    Ok(year) => tag.set_date_released(id3::Timestamp {
+                        year,
+                        month: None,
+                        day: None,
+                        hour: None,
+                        minute: None,
+                        second: None,
+                    }),
+                    Err(_) => tag.remove_date_released(),
+                }
+            }
+        }
+
+        match self.album_artist.is_empty() {
+            true => tag.remove_album_artist(),
+            false => tag.set_album_artist(self.album_artist.clone()),
+        }
+        // iTunes' compilation frame, which most other players read too
+        match self.compilation {
+            true => tag.set_text("TCMP", "1"),
+            false => {
+                tag.remove("TCMP");
+            }
+        }
 
         tag.write_to_path(&self.file, id3::Version::Id3v24)?;
 
@@ -102,42 +287,121 @@ impl Default for Track {
             file: String::new(),
             title: String::from("No Track Selected"),
             album: Default::default(),
+            album_artist: Default::default(),
             artists: Default::default(),
             genres: Default::default(),
             year: Default::default(),
             mood: Default::default(),
             trackno: 1,
+            disc_no: 1,
+            compilation: false,
             len: 100.0,
         }
     }
 }
 
-/// Loads all tracks recursively in a directory
-pub fn load_tracks(directory: &str) -> anyhow::Result<Vec<Track>> {
-    info!("Loading tracks from {directory}");
+/// Counts of what changed in the library since the last scan
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ScanReport {
+    pub added: usize,
+    pub changed: usize,
+    pub removed: usize,
+}
+
+impl fmt::Display for ScanReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} changed, {} removed",
+            self.added, self.changed, self.removed
+        )
+    }
+}
+
+/// Size in bytes and modification time in seconds of a file, used to notice when it changes
+pub fn file_stamp(file: &str) -> Option<(u64, i64)> {
+    let metadata = fs::metadata(file).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?;
+    Some((metadata.len(), modified.as_secs() as i64))
+}
+
+/// Loads all tracks recursively in a directory, keyed by their persistent ids
+/// Tags are only read from files that are new or have changed since they were cached, and
+/// cached tracks whose files no longer exist are removed
+pub fn load_tracks(roots: &[LibraryRoot]) -> anyhow::Result<(BTreeMap<TrackId, Track>, ScanReport)> {
+    info!("Loading tracks from {roots:?}");
     let cache = init_db()?;
 
-    let files = get_song_files(directory)?;
+    let files = get_song_files(roots);
     let started = std::time::SystemTime::now();
     info!("Loaded {} tracks", files.len());
-    let mut tracks = Vec::new();
+    let ids = load_track_ids(&cache)?;
+    let mut cached = load_cached_files(&cache)?;
+    let mut tracks = BTreeMap::new();
+    let mut report = ScanReport::default();
 
     for file in files {
-        match get_from_cache(&cache, &file)? {
+        let file_hash = hash_filename(&file);
+        let id = match ids.get(&file_hash) {
+            Some(id) => *id,
+            None => new_track_id(&cache, &file)?,
+        };
+
+        let is_new = !cached.contains_key(&file_hash);
+        let cached_track = match cached.remove(&file_hash) {
+            Some(entry) => match entry.stamp {
+                Some(stamp) if Some(stamp) == file_stamp(&file) => get_from_cache(&cache, &file)?,
+                // Cached before stamps were stored, so trust it and record the stamp
+                None => {
+                    let track = get_from_cache(&cache, &file)?;
+                    if let Some(track) = &track {
+                        save_to_cache(&cache, track)?;
+                    }
+                    track
+                }
+                Some(_) => None,
+            },
+            None => None,
+        };
+
+        match cached_track {
             Some(track) => {
-                tracks.push(track);
-            }
-            None => {
-                let track = load_track(file)?;
-                tracks.push(track.clone());
-                save_to_cache(&cache, &track)?;
+                tracks.insert(id, track);
             }
+            None => match load_track(file.clone()) {
+                Ok(track) => {
+                    if is_new {
+                        report.added += 1;
+                    } else {
+                        report.changed += 1;
+                    }
+                    save_to_cache(&cache, &track)?;
+                    tracks.insert(id, track);
+                }
+                // Left out until it can be read, so it isn't cached as an empty track
+                Err(err) => error!("Could not load {file}: {err:?}"),
+            },
+        }
+    }
+
+    // Whatever is left wasn't found in the directory, but may just be from another directory
+    // Files on a folder that isn't mounted right now are kept for when it comes back
+    for (file_hash, entry) in cached {
+        let path = PathBuf::from(&entry.path);
+        let unmounted = roots.iter().any(|root| root.contains(&path) && !root.exists());
+        if !path.exists() && !unmounted {
+            remove_from_cache(&cache, &file_hash)?;
+            report.removed += 1;
         }
     }
 
-    info!("Track information loaded in {:?}", started.elapsed());
+    info!("Track information loaded in {:?}, {report}", started.elapsed());
 
-    Ok(tracks)
+    Ok((tracks, report))
 }
 
 /// Get artists from a track tag
@@ -317,68 +581,162 @@ pub fn get_text(tag: &Tag, key: &str) -> Option<String> {
 
 /// Loads a track in for any file type
 pub fn load_track(file: String) -> anyhow::Result<Track> {
-    let filetype = file.split('.').last().unwrap_or("");
-
-    Ok(match filetype {
+    match file_extension(&file).as_str() {
         "flac" => load_flac_track(file),
-        "ogg" => load_ogg_track(file),
+        "ogg" | "opus" => load_ogg_track(file),
+        "m4a" | "mp4" | "alac" => load_mp4_track(file),
+        // Usually raw ADTS streams tagged with ID3, but sometimes an MP4 container
+        "aac" => load_mp4_track(file.clone()).or_else(|_| load_id3_track(file)),
         _ => load_id3_track(file),
     }
-    .unwrap_or_default())
 }
 
-/// Loads in an OGG type track
+/// Loads in an OGG type track, either Vorbis or Opus
 pub fn load_ogg_track(file: String) -> anyhow::Result<Track> {
-    // let f = std::fs::File::open(&file)?;
-    // let tag = lewton::inside_ogg::OggStreamReader::new(f)?;
-    //
-    // let comments = tag.comment_hdr;
-    let track = Track {
-        file,
-        ..Default::default()
+    let tag = OggTag::read_from_path(&file)?;
+
+    Ok(track_from_comments(file, &tag.comments, tag.len))
+}
+
+/// Loads in an MP4 type track from its iTunes atoms, which includes m4a, alac and aac
+pub fn load_mp4_track(file: String) -> anyhow::Result<Track> {
+    let tag = mp4ameta::Tag::read_from_path(&file)?;
+
+    let title = match tag.title() {
+        Some(title) if !title.is_empty() => title.to_string(),
+        _ => file.clone(),
     };
 
-    // for (key, value) in &comments.comment_list {
-    //     match key.as_str() {
-    //         "TRACKNUMBER" => track.trackno = value.parse()?,
-    //         "ARTIST" => track.artists = value.split(|c| c == '\0' || c == ';').map(|a| a.to_string()).collect(),
-    //         "GENRE" => track.genres = value.split(|c| c == '\0' || c == ';').map(|g| g.to_string()).collect(),
-    //         "TITLE" => track.title = value.clone(),
-    //         "ALBUM" => track.album = value.clone(),
-    //         _ => {}
-    //     }
-    // }
+    let mut artists: Vec<String> = tag.artists().map(|a| a.to_string()).collect();
+    if artists.is_empty() {
+        artists = tag.album_artists().map(|a| a.to_string()).collect();
+    }
+
+    let len = tag.duration().unwrap_or(Duration::ZERO).as_secs_f64();
+    // Dates are usually a full timestamp, only the year is kept
+    let year = tag
+        .year()
+        .map(|date| date.split('-').next().unwrap_or_default().to_string())
+        .unwrap_or_default();
 
-    Ok(track)
+    Ok(Track {
+        title,
+        album: tag.album().unwrap_or_default().to_string(),
+        artists,
+        album_artist: tag.album_artist().unwrap_or_default().to_string(),
+        genres: tag.genres().map(|g| g.to_string()).collect(),
+        mood: None,
+        trackno: tag.track_number().unwrap_or(1) as usize,
+        disc_no: tag.disc_number().unwrap_or(1) as usize,
+        compilation: tag.compilation(),
+        year,
+        len,
+        file,
+    })
 }
 
 /// Loads in an flac track
 pub fn load_flac_track(file: String) -> anyhow::Result<Track> {
-    // let tag = metaflac::Tag::read_from_path(&file)?;
-    let track = Track { file, ..Default::default() };
-    
-    // for frame in tag.blocks() {
-    //     if let VorbisComment(ref comments) = &frame {
-    //         for (key, value) in &comments.comments {
-    //             match key.as_str() {
-    //                 "TRACKNUMBER" => track.trackno = value[0].parse()?,
-    //                 "ARTIST" => track.artists = value.clone(),
-    //                 "GENRE" => track.genres = value.clone(),
-    //                 "TITLE" => track.title = value[0].clone(),
-    //                 "ALBUM" => track.album = value[0].clone(),
-    //                 _ => {}
-    //             }
-    //         }
-    //     }
-    // }
-
-    Ok(track)
+    let tag = metaflac::Tag::read_from_path(&file)?;
+
+    let len = match tag.get_streaminfo() {
+        Some(info) if info.sample_rate > 0 => info.total_samples as f64 / info.sample_rate as f64,
+        _ => 0.0,
+    };
+
+    let comments = match tag.vorbis_comments() {
+        Some(comments) => comments.comments.clone(),
+        None => HashMap::new(),
+    };
+
+    Ok(track_from_comments(file, &comments, len))
+}
+
+/// Builds a track from Vorbis comments, as used by FLAC and OGG files
+pub fn track_from_comments(file: String, comments: &HashMap<String, Vec<String>>, len: f64) -> Track {
+    let first = |key: &str| get_comment(comments, key).into_iter().next();
+
+    let title = first("TITLE").unwrap_or_else(|| file.clone());
+
+    // ARTISTS holds each artist separately when ARTIST is a single joined credit
+    let mut artists = get_comment(comments, "ARTISTS");
+    if artists.is_empty() {
+        artists = get_comment(comments, "ARTIST");
+    }
+    if artists.is_empty() {
+        artists = get_comment(comments, "ALBUMARTIST");
+    }
+
+    let mut genres = get_comment(comments, "GENRE");
+    genres.append(&mut get_comment(comments, "AB:GENRE"));
+
+    // Track and disc numbers are sometimes written as "3/12"
+    let number = |key: &str| first(key).and_then(|n| n.split('/').next()?.trim().parse().ok());
+    let trackno = number("TRACKNUMBER").unwrap_or(1);
+    let disc_no = number("DISCNUMBER").unwrap_or(1);
+
+    let album_artist = first("ALBUMARTIST")
+        .or_else(|| first("ALBUM ARTIST"))
+        .unwrap_or_default();
+
+    // Dates are usually a full date, only the year is kept
+    let year = first("ORIGINALDATE")
+        .or_else(|| first("DATE"))
+        .map(|date| date.split('-').next().unwrap_or_default().to_string())
+        .unwrap_or_default();
+
+    Track {
+        file,
+        title,
+        album: first("ALBUM").unwrap_or_default(),
+        album_artist,
+        artists,
+        genres,
+        mood: None,
+        trackno,
+        disc_no,
+        compilation: first("COMPILATION").is_some_and(|c| c == "1"),
+        year,
+        len,
+    }
+}
+
+/// Gets all values of a Vorbis comment, ignoring the case of the key
+pub fn get_comment(comments: &HashMap<String, Vec<String>>, key: &str) -> Vec<String> {
+    comments
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+        .flat_map(|(_, values)| values.iter().flat_map(|v| v.split('\0')))
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect()
+}
+
+/// A single value list, or an empty one to remove the comment
+fn non_empty(value: &str) -> Vec<String> {
+    match value.is_empty() {
+        true => Vec::new(),
+        false => vec![value.to_string()],
+    }
+}
+
+/// Sets the values of a Vorbis comment, replacing it under any case
+pub fn set_comment(comments: &mut HashMap<String, Vec<String>>, key: &str, values: Vec<String>) {
+    comments.retain(|k, _| !k.eq_ignore_ascii_case(key));
+    if !values.is_empty() {
+        comments.insert(key.to_string(), values);
+    }
 }
 
 /// Loads in a ID3 track, which includes mp3, wav, aiff and more
 pub fn load_id3_track(file: String) -> anyhow::Result<Track> {
-    let tag = Tag::read_from_path(file.clone())?;
-    let source = rodio::Decoder::new(BufReader::new(fs::File::open(file.clone())?))?;
+    // Untagged files can still be played, so they get their title from the file name
+    let tag = match Tag::read_from_path(file.clone()) {
+        Ok(tag) => tag,
+        Err(id3::Error{kind: id3::ErrorKind::NoTag, ..}) => Tag::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let source = open_decoder(&file)?;
 
     let mut title = tag.title().unwrap_or_default().to_string();
     if title.is_empty() {
@@ -400,6 +758,9 @@ pub fn load_id3_track(file: String) -> anyhow::Result<Track> {
         .unwrap_or(Duration::ZERO)
         .as_secs_f64();
     let trackno = tag.track().unwrap_or(1) as usize;
+    let disc_no = tag.disc().unwrap_or(1) as usize;
+    let album_artist = tag.album_artist().unwrap_or_default().to_string();
+    let compilation = tag.get("TCMP").and_then(|frame| frame.content().text()) == Some("1");
 
     let mut year = String::new();
     if let Some(date) = tag.original_date_released() {
@@ -419,87 +780,129 @@ pub fn load_id3_track(file: String) -> anyhow::Result<Track> {
         title,
         artists,
         album,
+        album_artist,
         genres,
         year,
         len,
         mood,
         trackno,
+        disc_no,
+        compilation,
     })
 }
 
 /// Returns list of song files in a given directory
-fn get_song_files(directory: &str) -> Result<Vec<String>, io::Error> {
-    // Can't seem to load paths with tildes in them
-    let expanded = if let Some(home) = dirs::home_dir() {
+pub fn get_song_files(roots: &[LibraryRoot]) -> Vec<String> {
+    get_library_files(roots, path_is_audio)
+}
+
+/// Reads the files of a type from each library folder, following their patterns
+pub fn get_library_files(roots: &[LibraryRoot], wanted: fn(PathBuf) -> bool) -> Vec<String> {
+    let mut files = Vec::new();
+    // Shared between roots so overlapping folders aren't read twice
+    let mut visited = HashSet::new();
+
+    for root in roots {
+        if let Err(err) = recursive_read_dir(&root.dir(), root, wanted, &mut visited, &mut files) {
+            error!("Could not read library folder {}: {err}", root.path);
+        }
+    }
+
+    files
+}
+
+/// Lowercased extension of a file, so that files are matched to their format in any case
+pub fn file_extension(file: impl AsRef<Path>) -> String {
+    file.as_ref().extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase()
+}
+
+/// Expands the tilde in a directory, which paths can't be loaded with
+pub fn expand_directory(directory: &str) -> String {
+    if let Some(home) = dirs::home_dir() {
         directory.replace("~", &home.display().to_string())
     } else {
         directory.to_string()
-    };
-
-    let files = recursive_read_dir(&expanded)?;
-
-    Ok(files)
+    }
 }
 
-/// Recursively reads a directory
-fn recursive_read_dir(dir: &str) -> Result<Vec<String>, io::Error> {
-    let mut files = Vec::new();
+/// Recursively reads a directory, skipping directories that were already read so that
+/// symlinks pointing back up the tree don't loop forever
+/// Only fails if `dir` itself can't be read, folders inside it that can't be read are skipped
+pub fn recursive_read_dir(
+    dir: &Path,
+    root: &LibraryRoot,
+    wanted: fn(PathBuf) -> bool,
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<String>,
+) -> Result<(), io::Error> {
+    if !visited.insert(dir.canonicalize()?) {
+        warn!("Skipping {dir:?}, it has already been read");
+        return Ok(());
+    }
 
     for entry in fs::read_dir(dir)? {
-        let path = entry?.path();
-        let filename = path.to_str().unwrap().to_string();
+        // One unreadable folder shouldn't stop the rest of the library from loading
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(err) => {
+                error!("Could not read an entry in {dir:?}: {err}");
+                continue;
+            }
+        };
 
-        match path.is_file() {
-            true => {
-                if path_is_audio(path) {
-                    files.push(filename.into());
-                }
+        if path.is_file() {
+            if wanted(path.clone()) && root.allows(&path) {
+                files.push(path.to_string_lossy().to_string());
             }
-            false => {
-                let mut dir_files = recursive_read_dir(&filename)?;
-                files.append(&mut dir_files);
+        } else if path.is_dir() && !root.excludes(&path) {
+            if let Err(err) = recursive_read_dir(&path, root, wanted, visited, files) {
+                error!("Could not read {path:?}: {err}");
             }
         }
     }
 
-    Ok(files)
+    Ok(())
 }
 
 /// Returns the track image information from metadata as bytes
 pub fn get_track_image(file: &str) -> Option<Vec<u8>> {
-    let filetype = file.split(".").last()?;
-
-    match filetype {
+    match file_extension(file).as_str() {
         "flac" => {
-            // let tag = metaflac::Tag::read_from_path(&file).ok()?;
-            //
-            // for frame in tag.blocks() {
-            //     if let Block::Picture(picture) = frame {
-            //         return Some(picture.data.clone());
-            //     }
-            // }
-
-            None
+            let tag = metaflac::Tag::read_from_path(file).ok()?;
+
+            // Prefer the front cover over any other pictures, like the back or artist photos
+            let picture = tag
+                .pictures()
+                .find(|p| p.picture_type == metaflac::block::PictureType::CoverFront)
+                .or_else(|| tag.pictures().next())?;
+            Some(picture.data.clone())
         }
-        "ogg" => {
-            // let f = std::fs::File::open(file).ok()?;
-            // let tag = lewton::inside_ogg::OggStreamReader::new(f).ok()?;
-            //
-            // let comments = tag.comment_hdr;
-            // let picture = comments.comment_list.iter().find(|(k, v)| k == "METADATA_BLOCK_PICTURE")?;
-            //
-            // let mut encoded = picture.1.clone();
-            // for _ in 0..(encoded.len() % 4) {
-            //     encoded.push('=');
-            // }
-            //
-            // let decoded = base64::prelude::BASE64_STANDARD
-            //     .decode(encoded.as_bytes()).unwrap();
-            // let picture = metaflac::block::Picture::from_bytes(&decoded).ok()?;
-            //
-            // Some(picture.data)
-            None
+        "ogg" | "opus" => {
+            let tag = OggTag::read_from_path(file).ok()?;
+            let pictures: Vec<metaflac::block::Picture> =
+                get_comment(&tag.comments, "METADATA_BLOCK_PICTURE")
+                    .iter()
+                    .filter_map(|encoded| {
+                        // Some taggers leave off the padding
+                        let encoded = encoded.trim().trim_end_matches('=');
+                        let decoded = BASE64_STANDARD_NO_PAD.decode(encoded).ok()?;
+                        metaflac::block::Picture::from_bytes(&decoded).ok()
+                    })
+                    .collect();
+
+            let picture = pictures
+                .iter()
+                .find(|p| p.picture_type == metaflac::block::PictureType::CoverFront)
+                .or(pictures.first())?;
+            Some(picture.data.clone())
         }
+        "m4a" | "mp4" | "alac" | "aac" => match mp4ameta::Tag::read_from_path(file) {
+            Ok(tag) => Some(tag.artwork()?.data.to_vec()),
+            Err(_) => {
+                let tag = id3::Tag::read_from_path(file).ok()?;
+                Some(tag.pictures().next()?.data.clone())
+            }
+        },
         _ => {
             let tag = id3::Tag::read_from_path(file).ok()?;
             let picture = tag.pictures().next()?;
@@ -509,14 +912,9 @@ pub fn get_track_image(file: &str) -> Option<Vec<u8>> {
 }
 
 /// Is a file an audio file?
-fn path_is_audio(path: PathBuf) -> bool {
-    match path
-        .extension()
-        .unwrap_or_default()
-        .to_str()
-        .unwrap_or_default()
-    {
-        "mp3" | "opus" | "wav" | "flac" | "ogg" | "aiff" => true,
+pub fn path_is_audio(path: PathBuf) -> bool {
+    match file_extension(&path).as_str() {
+        "mp3" | "opus" | "wav" | "flac" | "ogg" | "aiff" | "m4a" | "mp4" | "aac" | "alac" => true,
         _ => false,
     }
 }
@@ -528,32 +926,71 @@ pub struct TrackInfo {
     pub chroma: Array1<f32>,
     pub spectral: Array1<f32>,
     pub energy: f32,
+    /// Estimated key, see `analysis::key`
     pub key: i32,
+    /// How well the key matched, 0 if it isn't known
+    pub key_confidence: f32,
     pub bpm: f32,
     pub zcr: f32,
+    /// Loudness of the track, None if it has not been measured yet
+    pub loudness: Option<Loudness>,
+    /// Album loudness, only known when the file has ReplayGain album tags
+    pub album_loudness: Option<Loudness>,
+    /// How much the MFCCs change across the track, empty if only one part of it was analysed
+    pub mfcc_var: Array1<f32>,
+    pub energy_var: f32,
+    /// Version of the analysis the features came from, see `analysis::analysis_version`
+    pub analysis_version: i32,
 }
 
 impl TrackInfo {
     /// Averages a set of track audio features
     pub fn average(tracks: Vec<TrackInfo>) -> TrackInfo {
         let count = tracks.len() as f32;
+        let chroma = tracks
+            .iter()
+            .fold(Array1::zeros(12), |a, b| a + b.chroma.clone())
+            / count;
+        // Keys can't be averaged, so the key is found again from the average chroma
+        let (key, key_confidence) = estimate_key(&chroma);
+
         TrackInfo {
             mfcc: tracks
                 .iter()
                 .fold(Array1::zeros(13), |a, b| a + b.mfcc.clone())
                 / count,
-            chroma: tracks
-                .iter()
-                .fold(Array1::zeros(12), |a, b| a + b.chroma.clone())
-                / count,
+            chroma,
             spectral: tracks
                 .iter()
                 .fold(Array1::zeros(6), |a, b| a + b.spectral.clone())
                 / count,
             energy: tracks.iter().map(|t| t.energy).sum::<f32>() / count,
-            key: tracks.iter().map(|t| t.key).sum::<i32>() / count as i32,
+            key,
+            key_confidence,
             bpm: tracks.iter().map(|t| t.bpm).sum::<f32>() / count,
             zcr: tracks.iter().map(|t| t.zcr).sum::<f32>() / count,
+            loudness: None,
+            album_loudness: None,
+            // Variances across different tracks don't mean anything as a single track
+            mfcc_var: Array1::zeros(0),
+            energy_var: 0.0,
+            analysis_version: 0,
+        }
+    }
+
+    /// Moves the features a fraction `t` of the way towards another track's, or away from it
+    /// if `t` is negative
+    pub fn moved_towards(&self, other: &TrackInfo, t: f32) -> TrackInfo {
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+
+        TrackInfo {
+            mfcc: &self.mfcc + &((&other.mfcc - &self.mfcc) * t),
+            chroma: &self.chroma + &((&other.chroma - &self.chroma) * t),
+            spectral: &self.spectral + &((&other.spectral - &self.spectral) * t),
+            energy: lerp(self.energy, other.energy),
+            bpm: lerp(self.bpm, other.bpm),
+            zcr: lerp(self.zcr, other.zcr),
+            ..self.clone()
         }
     }
 }
@@ -566,8 +1003,14 @@ impl Default for TrackInfo {
             spectral: Array1::zeros(6),
             energy: 0.0,
             key: 0,
+            key_confidence: 0.0,
             bpm: 0.0,
             zcr: 0.0,
+            loudness: None,
+            album_loudness: None,
+            mfcc_var: Array1::zeros(0),
+            energy_var: 0.0,
+            analysis_version: 0,
         }
     }
 }
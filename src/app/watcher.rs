@@ -0,0 +1,182 @@
+use super::settings::LibraryRoot;
+use super::track::{
+    file_stamp, load_track, path_is_audio, recursive_read_dir, ScanReport, Track, TrackId,
+};
+use crate::database::{
+    hash_filename, init_db, load_cached_files, load_track_ids, new_track_id, remove_from_cache,
+    save_to_cache,
+};
+use log::{error, info};
+use rusqlite::Connection;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+/// How long the library has to be quiet before changes are picked up, so files that are
+/// still being copied or downloaded aren't read half written
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Watches the library folders for files being added, changed or removed
+pub struct LibraryWatcher {
+    pub roots: Vec<LibraryRoot>,
+    // Stops watching once dropped
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    pending: HashSet<PathBuf>,
+}
+
+impl LibraryWatcher {
+    pub fn new(roots: &[LibraryRoot]) -> notify::Result<Self> {
+        let (tx, events) = channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+
+        // One folder missing, like an unplugged drive, shouldn't stop the others being watched
+        for root in roots {
+            match watcher.watch(&root.dir(), RecursiveMode::Recursive) {
+                Ok(()) => info!("Watching {} for changes", root.path),
+                Err(err) => error!("Could not watch {}: {err}", root.path),
+            }
+        }
+
+        Ok(Self {
+            roots: roots.to_vec(),
+            _watcher: watcher,
+            events,
+            pending: HashSet::new(),
+        })
+    }
+
+    /// Waits for changes to settle, returning the paths that changed
+    /// The list is empty if nothing changed, and None is returned once the watcher stops
+    pub fn next_changes(&mut self) -> Option<Vec<PathBuf>> {
+        match self.events.recv_timeout(SETTLE_TIME) {
+            Ok(Ok(event)) => {
+                if !matches!(event.kind, EventKind::Access(_)) {
+                    self.pending.extend(event.paths);
+                }
+                Some(Vec::new())
+            }
+            Ok(Err(err)) => {
+                error!("Error watching library: {err}");
+                Some(Vec::new())
+            }
+            Err(RecvTimeoutError::Timeout) => Some(self.pending.drain().collect()),
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+/// Applies changed paths to a copy of the library, only reading tags from files that changed
+/// Returns the new library along with the tracks that were added or changed
+pub fn apply_changes(
+    all_tracks: &BTreeMap<TrackId, Track>,
+    paths: &[PathBuf],
+    roots: &[LibraryRoot],
+) -> anyhow::Result<(BTreeMap<TrackId, Track>, BTreeMap<TrackId, Track>, ScanReport)> {
+    apply_changes_with(&init_db()?, all_tracks, paths, roots)
+}
+
+/// Applies changed paths to a copy of the library, caching tracks in the given database
+fn apply_changes_with(
+    cache: &Connection,
+    all_tracks: &BTreeMap<TrackId, Track>,
+    paths: &[PathBuf],
+    roots: &[LibraryRoot],
+) -> anyhow::Result<(BTreeMap<TrackId, Track>, BTreeMap<TrackId, Track>, ScanReport)> {
+    let ids = load_track_ids(cache)?;
+    let cached = load_cached_files(cache)?;
+    let mut library = all_tracks.clone();
+    let mut updated = BTreeMap::new();
+    let mut report = ScanReport::default();
+
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            // Directories moved in don't send events for the files inside them
+            let root = roots.iter().find(|root| root.contains(path) && !root.excludes(path));
+            if let Some(root) = root {
+                // Whatever was read before the error is still added
+                if let Err(err) = recursive_read_dir(path, root, path_is_audio, &mut HashSet::new(), &mut files) {
+                    error!("Could not read {path:?}: {err}");
+                }
+            }
+        } else if path.is_file() {
+            if path_is_audio(path.clone()) && roots.iter().any(|root| root.allows(path)) {
+                files.push(path.to_string_lossy().to_string());
+            }
+        } else {
+            // Gone, along with everything inside it if it was a directory
+            let removed: Vec<TrackId> = library
+                .iter()
+                .filter(|(_, track)| Path::new(&track.file).starts_with(path))
+                .map(|(id, _)| *id)
+                .collect();
+
+            for id in removed {
+                if let Some(track) = library.remove(&id) {
+                    remove_from_cache(cache, &hash_filename(&track.file))?;
+                    report.removed += 1;
+                }
+            }
+        }
+    }
+
+    for file in files {
+        let file_hash = hash_filename(&file);
+        let id = match ids.get(&file_hash) {
+            Some(id) => *id,
+            None => new_track_id(cache, &file)?,
+        };
+
+        let stamp = cached.get(&file_hash).and_then(|entry| entry.stamp);
+        if library.contains_key(&id) && stamp.is_some() && stamp == file_stamp(&file) {
+            continue;
+        }
+
+        // Skip files that can't be read yet rather than losing the whole batch
+        let track = match load_track(file.clone()) {
+            Ok(track) => track,
+            Err(err) => {
+                error!("Could not load {file}: {err:?}");
+                continue;
+            }
+        };
+        save_to_cache(cache, &track)?;
+
+        match library.insert(id, track.clone()) {
+            Some(_) => report.changed += 1,
+            None => report.added += 1,
+        }
+        updated.insert(id, track);
+    }
+
+    Ok((library, updated, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::open_db;
+
+    #[test]
+    fn truncated_files_are_skipped() {
+        let dir = std::env::temp_dir().join(format!("trackfish-watcher-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = open_db(&dir.join("tracks.db")).unwrap();
+
+        // ID3 header promising a tag that never arrives, like a file still being copied in
+        let file = dir.join("partial.mp3");
+        std::fs::write(&file, b"ID3\x04\x00\x00\x00\x00\x10\x00").unwrap();
+
+        let root = LibraryRoot::new(dir.to_string_lossy().to_string());
+        let result = apply_changes_with(&cache, &BTreeMap::new(), &[file], &[root]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (library, updated, report) = result.unwrap();
+        assert!(library.is_empty());
+        assert!(updated.is_empty());
+        assert_eq!(report, ScanReport::default());
+    }
+}
@@ -5,8 +5,12 @@ use rusqlite::{
     Connection, OptionalExtension, Result, Row, ToSql,
 };
 
+use crate::analysis::Loudness;
+use crate::app::affinity::Affinity;
+use crate::app::blocklist::{BlockKind, BlockLevel, Blocklist};
+use crate::app::normalize::FeatureStats;
 use crate::app::settings::Settings;
-use crate::app::track::{Mood, Track, TrackInfo};
+use crate::app::track::{file_stamp, Mood, Track, TrackId, TrackInfo};
 use ndarray::Array1;
 
 pub fn hash_filename(name: &str) -> String {
@@ -16,7 +20,9 @@ pub fn hash_filename(name: &str) -> String {
 }
 
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
+use std::path::Path;
 
 /// Checks if the database has columns that are no longer needed or removed from the app
 pub fn table_outdated(conn: &Connection, table: &str) -> bool {
@@ -53,9 +59,25 @@ pub fn table_outdated(conn: &Connection, table: &str) -> bool {
     false
 }
 
+/// Adds a column to a table created by an older version of the app
+/// Returns whether the column had to be added
+fn add_missing_column(conn: &Connection, table: &str, column: &str, kind: &str) -> Result<bool> {
+    if conn.prepare(&format!("SELECT {column} FROM {table} LIMIT 0")).is_err() {
+        info!("Adding column {column} to table {table}");
+        conn.execute(&format!("ALTER TABLE {table} ADD COLUMN {column} {kind}"), [])?;
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
 /// Spins up the database, creating it if needed
 pub fn init_db() -> Result<Connection> {
-    let file = Settings::dir().join("tracks.db");
+    open_db(&Settings::dir().join("tracks.db"))
+}
+
+/// Opens the database at a given file, creating it and bringing its tables up to date if needed
+pub fn open_db(file: &Path) -> Result<Connection> {
     let db_exists = file.exists();
     info!("Database exists at {file:?}: {db_exists}");
 
@@ -96,6 +118,82 @@ pub fn init_db() -> Result<Connection> {
         [],
     )?;
 
+    // Ids are never reused, so anything still holding the id of a removed track can't end up
+    // pointing at a different one
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS track_ids (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            file_hash TEXT NOT NULL UNIQUE
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS track_rates (
+            file_hash TEXT PRIMARY KEY,
+            rate FLOAT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS track_affinity (
+            file_hash TEXT PRIMARY KEY,
+            affinity FLOAT NOT NULL,
+            updated INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blocklist (
+            kind TEXT NOT NULL,
+            key TEXT NOT NULL,
+            level TEXT NOT NULL,
+            PRIMARY KEY (kind, key)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS feature_stats (
+            id INTEGER PRIMARY KEY,
+            mean BLOB NOT NULL,
+            std BLOB NOT NULL,
+            count INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS analysis_failures (
+            file_hash TEXT PRIMARY KEY,
+            analysis_version INT NOT NULL,
+            error TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    add_missing_column(&conn, "tracks", "size", "INTEGER")?;
+    add_missing_column(&conn, "tracks", "mtime", "INTEGER")?;
+    let added_album_artist = add_missing_column(&conn, "tracks", "album_artist", "TEXT")?;
+    add_missing_column(&conn, "tracks", "disc_no", "INTEGER")?;
+    add_missing_column(&conn, "tracks", "compilation", "INTEGER")?;
+    if added_album_artist {
+        // Stamps that can't match make every file's tags get read again to fill these in
+        conn.execute("UPDATE tracks SET size = 0, mtime = -1", [])?;
+    }
+    add_missing_column(&conn, "weights", "loudness", "FLOAT")?;
+    add_missing_column(&conn, "weights", "peak", "FLOAT")?;
+    add_missing_column(&conn, "weights", "album_loudness", "FLOAT")?;
+    add_missing_column(&conn, "weights", "album_peak", "FLOAT")?;
+    add_missing_column(&conn, "weights", "key_confidence", "FLOAT")?;
+    add_missing_column(&conn, "weights", "analysis_version", "INT")?;
+    add_missing_column(&conn, "weights", "mfcc_var", "BLOB")?;
+    add_missing_column(&conn, "weights", "energy_var", "FLOAT")?;
+    add_missing_column(&conn, "feature_stats", "var_mean", "BLOB")?;
+    add_missing_column(&conn, "feature_stats", "var_std", "BLOB")?;
+
     Ok(conn)
 }
 
@@ -120,10 +218,13 @@ pub fn get_from_cache(conn: &Connection, filename: &str) -> Result<Option<Track>
                 file: row.get(1)?,
                 title: row.get(2)?,
                 album: row.get(3)?,
+                album_artist: row.get::<_, Option<String>>(12)?.unwrap_or_default(),
                 artists,
                 genres,
                 mood,
                 trackno: row.get(7)?,
+                disc_no: row.get::<_, Option<usize>>(13)?.unwrap_or(1),
+                compilation: row.get::<_, Option<bool>>(14)?.unwrap_or(false),
                 year: row.get(8)?,
                 len: row.get(9)?,
             })
@@ -133,6 +234,36 @@ pub fn get_from_cache(conn: &Connection, filename: &str) -> Result<Option<Track>
     Ok(result)
 }
 
+/// Path and file stamp of a track in the cache, used to tell if the file has changed since
+pub struct CachedFile {
+    pub path: String,
+    /// Size in bytes and modification time, None for tracks cached by older versions
+    pub stamp: Option<(u64, i64)>,
+}
+
+/// Loads the path and stamp of every cached track, keyed by file hash
+pub fn load_cached_files(conn: &Connection) -> Result<HashMap<String, CachedFile>> {
+    let mut stmt = conn.prepare("SELECT file_hash, file_path, size, mtime FROM tracks")?;
+    let rows = stmt.query_map([], |row| {
+        let size: Option<u64> = row.get(2)?;
+        let mtime: Option<i64> = row.get(3)?;
+        let file = CachedFile {
+            path: row.get(1)?,
+            stamp: size.zip(mtime),
+        };
+        Ok((row.get(0)?, file))
+    })?;
+    rows.collect()
+}
+
+/// Removes a track's metadata and weights from the database
+pub fn remove_from_cache(conn: &Connection, file_hash: &str) -> Result<()> {
+    conn.execute("DELETE FROM tracks WHERE file_hash = ?1", params![file_hash])?;
+    conn.execute("DELETE FROM weights WHERE file_hash = ?1", params![file_hash])?;
+    conn.execute("DELETE FROM analysis_failures WHERE file_hash = ?1", params![file_hash])?;
+    Ok(())
+}
+
 /// Turns a array of 32 bit floats into a byte array
 fn to_blob(array: &Array1<f32>) -> Vec<u8> {
     array.iter().map(|f| f.to_le_bytes()).flatten().collect()
@@ -154,7 +285,15 @@ pub fn save_track_weights(conn: &Connection, track: &str, weights: &TrackInfo) -
          energy,
          key,
          bpm,
-         zcr) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+         zcr,
+         loudness,
+         peak,
+         album_loudness,
+         album_peak,
+         key_confidence,
+         analysis_version,
+         mfcc_var,
+         energy_var) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
         params![
             file_hash,
             mfcc_blob,
@@ -163,7 +302,15 @@ pub fn save_track_weights(conn: &Connection, track: &str, weights: &TrackInfo) -
             weights.energy,
             weights.key,
             weights.bpm,
-            weights.zcr
+            weights.zcr,
+            weights.loudness.map(|l| l.integrated),
+            weights.loudness.map(|l| l.peak),
+            weights.album_loudness.map(|l| l.integrated),
+            weights.album_loudness.map(|l| l.peak),
+            weights.key_confidence,
+            weights.analysis_version,
+            to_blob(&weights.mfcc_var),
+            weights.energy_var,
         ],
     )?;
 
@@ -199,6 +346,15 @@ pub fn row_to_weights(row: &Row) -> Result<TrackInfo> {
     let key = row.get(5).unwrap_or(0);
     let bpm = row.get(6).unwrap_or(0.0);
     let zcr = row.get(7).unwrap_or(0.0);
+    let loudness = row_to_loudness(row, 8);
+    let album_loudness = row_to_loudness(row, 10);
+    // Rows analysed before keys were detected don't have one, and get analysed again as their
+    // version is out of date
+    let key_confidence = row.get::<_, Option<f32>>(12).ok().flatten().unwrap_or(0.0);
+    // Rows from before analysis was versioned all came from a single window
+    let analysis_version = row.get::<_, Option<i32>>(13).ok().flatten().unwrap_or(1);
+    let mfcc_var = row.get::<_, Option<Vec<u8>>>(14).ok().flatten().unwrap_or_default();
+    let energy_var = row.get::<_, Option<f32>>(15).ok().flatten().unwrap_or(0.0);
 
     Ok(TrackInfo {
         mfcc,
@@ -206,17 +362,173 @@ pub fn row_to_weights(row: &Row) -> Result<TrackInfo> {
         spectral,
         energy,
         key,
+        key_confidence,
         bpm,
         zcr,
+        loudness,
+        album_loudness,
+        mfcc_var: blob_to_array(mfcc_var),
+        energy_var,
+        analysis_version,
     })
 }
 
-/// Saves track metadata into the database
+/// Reads a loudness and peak pair of columns, starting at the given column
+fn row_to_loudness(row: &Row, idx: usize) -> Option<Loudness> {
+    let integrated = row.get::<_, Option<f32>>(idx).ok().flatten()?;
+    let peak = row.get::<_, Option<f32>>(idx + 1).ok().flatten()?;
+    Some(Loudness { integrated, peak })
+}
+
+/// Loads the ids of every track seen so far, keyed by file hash
+pub fn load_track_ids(conn: &Connection) -> Result<HashMap<String, TrackId>> {
+    let mut stmt = conn.prepare("SELECT file_hash, id FROM track_ids")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Gives a track a new id, which it keeps for as long as its file stays in the same place
+pub fn new_track_id(conn: &Connection, track: &str) -> Result<TrackId> {
+    conn.execute(
+        "INSERT OR IGNORE INTO track_ids (file_hash) VALUES (?1)",
+        params![hash_filename(track)],
+    )?;
+    conn.query_row(
+        "SELECT id FROM track_ids WHERE file_hash = ?1",
+        params![hash_filename(track)],
+        |row| row.get(0),
+    )
+}
+
+/// Loads the remembered playback rates of tracks, keyed by file hash
+pub fn load_track_rates(conn: &Connection) -> Result<HashMap<String, f32>> {
+    let mut stmt = conn.prepare("SELECT file_hash, rate FROM track_rates")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Loads how much each track is liked, keyed by file hash
+pub fn load_track_affinities(conn: &Connection) -> Result<HashMap<String, Affinity>> {
+    let mut stmt = conn.prepare("SELECT file_hash, affinity, updated FROM track_affinity")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get(0)?, Affinity { value: row.get(1)?, updated: row.get(2)? }))
+    })?;
+    rows.collect()
+}
+
+/// Remembers how much a track is liked
+pub fn save_track_affinity(conn: &Connection, track: &str, affinity: Affinity) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO track_affinity (file_hash, affinity, updated) VALUES (?1, ?2, ?3)",
+        params![hash_filename(track), affinity.value, affinity.updated],
+    )?;
+    Ok(())
+}
+
+/// Loads every "never play" and "play less often" entry
+pub fn load_blocklist(conn: &Connection) -> Result<Blocklist> {
+    let mut stmt = conn.prepare("SELECT kind, key, level FROM blocklist")?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+    })?;
+
+    let mut blocklist = Blocklist::default();
+    for row in rows {
+        let (kind, key, level) = row?;
+        // Entries written by a newer version are left alone rather than failing the load
+        if let (Some(kind), Some(level)) = (BlockKind::parse(&kind), BlockLevel::parse(&level)) {
+            blocklist.insert(kind, key, level);
+        }
+    }
+
+    Ok(blocklist)
+}
+
+/// Saves or clears the blocklist entry for a track file, artist or genre
+pub fn save_block(conn: &Connection, kind: BlockKind, name: &str, level: Option<BlockLevel>) -> Result<()> {
+    let key = Blocklist::key(kind, name);
+    match level {
+        Some(level) => conn.execute(
+            "INSERT OR REPLACE INTO blocklist (kind, key, level) VALUES (?1, ?2, ?3)",
+            params![kind.as_str(), key, level.as_str()],
+        )?,
+        None => conn.execute(
+            "DELETE FROM blocklist WHERE kind = ?1 AND key = ?2",
+            params![kind.as_str(), key],
+        )?,
+    };
+    Ok(())
+}
+
+/// Saves the library wide feature stats, replacing the old ones
+pub fn save_feature_stats(conn: &Connection, stats: &FeatureStats) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO feature_stats (id, mean, std, var_mean, var_std, count)
+        VALUES (0, ?1, ?2, ?3, ?4, ?5)",
+        params![
+            to_blob(&stats.mean),
+            to_blob(&stats.std),
+            to_blob(&stats.var_mean),
+            to_blob(&stats.var_std),
+            stats.count
+        ],
+    )?;
+    Ok(())
+}
+
+/// Loads the library wide feature stats, if they have been worked out before
+pub fn load_feature_stats(conn: &Connection) -> Result<Option<FeatureStats>> {
+    conn.query_row("SELECT mean, std, var_mean, var_std, count FROM feature_stats WHERE id = 0", [], |row| {
+        Ok(FeatureStats {
+            mean: blob_to_array(row.get(0)?),
+            std: blob_to_array(row.get(1)?),
+            var_mean: blob_to_array(row.get::<_, Option<Vec<u8>>>(2)?.unwrap_or_default()),
+            var_std: blob_to_array(row.get::<_, Option<Vec<u8>>>(3)?.unwrap_or_default()),
+            count: row.get(4)?,
+        })
+    })
+    .optional()
+}
+
+/// Loads the files that failed to be analysed, as the analysis version and error keyed by
+/// file hash
+pub fn load_analysis_failures(conn: &Connection) -> Result<HashMap<String, (i32, String)>> {
+    let mut stmt = conn.prepare("SELECT file_hash, analysis_version, error FROM analysis_failures")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?;
+    rows.collect()
+}
+
+/// Remembers that a file could not be analysed, so it isn't tried again on every launch
+pub fn save_analysis_failure(conn: &Connection, track: &str, version: i32, error: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO analysis_failures (file_hash, analysis_version, error) VALUES (?1, ?2, ?3)",
+        params![hash_filename(track), version, error],
+    )?;
+    Ok(())
+}
+
+/// Forgets every failed analysis, so those files get tried again
+pub fn clear_analysis_failures(conn: &Connection) -> Result<()> {
+    conn.execute("DELETE FROM analysis_failures", [])?;
+    Ok(())
+}
+
+/// Remembers the playback rate of a track
+pub fn save_track_rate(conn: &Connection, track: &str, rate: f32) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO track_rates (file_hash, rate) VALUES (?1, ?2)",
+        params![hash_filename(track), rate],
+    )?;
+    Ok(())
+}
+
+/// Saves track metadata into the database, along with the file's current stamp
 pub fn save_to_cache(conn: &Connection, item: &Track) -> Result<()> {
     let file_hash = hash_filename(&item.file);
+    let (size, mtime) = file_stamp(&item.file).unzip();
     conn.execute(
-        "INSERT OR REPLACE INTO tracks (file_hash, file_path, title, album, artists, genres, mood, trackno, year, len) VALUES (
-            ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        "INSERT OR REPLACE INTO tracks (file_hash, file_path, title, album, artists, genres, mood, trackno, year, len, size, mtime, album_artist, disc_no, compilation) VALUES (
+            ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
         params![
             file_hash,
             item.file,
@@ -227,7 +539,12 @@ pub fn save_to_cache(conn: &Connection, item: &Track) -> Result<()> {
             item.mood,
             item.trackno,
             item.year,
-            item.len
+            item.len,
+            size,
+            mtime,
+            item.album_artist,
+            item.disc_no,
+            item.compilation,
         ],
     )?;
     Ok(())
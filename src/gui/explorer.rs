@@ -13,10 +13,11 @@ use dioxus::stores::SyncStore;
 pub use genres::GenreList;
 pub use search::{SearchView, TracksSearch};
 
+use super::trackoptions::BlockOptions;
 use super::{View, TRACKOPTION, VIEW};
+use crate::app::blocklist::BlockKind;
 use crate::app::controller::MusicControllerStoreExt;
-use crate::app::utils::similar;
-use crate::app::MusicController;
+use crate::app::{MusicController, TrackId};
 use dioxus::document::eval;
 use dioxus::prelude::*;
 use log::info;
@@ -67,7 +68,7 @@ pub fn TracksView(controller: SyncStore<MusicController>, viewtype: View) -> Ele
 
     // Memo to hold the view name for any given viewtype
     let name = use_memo(move || match viewtype() {
-        View::Albums => VIEW.read().album.clone().unwrap(),
+        View::Albums => VIEW.read().album.clone().unwrap().title,
         View::Artists => VIEW.read().artist.clone().unwrap(),
         View::Genres => VIEW.read().genre.clone().unwrap(),
         View::Playlists => controller.playlists().get(VIEW.read().playlist.unwrap()).unwrap().read()
@@ -84,19 +85,15 @@ pub fn TracksView(controller: SyncStore<MusicController>, viewtype: View) -> Ele
                 .clone();
         }
 
-        let mut tracks: Vec<usize> = controller.all_tracks().iter().enumerate().filter(|(_, t)| match viewtype() {
-            View::Albums => similar(&t.read().album, &name.read()),
+        let mut tracks: Vec<TrackId> = controller.all_tracks().iter().filter(|(_, t)| match viewtype() {
+            View::Albums => t.read().album_id().similar(VIEW.read().album.as_ref().unwrap()),
             View::Artists => t.read().has_artist(&name.read()),
             View::Genres => t.read().has_genre(&name.read()),
             _ => unreachable!(),
-        }).map(|(idx, _)| idx).collect();
+        }).map(|(id, _)| id).collect();
 
         if viewtype() == View::Albums {
-            tracks.sort_by(|a, b| {
-                controller.all_tracks().get(*a).unwrap().read()
-                    .trackno
-                    .cmp(&controller.all_tracks().get(*b).unwrap().read().trackno)
-            });
+            tracks.sort_by_key(|id| controller.all_tracks().get(*id).unwrap().read().album_position());
         }
 
         tracks
@@ -204,7 +201,7 @@ pub fn TracksView(controller: SyncStore<MusicController>, viewtype: View) -> Ele
                     style: "top: {i * ROW_HEIGHT}px; position: absolute;",
                     onclick: move |_| {
                         match viewtype() {
-                            View::Albums => controller.write().play_album_at(name(), tracks.read()[i]),
+                            View::Albums => controller.write().play_album_at(VIEW.read().album.clone().unwrap(), tracks.read()[i]),
                             View::Artists => controller.write().play_artist_at(name(), tracks.read()[i]),
                             View::Genres => controller.write().play_genre_at(name(), tracks.read()[i]),
                             View::Playlists => {
@@ -304,7 +301,7 @@ pub fn ExplorerOptions(
     adding_to_queue: Signal<bool>,
     name: Memo<String>,
     viewtype: Signal<View>,
-    tracks: Memo<Vec<usize>>,
+    tracks: Memo<Vec<TrackId>>,
 ) -> Element {
     rsx! {
         div {
@@ -317,7 +314,7 @@ pub fn ExplorerOptions(
                 button {
                     onclick: move |_| {
                         match viewtype() {
-                            View::Albums => controller.write().play_album_at(name(), tracks.read()[0]),
+                            View::Albums => controller.write().play_album_at(VIEW.read().album.clone().unwrap(), tracks.read()[0]),
                             View::Artists => controller.write().play_artist_at(name(), tracks.read()[0]),
                             View::Genres => controller.write().play_genre_at(name(), tracks.read()[0]),
                             View::Playlists => {
@@ -340,7 +337,7 @@ pub fn ExplorerOptions(
                         let random_index = rand::thread_rng().gen_range(0..tracks.read().len());
                         let track = tracks.read()[random_index];
                         match viewtype() {
-                            View::Albums => controller.write().play_album_at(name(), track),
+                            View::Albums => controller.write().play_album_at(VIEW.read().album.clone().unwrap(), track),
                             View::Artists => controller.write().play_artist_at(name(), track),
                             View::Genres => controller.write().play_genre_at(name(), track),
                             View::Playlists => {
@@ -358,6 +355,15 @@ pub fn ExplorerOptions(
                     "Shuffle"
                 }
 
+                button {
+                    onclick: move |_| {
+                        controller.write().start_radio_from(tracks(), name());
+                        VIEW.write().open(View::Song);
+                    },
+                    img { src: RADIO_ICON }
+                    "Start radio"
+                }
+
                 button { onclick: move |_| adding_to_playlist.set(true),
                     img { src: PLAYLIST_ADD_ICON }
                     "Add to a playlist"
@@ -367,6 +373,18 @@ pub fn ExplorerOptions(
                     img { src: QUEUE_ICON }
                     "Add to a queue"
                 }
+
+                match viewtype() {
+                    View::Artists => rsx! {
+                        hr {}
+                        BlockOptions { controller, kind: BlockKind::Artist, name: name() }
+                    },
+                    View::Genres => rsx! {
+                        hr {}
+                        BlockOptions { controller, kind: BlockKind::Genre, name: name() }
+                    },
+                    _ => rsx! {},
+                }
             }
         }
     }
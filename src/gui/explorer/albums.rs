@@ -1,7 +1,7 @@
 use super::TracksView;
 use crate::app::utils::strip_unnessecary;
 use crate::{
-    app::{MusicController, utils::similar},
+    app::{track::AlbumId, MusicController, TrackId},
     gui::{icons::*, View, VIEW, get_album_artwork},
 };
 use dioxus::document::eval;
@@ -20,7 +20,7 @@ pub fn AlbumsList(controller: SyncStore<MusicController>) -> Element {
             .albums
             .clone()
             .into_iter()
-            .collect::<Vec<(String, (usize, usize))>>();
+            .collect::<Vec<(AlbumId, (usize, TrackId))>>();
         albums_unsorted.sort_by(|(_, a), (_, b)| b.0.cmp(&a.0));
         albums.set(albums_unsorted);
     });
@@ -130,8 +130,8 @@ pub fn AlbumsList(controller: SyncStore<MusicController>) -> Element {
                     for i in start_index()..end_index() {
                         div {
                             class: "albumitem",
-                            id: "album-{albums.read()[i].0}",
-                            key: "album-{i}-{albums.read()[i].0}",
+                            id: "album-{albums.read()[i].0.artist}-{albums.read()[i].0}",
+                            key: "album-{i}-{albums.read()[i].0.artist}-{albums.read()[i].0}",
                             onclick: move |_| set_album(albums.read()[i].0.clone()),
 
                             img {
@@ -140,12 +140,12 @@ pub fn AlbumsList(controller: SyncStore<MusicController>) -> Element {
                             }
 
                             div { class: "albuminfo",
-                                if albums.read()[i].0.is_empty() {
+                                if albums.read()[i].0.title.is_empty() {
                                     span { "Unknown Album" }
                                 } else {
                                     span { "{albums.read()[i].0}" }
                                 }
-                                small { "{albums.read()[i].1.0} songs" }
+                                small { "{albums.read()[i].0.artist} · {albums.read()[i].1.0} songs" }
                             }
                         }
                     }
@@ -167,7 +167,7 @@ pub fn AlbumsList(controller: SyncStore<MusicController>) -> Element {
 pub fn AlbumsSearch(
     controller: SyncStore<MusicController>,
     is_searching: Signal<bool>,
-    albums: Signal<Vec<(String, (usize, usize))>>,
+    albums: Signal<Vec<(AlbumId, (usize, TrackId))>>,
     row_height: Signal<usize>,
     items_per_row: Signal<usize>
 ) -> Element {
@@ -186,9 +186,9 @@ pub fn AlbumsSearch(
                 .albums
                 .iter()
                 .map(|a| a.0)
-                .filter(|t| strip_unnessecary(&t).starts_with(&search))
+                .filter(|album| strip_unnessecary(&album.title).starts_with(&search))
                 .cloned()
-                .collect::<Vec<String>>()
+                .collect::<Vec<AlbumId>>()
         }
     });
 
@@ -215,7 +215,7 @@ pub fn AlbumsSearch(
                             onclick: {
                                 let album = album.clone();
                                 move |_| {
-                                    let index = albums.read().iter().position(|a| similar(&a.0, &album)).unwrap_or(0);
+                                    let index = albums.read().iter().position(|a| a.0.similar(&album)).unwrap_or(0);
                                     let row = index / items_per_row();
                                     let scroll_amount = row * row_height();
                                     document::eval(
@@ -229,6 +229,7 @@ pub fn AlbumsSearch(
 
                             img { src: "/trackimage/{get_album_artwork(controller, album.clone())}?origin=albums", loading: "lazy" }
                             span { "{album}" }
+                            small { "{album.artist}" }
                         }
                     }
                 }
@@ -1,6 +1,6 @@
 use super::TracksSearch;
 use super::{View, VIEW};
-use crate::app::MusicController;
+use crate::app::{MusicController, TrackId};
 use crate::app::controller::MusicControllerStoreExt;
 use crate::gui::icons::*;
 use dioxus::document::eval;
@@ -20,12 +20,12 @@ fn display_time(total: u64) -> String {
 pub fn AllTracks(controller: SyncStore<MusicController>) -> Element {
     let mut is_searching = use_signal(|| false);
     
-    let tracks = use_memo(move || (0..controller.all_tracks().read().len()).collect::<Vec<usize>>());
+    let tracks = use_memo(move || controller.all_tracks().read().keys().copied().collect::<Vec<TrackId>>());
     let total_time = use_memo(move || {
         controller
             .read()
             .all_tracks
-            .iter()
+            .values()
             .map(|t| t.len)
             .sum::<f64>() as u64
     });
@@ -98,21 +98,21 @@ pub fn AllTracks(controller: SyncStore<MusicController>) -> Element {
 
                 div { min_height: "{(tracks.read().len()) * ROW_HEIGHT}px" }
 
-                for i in start_index()..end_index() {
+                for (i, track) in (start_index()..end_index()).map(|i| (i, tracks.read()[i])) {
                     div {
                         class: "trackitem",
                         id: "alltracks-trackitem-{i}",
                         style: "top: {i * ROW_HEIGHT}px; position: absolute;",
                         onclick: move |_| {
-                            controller.write().add_all_queue(i);
+                            controller.write().add_all_queue(track);
                             VIEW.write().current = View::Song;
                         },
                         img {
                             class: "trackitemicon",
                             loading: "onvisible",
-                            src: if VIEW.read().current == View::AllTracks { "/trackimage/{i}?origin=alltracks" },
+                            src: if VIEW.read().current == View::AllTracks { "/trackimage/{track}?origin=alltracks" },
                         }
-                        span { "{controller.all_tracks().get(i).unwrap().read().title}" }
+                        span { "{controller.all_tracks().get(track).unwrap().read().title}" }
                         div { flex_grow: 1 }
                         img {
                             class: "trackbutton",
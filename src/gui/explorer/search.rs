@@ -1,6 +1,6 @@
 use crate::app::controller::MusicControllerStoreExt;
 use crate::app::utils::strip_unnessecary;
-use crate::app::MusicController;
+use crate::app::{track::AlbumId, MusicController, TrackId};
 use crate::gui::{icons::*, View, VIEW, get_album_artwork};
 use dioxus::prelude::*;
 use dioxus::stores::SyncStore;
@@ -14,12 +14,13 @@ pub fn SearchView(controller: SyncStore<MusicController>) -> Element {
         if clean_search().len() < 2 {
             Vec::new()
         } else {
-            (0..controller.all_tracks().read().len())
-                .filter(|t| {
-                    strip_unnessecary(&controller.all_tracks().read().get(*t).unwrap().title)
-                        .starts_with(&clean_search())
-                })
-                .collect::<Vec<usize>>()
+            controller
+                .all_tracks()
+                .read()
+                .iter()
+                .filter(|(_, t)| strip_unnessecary(&t.title).starts_with(&clean_search()))
+                .map(|(id, _)| *id)
+                .collect::<Vec<TrackId>>()
         }
     });
 
@@ -44,9 +45,9 @@ pub fn SearchView(controller: SyncStore<MusicController>) -> Element {
                 .albums()()
                 .iter()
                 .map(|a| a.0)
-                .filter(|t| strip_unnessecary(&t).starts_with(&clean_search()))
+                .filter(|album| strip_unnessecary(&album.title).starts_with(&clean_search()))
                 .cloned()
-                .collect::<Vec<String>>()
+                .collect::<Vec<AlbumId>>()
         }
     });
 
@@ -147,7 +148,7 @@ pub fn SearchView(controller: SyncStore<MusicController>) -> Element {
 #[component]
 pub fn TracksSearch(
     controller: SyncStore<MusicController>,
-    tracks: Memo<Vec<usize>>,
+    tracks: Memo<Vec<TrackId>>,
     is_searching: Signal<bool>,
     id_prefix: String,
 ) -> Element {
@@ -174,7 +175,7 @@ pub fn TracksSearch(
                         strip_unnessecary(&controller.all_tracks().get(**t).unwrap().read().title).starts_with(&search)
                     })
                     .cloned()
-                    .collect::<Vec<usize>>()
+                    .collect::<Vec<TrackId>>()
             })
         }
     });
@@ -206,7 +207,8 @@ pub fn TracksSearch(
                         div {
                             class: "trackitem",
                             onclick: move |_| {
-                                let scroll_amount = track * row_size();
+                                let row = tracks.read().iter().position(|t| *t == track).unwrap_or(0);
+                                let scroll_amount = row * row_size();
                                 document::eval(
                                     &format!(
                                         "document.getElementById('{id_prefix}').scrollTop = {};",
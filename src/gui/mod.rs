@@ -12,31 +12,33 @@ pub mod trackview;
 
 #[cfg(target_os = "android")]
 pub mod media;
-use crate::app::track::TrackInfo;
+use crate::app::track::{AlbumId, TrackInfo};
 use crate::app::utils::strip_unnessecary;
-use crate::database::{hash_filename, init_db, row_to_weights};
+use crate::database::{cached_weight, hash_filename, init_db, row_to_weights};
 #[cfg(target_os = "android")]
 use crate::gui::media::{MediaMsg, MEDIA_MSG_TX};
 #[cfg(target_os = "android")]
 use crate::app::track::get_track_image;
-use crate::analysis::generate_track_info;
-use crate::database::save_track_weights;
+use crate::analysis::{analysis_version, update_track_info};
+use crate::app::analyser::{run_jobs, ANALYSIS_CONTROL};
+use crate::database::{load_analysis_failures, save_analysis_failure, save_track_weights};
 
 use dioxus::prelude::*;
 use dioxus::stores::SyncStore;
-use log::info;
+use log::{error, info};
 use once_cell::sync::Lazy;
 use rusqlite::{Rows, params};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Mutex;
 use std::thread::JoinHandle;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use crate::app::audio::AudioPlayer;
+use crate::app::audio::{AudioPlayer, STREAM_RETRY_INTERVAL};
 use crate::app::controller::{MUSIC_PLAYER_ACTIONS, MusicControllerStoreExt, MusicMsg};
-use crate::app::{MusicController, load_tracks, Track};
+use crate::app::{MusicController, load_tracks, ScanReport, Track, TrackId};
+use crate::app::watcher::{apply_changes, LibraryWatcher};
 
 pub use confirm::Confirmation;
 pub use explorer::{AlbumsList, AllTracks, ArtistList, GenreList, SearchView};
@@ -52,26 +54,29 @@ pub use library::LibraryManagement;
 /// Current view of the application, eg TrackView, Queue, Settings, etc
 pub const VIEW: GlobalSignal<ViewData> = Signal::global(|| ViewData::new());
 /// If a track options menu is currently open (a Some value containing the track ID) or not (None)
-pub const TRACKOPTION: GlobalSignal<Option<usize>> = Signal::global(|| None);
+pub const TRACKOPTION: GlobalSignal<Option<TrackId>> = Signal::global(|| None);
 /// To be set when a song is to be added to the playlist by a user
-pub const ADD_TO_PLAYLIST: GlobalSignal<Option<usize>> = Signal::global(|| None);
+pub const ADD_TO_PLAYLIST: GlobalSignal<Option<TrackId>> = Signal::global(|| None);
 
 pub const MOBILE: GlobalSignal<bool> = Signal::global(|| cfg!(target_os = "android"));
 
 /// Whether a tag edit is being made or not 
-pub const EDITING_TAG: GlobalSignal<Option<(usize, Track)>> = Signal::global(|| None);
+pub const EDITING_TAG: GlobalSignal<Option<(TrackId, Track)>> = Signal::global(|| None);
 
 /// Global reference to the dioxus SyncSignal holding the main MusicController
 /// This allows the controller to be used in threads, and from outside a component
 pub static CONTROLLER: Lazy<Mutex<Option<SyncStore<MusicController>>>> =
     Lazy::new(|| Mutex::new(None));
 
-/// Returns a track id of the first track in an album for a given album name
+/// Returns a track id of the first track in a given album
 /// The cover loading code works from track IDs so this works
-pub fn get_album_artwork(controller: SyncStore<MusicController>, album: String) -> usize {
+pub fn get_album_artwork(controller: SyncStore<MusicController>, album: AlbumId) -> TrackId {
     controller.albums().get(album).unwrap().read().1
 }
 
+/// How often the playback state is saved while the app runs
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
 /// Starts a thread running all background tasks for the MusicController
 /// To avoid issues on Android where the app freezes in the background, this allows the app to
 /// run from a foreground service initiated runtime
@@ -80,12 +85,23 @@ pub fn start_controller_thread() {
         info!("Created controller thread with thread id {:?}", std::thread::current().id());
         let res = std::panic::catch_unwind(|| {
             let mut track_playing = false;
-            let mut audio_player = AudioPlayer::new();
+            let mut last_saved = Instant::now();
 
             #[allow(unused_mut)]
             let (music_tx, mut rx) = channel();
             *MUSIC_PLAYER_ACTIONS.lock().unwrap() = Some(music_tx);
 
+            // Messages wait in the channel until there's a device to play them on
+            let mut audio_player = loop {
+                match AudioPlayer::new() {
+                    Ok(audio_player) => break audio_player,
+                    Err(err) => {
+                        error!("Could not open an output stream, retrying in {STREAM_RETRY_INTERVAL:?}: {err:?}");
+                        std::thread::sleep(STREAM_RETRY_INTERVAL);
+                    }
+                }
+            };
+
             #[allow(unused_mut)]
             #[cfg(target_os = "android")]
             let (tx, mut media_rx) = channel();
@@ -129,19 +145,39 @@ pub fn start_controller_thread() {
                             MusicMsg::Pause => audio_player.pause(),
                             MusicMsg::Play => audio_player.play(),
                             MusicMsg::Toggle => audio_player.toggle_playing(),
-                            MusicMsg::PlayTrack(file) => {
+                            MusicMsg::PlayTrack(track) => {
                                 info!("locking controller");
                                 if let Some(ctrl) = *CONTROLLER.lock().unwrap() {
                                     let mut controller = ctrl.clone();
-                                    controller.write().song_length = audio_player.play_track(&file);
+                                    match audio_player.play_track(&track) {
+                                        Ok(len) => controller.write().song_length = len,
+                                        // The sink is left empty, so the track gets skipped once
+                                        // it's seen as finished below
+                                        Err(err) => error!("Could not play track {:?}: {err:?}", track.file),
+                                    }
                                     //controller.write().progress_secs = 0.0;
 
                                     track_playing = true;
                                     info!("played track from thread");
                                 }
                             }
+                            MusicMsg::PrepareNext(track, crossfade) => {
+                                audio_player.prepare_next(&track, crossfade)
+                            }
+                            MusicMsg::SetCrossfade(seconds) => audio_player.set_crossfade(seconds),
+                            MusicMsg::SetEqualizer(bands) => audio_player.set_equalizer(bands),
+                            MusicMsg::SetRate(rate) => audio_player.set_rate(rate),
+                            MusicMsg::SetPreservePitch(preserve_pitch) => {
+                                audio_player.set_preserve_pitch(preserve_pitch)
+                            }
+                            MusicMsg::SetOutputDevice(device) => {
+                                audio_player.set_output_device(device)
+                            }
                             MusicMsg::SetVolume(volume) => audio_player.set_volume(volume),
                             MusicMsg::SetPos(pos) => audio_player.set_pos(pos),
+                            MusicMsg::RescanLibrary => {
+                                rescan_tracks();
+                            }
                             MusicMsg::UpdateInfo => {
                                 info!("locking controller");
                                 if let Some(ctrl) = *CONTROLLER.lock().unwrap() {
@@ -192,11 +228,32 @@ pub fn start_controller_thread() {
                     _ => {}
                 }
 
+                // Moves the controller onto a prepared track once it starts playing
+                if let Some(file) = audio_player.update() {
+                    if let Some(ctrl) = *CONTROLLER.lock().unwrap() {
+                        let mut controller = ctrl.clone();
+                        controller.write().song_length = audio_player.song_length();
+                        controller.write().track_advanced(&file);
+                    }
+                }
+
+                // Periodically save the queues and position, in case the app gets closed
+                if last_saved.elapsed() >= STATE_SAVE_INTERVAL {
+                    last_saved = Instant::now();
+                    if let Some(ctrl) = *CONTROLLER.lock().unwrap() {
+                        let mut controller = ctrl.clone();
+                        if audio_player.playing() {
+                            controller.write().progress_secs = audio_player.progress_secs();
+                        }
+                        controller.read().save_state();
+                    }
+                }
+
                 // Manage track skipping
                 if audio_player.track_ended() && track_playing {
                     if let Some(ctrl) = *CONTROLLER.lock().unwrap() {
                         let mut controller = ctrl.clone();
-                        controller.write().skip();
+                        controller.write().track_finished();
                         track_playing = false;
                     }
                 }
@@ -213,16 +270,16 @@ pub fn init_tracks() -> JoinHandle<()> {
     std::thread::spawn(|| {
         let res = std::panic::catch_unwind(|| {
             let started = Instant::now();
-            let mut tracks = Vec::new();
+            let mut tracks = BTreeMap::new();
 
             if let Some(ctrl) = *CONTROLLER.lock().unwrap() {
                 let mut controller = ctrl.clone();
-                let maybe_tracks = load_tracks(&controller.settings().read().directory);
+                let maybe_tracks = load_tracks(&controller.settings().read().libraries);
 
-                if let Ok(t) = maybe_tracks {
+                if let Ok((t, report)) = maybe_tracks {
                     tracks = t.clone();
-                    let dir = controller.settings().read().directory.clone();
-                    controller.set(MusicController::new(t, dir));
+                    controller.set(MusicController::new(t));
+                    controller.write().last_scan = Some(report);
                     info!("Loaded all tracks in {:?}", started.elapsed());
                 } else {
                     info!("{:?}", maybe_tracks);
@@ -231,65 +288,220 @@ pub fn init_tracks() -> JoinHandle<()> {
             
             info!("taken {:?}", started.elapsed());
 
-            let cache = init_db().unwrap();
-            let mut stmt = cache.prepare("SELECT * FROM weights").unwrap();
-            let mut result: Rows = stmt.query(params!()).unwrap();
-            let mut weights: HashMap<String, TrackInfo> = HashMap::new();
-            while let Ok(Some(row)) = result.next() {
-                let hash = row.get(0).unwrap();
-                match row_to_weights(&row) {
-                    Ok(row) => { weights.insert(hash, row); },
-                    Err(err) => error!("Error retrieving data: {err}"),
-                } 
-            }
-                    info!("taken {:?}", started.elapsed());
-
-            let mut len = 0;
-            if let Some(ctrl) = *CONTROLLER.lock().unwrap() {
-                let controller = ctrl.clone();
-                len = controller.all_tracks().read().len();
-            }
+            start_library_watcher();
+            load_track_info(&tracks, started, true);
+        });
 
-            let mut buffer = Vec::new();
+        info!("init tracks result: {res:?}");
+    })
+}
 
-            info!("loading info {:?}", started.elapsed());
+/// Watches the library folders, so that files added or removed while the app is open are
+/// picked up without a rescan
+pub fn start_library_watcher() {
+    std::thread::spawn(|| {
+        let res = std::panic::catch_unwind(|| {
+            let Some(ctrl) = *CONTROLLER.lock().unwrap() else {
+                return;
+            };
+            let mut controller = ctrl.clone();
+            let mut watcher: Option<LibraryWatcher> = None;
 
+            loop {
+                // Follow the library folders when they get changed in the settings
+                let roots = controller.settings().read().libraries.clone();
+                if watcher.as_ref().map(|w| &w.roots) != Some(&roots) {
+                    watcher = match LibraryWatcher::new(&roots) {
+                        Ok(watcher) => Some(watcher),
+                        Err(err) => {
+                            error!("Could not watch library: {err}");
+                            None
+                        }
+                    };
+                }
 
-            for i in 0..len {
-                let track = tracks[i].clone();
-                let file_hash = hash_filename(&track.file);
+                let Some(paths) = watcher.as_mut().map(|w| w.next_changes()) else {
+                    std::thread::sleep(Duration::from_secs(5));
+                    continue;
+                };
 
-                let track_info = if weights.contains_key(&file_hash) {
-                    weights[&file_hash].clone()
-                } else {
-                    let track_info = generate_track_info(&track);
-                    save_track_weights(&cache, &track.file, &track_info).unwrap();
-                    track_info
+                let Some(paths) = paths else {
+                    watcher = None;
+                    continue;
                 };
 
-                buffer.push(track_info);
+                if paths.is_empty() {
+                    continue;
+                }
+
+                let all_tracks = controller.all_tracks().read().clone();
+                match apply_changes(&all_tracks, &paths, &roots) {
+                    Ok((library, updated, report)) => {
+                        if report == ScanReport::default() {
+                            continue;
+                        }
 
-                if i % 100 == 0 {
-                    info!("{i}/{len} analyzed");
+                        info!("Library changed: {report}");
+                        controller.write().set_library(library, report);
+                        load_track_info(&updated, Instant::now(), false);
+                    }
+                    Err(err) => error!("Could not update library: {err:?}"),
                 }
             }
+        });
 
-            match CONTROLLER.lock() {
-                Ok(res) => {
-                    if let Some(ctrl) = *res {
-                        let mut controller = ctrl.clone();
-                        controller.write().track_info = buffer;
-                    }
-                },
-                Err(res) => info!("{res:?}"),
+        info!("library watcher result: {res:?}");
+    });
+}
+
+/// Scans the library folders again, keeping playback and queues going
+pub fn rescan_tracks() -> JoinHandle<()> {
+    std::thread::spawn(|| {
+        let res = std::panic::catch_unwind(|| {
+            let started = Instant::now();
+            let Some(ctrl) = *CONTROLLER.lock().unwrap() else {
+                return;
+            };
+            let mut controller = ctrl.clone();
+            let roots = controller.settings().read().libraries.clone();
+
+            match load_tracks(&roots) {
+                Ok((tracks, report)) => {
+                    controller.write().set_library(tracks.clone(), report);
+                    info!("Rescanned library in {:?}: {report}", started.elapsed());
+                    load_track_info(&tracks, started, true);
+                }
+                Err(err) => error!("Could not rescan library: {err:?}"),
             }
-            info!("taken {:?}", started.elapsed());
         });
 
-        info!("init tracks result: {res:?}");
+        info!("rescan tracks result: {res:?}");
     })
 }
 
+/// Loads the features of the given tracks from the database, analysing any that are missing
+/// on a pool of threads and handing each one to the controller as soon as it's ready
+/// Loading the whole library cancels any analysis that is already running
+fn load_track_info(tracks: &BTreeMap<TrackId, Track>, started: Instant, whole_library: bool) {
+    let Some(ctrl) = *CONTROLLER.lock().unwrap() else {
+        return;
+    };
+    let mut controller = ctrl.clone();
+    if whole_library {
+        ANALYSIS_CONTROL.cancel();
+    }
+
+    let cache = init_db().unwrap();
+    let mut weights: HashMap<String, TrackInfo> = HashMap::new();
+    if whole_library {
+        let mut stmt = cache.prepare("SELECT * FROM weights").unwrap();
+        let mut result: Rows = stmt.query(params!()).unwrap();
+        while let Ok(Some(row)) = result.next() {
+            let hash = row.get(0).unwrap();
+            match row_to_weights(&row) {
+                Ok(row) => { weights.insert(hash, row); },
+                Err(err) => error!("Error retrieving data: {err}"),
+            } 
+        }
+    } else {
+        // Only a few files change at once, so just their rows are looked up
+        for track in tracks.values() {
+            match cached_weight(&cache, &track.file) {
+                Ok(row) => { weights.insert(hash_filename(&track.file), row); },
+                Err(rusqlite::Error::QueryReturnedNoRows) => {}
+                Err(err) => error!("Error retrieving data: {err}"),
+            }
+        }
+    }
+    info!("taken {:?}", started.elapsed());
+
+    let failures = load_analysis_failures(&cache).unwrap_or_default();
+    let settings = controller.settings().read().analysis.clone();
+    let version = analysis_version(settings.mode);
+
+    let mut ready = BTreeMap::new();
+    let mut failed = Vec::new();
+    let mut jobs = Vec::new();
+    for (id, track) in tracks {
+        let file_hash = hash_filename(&track.file);
+        match weights.remove(&file_hash) {
+            // Loudness was measured alongside the features, so a track at the current version
+            // without one is a track it couldn't be measured for
+            Some(track_info) if track_info.analysis_version == version => {
+                ready.insert(*id, track_info);
+            }
+            cached => match failures.get(&file_hash) {
+                // Files that failed before are left alone until the user retries them
+                Some((failed_version, error)) if *failed_version == version => {
+                    failed.push((track.file.clone(), error.clone()));
+                }
+                _ => jobs.push((*id, track, cached)),
+            },
+        }
+    }
+
+    {
+        let mut controller = controller.write();
+        controller.add_track_info(ready);
+        // Stats from the tracks that are already done keep the radio usable while the rest
+        // get analysed
+        if whole_library {
+            controller.update_feature_stats();
+        }
+        controller.analysis.total = controller.all_tracks.len();
+        controller.analysis.analysed = controller.track_info.len();
+        if whole_library {
+            controller.analysis.failed = failed;
+        } else {
+            controller.analysis.failed.extend(failed);
+        }
+        controller.analysis.running = !jobs.is_empty();
+        controller.analysis.paused = ANALYSIS_CONTROL.paused();
+        // Radio queues can only pick ahead once track features are loaded
+        controller.prepare_next();
+    }
+    info!("loaded info in {:?}, {} tracks left to analyse", started.elapsed(), jobs.len());
+
+    let finished = run_jobs(
+        &jobs,
+        settings.threads(),
+        |(_, track, cached)| update_track_info(track, cached.clone(), settings.mode),
+        |(id, track, _), result| match result {
+            Ok(track_info) => {
+                if let Err(err) = save_track_weights(&cache, &track.file, &track_info) {
+                    error!("Could not save features of {}: {err}", track.file);
+                }
+
+                let mut controller = controller.write();
+                let first = controller.track_info.is_empty();
+                controller.add_track_info([(*id, track_info)]);
+                controller.analysis.analysed = controller.track_info.len();
+                controller.analysis.total = controller.all_tracks.len();
+                if first {
+                    controller.prepare_next();
+                }
+            }
+            Err(err) => {
+                error!("Could not analyse {}: {err:?}", track.file);
+                if let Err(err) = save_analysis_failure(&cache, &track.file, version, &err.to_string()) {
+                    error!("Could not save analysis failure: {err}");
+                }
+                controller.write().analysis.failed.push((track.file.clone(), err.to_string()));
+            }
+        },
+    );
+
+    if finished {
+        let mut controller = controller.write();
+        // Another run may still be going for tracks that were added since this one started
+        let status = &controller.analysis;
+        let remaining = status.analysed + status.failed.len() < status.total;
+        controller.analysis.running = remaining;
+        controller.update_feature_stats();
+    }
+    info!("taken {:?}, finished: {finished}", started.elapsed());
+}
+
 /// Enum holding view state
 #[derive(Debug, PartialEq, Clone)]
 pub enum View {
@@ -339,7 +551,7 @@ impl View {
 /// Holds the current view, and information about if track views are open
 pub struct ViewData {
     pub current: View,
-    pub album: Option<String>,
+    pub album: Option<AlbumId>,
     pub artist: Option<String>,
     pub playlist: Option<usize>,
     pub autoplaylist: Option<usize>,
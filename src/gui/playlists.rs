@@ -83,7 +83,7 @@ pub fn PlaylistsView(controller: SyncStore<MusicController>) -> Element {
 
                         button {
                             onclick: move |_| {
-                                let dir = controller.settings().read().directory.clone();
+                                let dir = controller.settings().read().main_directory();
                                 controller.playlists().write().push(Playlist::new(playlist_name(), dir));
                                 *CREATING_PLAYLIST.write() = false;
                                 playlist_name.set(String::new());
@@ -147,7 +147,6 @@ pub fn PlaylistsView(controller: SyncStore<MusicController>) -> Element {
 
                         button {
                             onclick: move |_| {
-                                let _dir = controller.write().settings.directory.clone();
                                 controller.write().autoplaylists.push(AutoPlaylist::new(autoplaylist_name()));
                                 *CREATING_AUTOPLAYLIST.write() = false;
                                 autoplaylist_name.set(String::new());
@@ -291,6 +290,16 @@ pub fn PlaylistOptions(
         div { class: "optionsbg", onclick: move |_| playlist_options.set(None),
             div { class: "optionbox", style: "--width: 300px; --height: 50px;",
                 h3 { "{controller.playlists().get(playlist_options().unwrap()).unwrap().read().name}" }
+                button {
+                    onclick: move |_| {
+                        let playlist = controller.playlists().get(playlist_options().unwrap()).unwrap()();
+                        controller.write().start_radio_from(playlist.tracks, playlist.name);
+                        VIEW.write().open(View::Song);
+                    },
+                    img { src: RADIO_ICON }
+                    "Start radio"
+                }
+
                 button { onclick: move |_| renaming_playlist.set(playlist_options()),
                     img { src: EDIT_ICON }
                     "Rename playlist"
@@ -1,6 +1,6 @@
 use dioxus::prelude::*;
 use dioxus::stores::SyncStore;
-use crate::app::MusicController;
+use crate::app::{MusicController, TrackId};
 use crate::app::autoplaylist::Condition;
 use crate::app::autoplaylist::{StrIdentifier, NumIdentifier, Identifier, StrOperator};
 use crate::gui::icons::*;
@@ -13,7 +13,7 @@ use crate::app::controller::MusicControllerStoreExt;
 pub fn AutoPlaylistView(controller: SyncStore<MusicController>) -> Element {
     let mut explorer_settings = use_signal(|| false);
     let base_path = Vec::new();
-    let mut tracks: Signal<Vec<usize>> = use_signal(|| Vec::new());
+    let mut tracks: Signal<Vec<TrackId>> = use_signal(|| Vec::new());
 
     rsx!{
         div { class: "tracksviewheader",
@@ -36,7 +36,7 @@ pub fn AutoPlaylistView(controller: SyncStore<MusicController>) -> Element {
             div {
                 class: "autoplaylist-menu",
                 button { 
-                    onclick: move |_| tracks.set(controller.autoplaylists().get(VIEW.read().autoplaylist.unwrap()).unwrap().read().conditions.qualify_tracks(&*controller.all_tracks().read())),
+                    onclick: move |_| tracks.set(controller.read().autoplaylist_tracks(VIEW.read().autoplaylist.unwrap())),
                     "Refresh"
                 }
 
@@ -60,7 +60,7 @@ pub fn AutoPlaylistView(controller: SyncStore<MusicController>) -> Element {
                         loading: "onvisible",
                     }
 
-                    span { "{controller.all_tracks().get(i).unwrap().read().title}" }
+                    span { "{controller.all_tracks().get(tracks.read()[i]).unwrap().read().title}" }
 
                     div { flex_grow: 1 }
 
@@ -263,6 +263,7 @@ pub fn StrSelect(controller: SyncStore<MusicController>, ident: StrIdentifier, p
             option { "Artist" }
             option { "Album" }
             option { "Genre" }
+            option { "Key" }
         }
     }
 }
@@ -290,6 +291,7 @@ pub fn IdentSelect(controller: SyncStore<MusicController>, ident: Identifier, pa
             option { "Artist" }
             option { "Album" }
             option { "Genre" }
+            option { "Key" }
             option { "Year" }
             option { "Length" }
             option { "Energy" }
@@ -329,6 +331,18 @@ pub fn AutoPlaylistOptions(
         div { class: "optionsbg", onclick: move |_| autoplaylist_options.set(None),
             div { class: "optionbox", style: "--width: 300px; --height: 50px;",
                 h3 { "{controller.autoplaylists().get(autoplaylist_options().unwrap()).unwrap().read().name}" }
+                button {
+                    onclick: move |_| {
+                        let autoplaylist = autoplaylist_options().unwrap();
+                        let name = controller.autoplaylists().get(autoplaylist).unwrap().read().name.clone();
+                        let tracks = controller.read().autoplaylist_tracks(autoplaylist);
+                        controller.write().start_radio_from(tracks, name);
+                        VIEW.write().open(View::Song);
+                    },
+                    img { src: RADIO_ICON }
+                    "Start radio"
+                }
+
                 button { onclick: move |_| renaming_autoplaylist.set(autoplaylist_options()),
                     img { src: EDIT_ICON }
                     "Rename autoplaylist"
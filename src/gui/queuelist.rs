@@ -1,5 +1,6 @@
 use super::icons::*;
 use super::{View, TRACKOPTION, VIEW};
+use crate::app::queue::EndBehaviour;
 use crate::app::MusicController;
 use dioxus::document::eval;
 use dioxus::prelude::*;
@@ -111,7 +112,8 @@ pub fn QueueList(controller: SyncStore<MusicController>) -> Element {
     // Callback for moving item into other place in queue
     let move_queue_item = move |_: Event<MouseData>| {
         if let Some(current) = current_dragging() {
-            controller.write().queues[selected_queue()].swap(current, hovering_over())
+            controller.write().queues[selected_queue()].swap(current, hovering_over());
+            controller.write().prepare_next();
         }
         current_dragging.set(None);
     };
@@ -189,8 +191,25 @@ pub fn QueueOptions(
 ) -> Element {
     rsx! {
         div { class: "optionsbg", onclick: move |_| queue_editing.set(None),
-            div { class: "optionbox", style: "--width: 300px; --height: 100px;",
+            div { class: "optionbox", style: "--width: 300px; --height: 140px;",
                 h3 { "{controller.queues().get(queue_editing().unwrap()).unwrap().read().queue_type}" }
+                div { class: "selectwrapper",
+                    select {
+                        class: "settingsselect",
+                        onclick: |e| e.stop_propagation(),
+                        onchange: move |e| {
+                            let behaviour = e.value().parse::<usize>().unwrap();
+                            controller.write().set_end_behaviour(queue_editing.unwrap(), EndBehaviour::ALL[behaviour]);
+                        },
+                        for (i, behaviour) in EndBehaviour::ALL.into_iter().enumerate() {
+                            option {
+                                value: "{i}",
+                                selected: controller.queues().get(queue_editing().unwrap()).unwrap().read().end_behaviour == behaviour,
+                                "At end: {behaviour}"
+                            }
+                        }
+                    }
+                }
                 button { onclick: move |_| controller.write().remove_queue(queue_editing.unwrap()),
                     img { src: REMOVE_ICON }
                     "Remove queue"
@@ -1,5 +1,7 @@
 use super::icons::*;
+use crate::app::audio::output_devices;
 use crate::app::MusicController;
+use crate::app::settings::{AnalysisMode, LibraryRoot, RadioMode, ReplayGainMode, SeedMode};
 use crate::app::controller::MusicControllerStoreExt;
 use dioxus::{prelude::*, stores::SyncStore};
 use log::info;
@@ -90,9 +92,33 @@ impl Display for SettingsMenu {
 
 #[component]
 fn AudioSettings(controller: SyncStore<MusicController>) -> Element {
+    let devices = use_hook(output_devices);
+
     rsx! {
         div { class: "settingsmenu",
             h2 { class: "settingsbar", "Audio" }
+            div { class: "settingbox",
+                span { "Output device" }
+                div { class: "selectwrapper",
+                    select {
+                        class: "settingsselect",
+                        onchange: move |e| {
+                            let device = Some(e.value()).filter(|d| d != "System default");
+                            controller.write().set_output_device(device);
+                        },
+                        option {
+                            selected: controller.settings().read().audio.output_device.is_none(),
+                            "System default"
+                        }
+                        for device in devices.iter() {
+                            option {
+                                selected: controller.settings().read().audio.output_device.as_ref() == Some(device),
+                                "{device}"
+                            }
+                        }
+                    }
+                }
+            }
             div { class: "settingbox",
                 SettingsInput {
                     label: "Volume",
@@ -101,6 +127,112 @@ fn AudioSettings(controller: SyncStore<MusicController>) -> Element {
                     oninput: move |e: Event<FormData>| controller.write().set_volume(e.parsed::<f32>().unwrap()),
                 }
             }
+            div { class: "settingbox",
+                SettingsInput {
+                    label: "Crossfade (seconds)",
+                    max: "12",
+                    value: "{controller.settings().read().audio.crossfade}",
+                    oninput: move |e: Event<FormData>| controller.write().set_crossfade(e.parsed::<f32>().unwrap()),
+                }
+            }
+            div { class: "settingbox",
+                span { "Loudness normalization" }
+                div { class: "selectwrapper",
+                    select {
+                        class: "settingsselect",
+                        onchange: move |e| {
+                            let mode = match e.value().as_str() {
+                                "Track" => ReplayGainMode::Track,
+                                "Album" => ReplayGainMode::Album,
+                                _ => ReplayGainMode::Off,
+                            };
+                            controller.write().set_replaygain(mode);
+                        },
+                        for mode in [ReplayGainMode::Off, ReplayGainMode::Track, ReplayGainMode::Album] {
+                            option {
+                                selected: controller.settings().read().audio.replaygain == mode,
+                                "{mode:?}"
+                            }
+                        }
+                    }
+                }
+            }
+            div { class: "settingbox",
+                SettingsInput {
+                    label: "Preamp (dB)",
+                    min: "-12",
+                    max: "12",
+                    value: "{controller.settings().read().audio.preamp}",
+                    oninput: move |e: Event<FormData>| controller.write().set_preamp(e.parsed::<f32>().unwrap()),
+                }
+            }
+            div { class: "settingbox",
+                span { "Prevent clipping" }
+                input {
+                    r#type: "checkbox",
+                    checked: controller.settings().read().audio.prevent_clipping,
+                    oninput: move |e| controller.write().set_prevent_clipping(e.value() == "true"),
+                }
+            }
+            div { class: "settingbox",
+                span { "Keep pitch when changing speed" }
+                input {
+                    r#type: "checkbox",
+                    checked: controller.settings().read().audio.preserve_pitch,
+                    oninput: move |e| controller.write().set_preserve_pitch(e.value() == "true"),
+                }
+            }
+            EqualizerSettings { controller }
+        }
+    }
+}
+
+#[component]
+fn EqualizerSettings(controller: SyncStore<MusicController>) -> Element {
+    let equalizer = controller.settings().read().audio.equalizer.clone();
+
+    rsx! {
+        h2 { class: "settingsbar", "Equalizer" }
+        div { class: "settingbox",
+            span { "Enable equalizer" }
+            input {
+                r#type: "checkbox",
+                checked: equalizer.enabled,
+                oninput: move |e| controller.write().set_equalizer_enabled(e.value() == "true"),
+            }
+        }
+        div { class: "settingbox",
+            span { "Preset" }
+            div { class: "selectwrapper",
+                select {
+                    class: "settingsselect",
+                    onchange: move |e| controller.write().load_equalizer_preset(&e.value()),
+                    for preset in equalizer.presets.iter() {
+                        option { selected: preset.name == equalizer.preset, "{preset.name}" }
+                    }
+                }
+            }
+        }
+        for (i, band) in equalizer.bands.iter().enumerate() {
+            SettingsInput {
+                label: "{band.frequency} Hz (dB)",
+                min: "-12",
+                max: "12",
+                value: "{band.gain}",
+                oninput: move |e: Event<FormData>| controller.write().set_equalizer_gain(i, e.parsed::<f32>().unwrap()),
+            }
+        }
+        div { class: "settingbox",
+            span { "Save as preset" }
+            input {
+                r#type: "text",
+                placeholder: "Preset name",
+                onchange: move |e| {
+                    if !e.value().is_empty() {
+                        controller.write().save_equalizer_preset(e.value());
+                    }
+                },
+            }
         }
     }
 }
@@ -138,6 +270,61 @@ fn RadioSettings(controller: SyncStore<MusicController>) -> Element {
                 value: "{controller.settings().read().radio.temp}",
             }
 
+            div { class: "settingbox",
+                span { "Radio mode" }
+                div { class: "selectwrapper",
+                    select {
+                        class: "settingsselect",
+                        onchange: move |e| {
+                            let mode = match e.value().as_str() {
+                                "Harmonic" => RadioMode::Harmonic,
+                                _ => RadioMode::Similar,
+                            };
+                            controller.write().set_radio_mode(mode);
+                        },
+                        for mode in [RadioMode::Similar, RadioMode::Harmonic] {
+                            option {
+                                selected: controller.settings().read().radio.mode == mode,
+                                "{mode:?}"
+                            }
+                        }
+                    }
+                }
+            }
+
+            if controller.settings().read().radio.mode == RadioMode::Harmonic {
+                SettingsInput {
+                    label: "BPM tolerance (%)",
+                    max: "20.0",
+                    oninput: move |e: Event<FormData>| {
+                        controller.write().settings.radio.bpm_tolerance = e.parsed::<f32>().unwrap();
+                    },
+                    value: "{controller.settings().read().radio.bpm_tolerance}",
+                }
+            }
+
+            div { class: "settingbox",
+                span { "Radio from many tracks" }
+                div { class: "selectwrapper",
+                    select {
+                        class: "settingsselect",
+                        onchange: move |e| {
+                            let mode = match e.value().as_str() {
+                                "Centroid" => SeedMode::Centroid,
+                                _ => SeedMode::Mixture,
+                            };
+                            controller.write().set_seed_mode(mode);
+                        },
+                        for mode in [SeedMode::Mixture, SeedMode::Centroid] {
+                            option {
+                                selected: controller.settings().read().radio.seed_mode == mode,
+                                "{mode:?}"
+                            }
+                        }
+                    }
+                }
+            }
+
             div { class: "settingbox",
                 span { "Track features to use" }
                 div { class: "selectwrapper",
@@ -222,12 +409,23 @@ fn RadioSettings(controller: SyncStore<MusicController>) -> Element {
                 },
                 value: "{controller.settings().read().radio.zcr_weight}",
             }
+
+            SettingsInput {
+                max: "2.0",
+                label: "Key weight",
+                oninput: move |e: Event<FormData>| {
+                    controller.write().settings.radio.key_weight = e.parsed::<f32>().unwrap();
+                },
+                value: "{controller.settings().read().radio.key_weight}",
+            }
         }
     }
 }
 
 #[component]
 fn SettingsInput(
+    #[props(default = "0".to_string())]
+    min: String,
     max: String,
     label: String,
     value: String,
@@ -238,8 +436,9 @@ fn SettingsInput(
             span { "{label}" }
             div { class: "settingsinput",
                 input {
-                    style: "--dist: calc({value} / {max} * 100.0%);",
+                    style: "--dist: calc(({value} - {min}) / ({max} - {min}) * 100.0%);",
                     r#type: "range",
+                    min,
                     max,
                     step: "0.01",
                     value: value.clone(),
@@ -254,18 +453,147 @@ fn SettingsInput(
 
 #[component]
 fn LibrarySettings(controller: SyncStore<MusicController>) -> Element {
+    let last_scan = match controller.last_scan()() {
+        Some(report) => format!("Last scan: {report}"),
+        None => String::from("Scanning library..."),
+    };
+    let libraries = controller.settings().read().libraries.clone();
+    let analysis = controller.analysis()();
+    let analysis_state = if analysis.paused {
+        "paused"
+    } else if analysis.in_progress() {
+        "running"
+    } else {
+        "done"
+    };
+
     rsx! {
         div { class: "settingsmenu",
             h2 { class: "settingsbar", "Library" }
 
+            for (i, root) in libraries.iter().enumerate() {
+                div { class: "libraryroot",
+                    div { class: "settingbox",
+                        span { "Music Folder" }
+                        input {
+                            r#type: "text",
+                            value: "{root.path}",
+                            onchange: move |e| edit_libraries(controller, |roots| roots[i].path = e.value()),
+                        }
+                        button {
+                            class: "settingboxbutton",
+                            onclick: move |_| edit_libraries(controller, |roots| { roots.remove(i); }),
+                            "Remove"
+                        }
+                    }
+                    div { class: "settingbox",
+                        span { "Include" }
+                        input {
+                            r#type: "text",
+                            placeholder: "Everything",
+                            value: root.include.join(", "),
+                            onchange: move |e| edit_libraries(controller, |roots| roots[i].include = parse_patterns(&e.value())),
+                        }
+                    }
+                    div { class: "settingbox",
+                        span { "Exclude" }
+                        input {
+                            r#type: "text",
+                            placeholder: "Samples, *.wav",
+                            value: root.exclude.join(", "),
+                            onchange: move |e| edit_libraries(controller, |roots| roots[i].exclude = parse_patterns(&e.value())),
+                        }
+                    }
+                }
+            }
+
             div { class: "settingbox",
-                span { "Music Directory" }
+                span { "Add music folder" }
                 input {
                     r#type: "text",
-                    value: "{controller.settings().read().directory}",
-                    onchange: move |e| controller.write().set_directory(e.value()),
+                    placeholder: "Folder path",
+                    onchange: move |e| {
+                        if !e.value().is_empty() {
+                            edit_libraries(controller, |roots| roots.push(LibraryRoot::new(e.value())));
+                        }
+                    },
+                }
+            }
+
+            div { class: "settingbox",
+                span { "Track analysis" }
+                div { class: "selectwrapper",
+                    select {
+                        class: "settingsselect",
+                        onchange: move |e| {
+                            let mode = match e.value().as_str() {
+                                "Segments" => AnalysisMode::Segments,
+                                "Whole" => AnalysisMode::Whole,
+                                _ => AnalysisMode::Window,
+                            };
+                            controller.write().set_analysis_mode(mode);
+                        },
+                        for mode in [AnalysisMode::Window, AnalysisMode::Segments, AnalysisMode::Whole] {
+                            option {
+                                selected: controller.settings().read().analysis.mode == mode,
+                                "{mode:?}"
+                            }
+                        }
+                    }
+                }
+            }
+
+            div { class: "settingbox",
+                span { "Analysis threads (0 for automatic)" }
+                input {
+                    r#type: "number",
+                    min: "0",
+                    value: "{controller.settings().read().analysis.threads}",
+                    onchange: move |e| {
+                        if let Ok(threads) = e.parsed::<usize>() {
+                            controller.write().settings.analysis.threads = threads;
+                            controller.write().settings.save();
+                        }
+                    },
+                }
+            }
+
+            div { class: "settingbox",
+                span { "Analysed {analysis.analysed} of {analysis.total} tracks ({analysis_state})" }
+                if !analysis.failed.is_empty() {
+                    button {
+                        class: "settingboxbutton",
+                        title: analysis.failed.iter().map(|(file, err)| format!("{file}: {err}")).collect::<Vec<_>>().join("\n"),
+                        onclick: move |_| controller.write().retry_failed_analysis(),
+                        "Retry {analysis.failed.len()} failed"
+                    }
+                }
+            }
+
+            div { class: "settingbox",
+                span { "{last_scan}" }
+                button {
+                    class: "settingboxbutton",
+                    onclick: move |_| controller.read().rescan_library(),
+                    "Rescan library"
                 }
             }
         }
     }
 }
+
+/// Changes the library folders, which saves them and rescans the library
+fn edit_libraries(mut controller: SyncStore<MusicController>, edit: impl FnOnce(&mut Vec<LibraryRoot>)) {
+    let mut libraries = controller.settings().read().libraries.clone();
+    edit(&mut libraries);
+    controller.write().set_libraries(libraries);
+}
+
+/// Splits a comma separated list of glob patterns
+fn parse_patterns(patterns: &str) -> Vec<String> {
+    patterns
+        .split(',')
+        .map(|pattern| pattern.trim().to_string())
+        .filter(|pattern| !pattern.is_empty())
+        .collect()
+}
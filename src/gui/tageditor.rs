@@ -49,6 +49,71 @@ pub fn TagEditor(controller: SyncStore<MusicController>) -> Element {
                         }
                     }
 
+                    div { class: "editorline",
+                        label { r#for: "albumartist", "Album Artist" }
+                        input {
+                            name: "albumartist",
+                            id: "albumartist",
+                            r#type: "text",
+                            value: "{tag.read().album_artist}",
+                            oninput: move |e| tag.write().album_artist = e.value(),
+                        }
+                    }
+
+                    div { class: "editorline",
+                        label { r#for: "trackno", "Track" }
+                        input {
+                            name: "trackno",
+                            id: "trackno",
+                            r#type: "number",
+                            min: "1",
+                            value: "{tag.read().trackno}",
+                            oninput: move |e| {
+                                if let Ok(trackno) = e.value().parse() {
+                                    tag.write().trackno = trackno;
+                                }
+                            },
+                        }
+                    }
+
+                    div { class: "editorline",
+                        label { r#for: "discno", "Disc" }
+                        input {
+                            name: "discno",
+                            id: "discno",
+                            r#type: "number",
+                            min: "1",
+                            value: "{tag.read().disc_no}",
+                            oninput: move |e| {
+                                if let Ok(disc_no) = e.value().parse() {
+                                    tag.write().disc_no = disc_no;
+                                }
+                            },
+                        }
+                    }
+
+                    div { class: "editorline",
+                        label { r#for: "year", "Year" }
+                        input {
+                            name: "year",
+                            id: "year",
+                            r#type: "text",
+                            value: "{tag.read().year}",
+                            oninput: move |e| tag.write().year = e.value().trim().to_string(),
+                        }
+                    }
+
+                    div { class: "editorline",
+                        label { r#for: "compilation", "Compilation" }
+                        input {
+                            name: "compilation",
+                            id: "compilation",
+                            r#type: "checkbox",
+                            checked: tag.read().compilation,
+                            oninput: move |e| tag.write().compilation = e.value() == "true",
+                        }
+                    }
+
                     div { class: "editormultiple",
                         div { class: "editormultipleline",
                             label { "Artists" }
@@ -1,4 +1,5 @@
-use crate::app::MusicController;
+use crate::app::blocklist::{BlockKind, BlockLevel};
+use crate::app::{MusicController, TrackId};
 use crate::gui::EDITING_TAG;
 
 use super::icons::*;
@@ -124,6 +125,14 @@ pub fn TrackOptions(controller: SyncStore<MusicController>) -> Element {
 
                     hr {}
 
+                    BlockOptions {
+                        controller,
+                        kind: BlockKind::Track,
+                        name: controller.all_tracks().get(track).unwrap().read().file.clone(),
+                    }
+
+                    hr {}
+
                     button {
                         onclick: move |_| {
                             let artist = controller.all_tracks().get(track).unwrap().read().artists[0].clone();
@@ -137,9 +146,9 @@ pub fn TrackOptions(controller: SyncStore<MusicController>) -> Element {
 
                     button {
                         onclick: move |_| {
-                            let album = controller.all_tracks().get(track).unwrap().read().album.clone();
+                            let album = controller.all_tracks().get(track).unwrap().read().album_id();
                             VIEW.write().open(View::Albums);
-                            VIEW.write().album = Some(album.clone());
+                            VIEW.write().album = Some(album);
                             TRACKOPTION.set(None);
                         },
                         img { src: ALBUM_ICON }
@@ -150,8 +159,8 @@ pub fn TrackOptions(controller: SyncStore<MusicController>) -> Element {
 
                     button {
                         onclick: move |_| {
-                            let index = TRACKOPTION().unwrap();
-                            EDITING_TAG.set(Some((index, controller.all_tracks().get(index).unwrap()())));
+                            let id = TRACKOPTION().unwrap();
+                            EDITING_TAG.set(Some((id, controller.all_tracks().get(id).unwrap()())));
                         },
                         img { src: EDIT_ICON }
                         "Edit tags"
@@ -170,7 +179,7 @@ pub fn TrackOptions(controller: SyncStore<MusicController>) -> Element {
 }
 
 #[component]
-pub fn TrackOptionsQueueView(controller: SyncStore<MusicController>, track: usize) -> Element {
+pub fn TrackOptionsQueueView(controller: SyncStore<MusicController>, track: TrackId) -> Element {
     rsx! {
         button {
             img { src: INFO_ICON }
@@ -180,21 +189,21 @@ pub fn TrackOptionsQueueView(controller: SyncStore<MusicController>, track: usiz
 }
 
 #[component]
-pub fn TrackOptionsTrackView(controller: SyncStore<MusicController>, track: usize) -> Element {
+pub fn TrackOptionsTrackView(controller: SyncStore<MusicController>, track: TrackId) -> Element {
     rsx! {
         span {}
     }
 }
 
 #[component]
-pub fn TrackOptionsExplorerView(controller: SyncStore<MusicController>, track: usize) -> Element {
+pub fn TrackOptionsExplorerView(controller: SyncStore<MusicController>, track: TrackId) -> Element {
     rsx! {
         span {}
     }
 }
 
 #[component]
-pub fn TrackOptionsPlaylistsView(controller: SyncStore<MusicController>, track: usize) -> Element {
+pub fn TrackOptionsPlaylistsView(controller: SyncStore<MusicController>, track: TrackId) -> Element {
     rsx! {
         button {
             onclick: move |_| {
@@ -208,3 +217,44 @@ pub fn TrackOptionsPlaylistsView(controller: SyncStore<MusicController>, track:
         }
     }
 }
+
+/// Buttons for playing a track, artist or genre less often, or never playing it in radio,
+/// shuffle and autoplaylists
+#[component]
+pub fn BlockOptions(controller: SyncStore<MusicController>, kind: BlockKind, name: String) -> Element {
+    let level = controller.blocklist().read().get(kind, &name);
+    let what = match kind {
+        BlockKind::Track => "track",
+        BlockKind::Artist => "artist",
+        BlockKind::Genre => "genre",
+    };
+    let less_often_name = name.clone();
+
+    rsx! {
+        button {
+            onclick: move |_| {
+                let new_level = if level == Some(BlockLevel::LessOften) { None } else { Some(BlockLevel::LessOften) };
+                controller.write().set_block(kind, &less_often_name, new_level);
+            },
+            img { src: DOWN_ICON }
+            if level == Some(BlockLevel::LessOften) {
+                "Play this {what} as usual"
+            } else {
+                "Play this {what} less often"
+            }
+        }
+
+        button {
+            onclick: move |_| {
+                let new_level = if level == Some(BlockLevel::Never) { None } else { Some(BlockLevel::Never) };
+                controller.write().set_block(kind, &name, new_level);
+            },
+            img { src: REMOVE_ICON }
+            if level == Some(BlockLevel::Never) {
+                "Allow this {what} again"
+            } else {
+                "Never play this {what}"
+            }
+        }
+    }
+}
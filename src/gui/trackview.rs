@@ -1,4 +1,5 @@
 use super::{View, TRACKOPTION, VIEW};
+use crate::analysis::key_name;
 use crate::app::{MusicController, Track};
 use crate::app::controller::MusicControllerStoreExt;
 use crate::gui::icons::*;
@@ -26,6 +27,13 @@ pub fn TrackView(controller: SyncStore<MusicController>) -> Element {
         }
     };
 
+    // Detected key of the current track, if analysis found one
+    let current_key = move || {
+        controller.track_info().read().get(&current_track_idx())
+            .filter(|info| info.key_confidence > 0.0)
+            .map(|info| key_name(info.key))
+    };
+
     // Skip to next song
     let skip = move |_: Event<MouseData>| {
         controller.write().skip();
@@ -45,7 +53,9 @@ pub fn TrackView(controller: SyncStore<MusicController>) -> Element {
         loop {
             time::sleep(Duration::from_secs_f64(0.25)).await;
             if !progress_held() && controller.playing()() {
-                *controller.progress_secs().write() += 0.25;
+                // Progress is the position in the file, so it moves faster at higher rates
+                let rate = controller.read().current_rate() as f64;
+                *controller.progress_secs().write() += 0.25 * rate;
                 *progress.write() = *controller.progress_secs().read();
             }
         }
@@ -95,9 +105,7 @@ pub fn TrackView(controller: SyncStore<MusicController>) -> Element {
                     class: "albumspecifier",
                     // Open album view on click
                     onclick: move |_| {
-                        VIEW.write().album = Some(
-                            current_track().album.to_string(),
-                        );
+                        VIEW.write().album = Some(current_track().album_id());
                         VIEW.write().open(View::Albums);
                     },
                     "{current_track().album}"
@@ -117,6 +125,10 @@ pub fn TrackView(controller: SyncStore<MusicController>) -> Element {
                     }
                 }
 
+                if let Some(key) = current_key() {
+                    span { class: "keyspecifier", "{key}" }
+                }
+
                 // Track progress information
                 div { class: "progressrow",
                     span { class: "songprogress", "{format_seconds(progress())}" }
@@ -174,12 +186,29 @@ pub fn TrackView(controller: SyncStore<MusicController>) -> Element {
                         background_image: if controller.shuffle()() { "url({SHUFFLE_ON_ICON})" } else { "url({SHUFFLE_ICON})" },
                         onclick: move |_| controller.write().toggle_shuffle(),
                     }
+
+                    button {
+                        class: "ratebutton",
+                        onclick: move |_| {
+                            let rate = controller.read().current_rate();
+                            controller.write().set_rate(next_rate(rate));
+                        },
+                        "{controller.read().current_rate()}x"
+                    }
                 }
             }
         }
     }
 }
 
+/// Playback rates the rate button cycles through
+const RATES: [f32; 7] = [0.5, 0.75, 1.0, 1.25, 1.5, 1.75, 2.0];
+
+/// The next rate up from the given one, wrapping back around to the slowest
+fn next_rate(rate: f32) -> f32 {
+    RATES.into_iter().find(|r| *r > rate + 0.01).unwrap_or(RATES[0])
+}
+
 fn format_seconds(seconds: f64) -> String {
     let seconds = seconds as i64;
     let s = seconds % 60;
@@ -20,7 +20,7 @@ use log::info;
 use dioxus::document::eval;
 use app::{
     track::get_track_image,
-    MusicController,
+    MusicController, TrackId,
 };
 
 use gui::*;
@@ -121,7 +121,7 @@ fn init() {
 
 #[component]
 fn SetUpRoute() -> Element {
-    use app::settings::Settings;
+    use app::settings::{LibraryRoot, Settings};
     let mut set_up = use_signal(Settings::exists);
     #[allow(unused_mut)]
     let mut dir = use_signal(Settings::default_audio_dir);
@@ -158,7 +158,7 @@ fn SetUpRoute() -> Element {
                 height: "50px",
                 onclick: move |_| {
                     Settings {
-                        directory: dir(),
+                        libraries: vec![LibraryRoot::new(dir())],
                         volume: 1.0,
                         ..Default::default()
                     }
@@ -173,8 +173,6 @@ fn SetUpRoute() -> Element {
 
 #[component]
 fn App() -> Element {
-    let loading_track_weights = use_signal(|| 0);
-    let tracks_count = use_signal(|| 0);
     let controller = use_store_sync(|| MusicController::empty());
     *gui::CONTROLLER.lock().unwrap() = Some(controller);
     
@@ -237,7 +235,7 @@ fn App() -> Element {
 
         info!("requested track image {:?}, thread {:?}", request.uri(), std::thread::current().id());
 
-        let id = if let Ok(id) = request.uri().path().replace("/trackimage/", "").parse() {
+        let id = if let Ok(id) = request.uri().path().replace("/trackimage/", "").parse::<TrackId>() {
             id
         } else {
             responder.respond(r);
@@ -290,11 +288,20 @@ fn App() -> Element {
             "#
         }
 
-        div {
-            class: "loadingpopupbg",
-            hidden: loading_track_weights() == tracks_count(),
+        if controller.analysis().read().in_progress() {
             div { class: "loadingpopup",
-                "Loading weights for track {loading_track_weights} out of {tracks_count} "
+                span { "Analysing track {controller.analysis().read().analysed} out of {controller.analysis().read().total}" }
+                button {
+                    onclick: move |_| {
+                        let paused = controller.analysis().read().paused;
+                        controller.write().pause_analysis(!paused);
+                    },
+                    if controller.analysis().read().paused { "Resume" } else { "Pause" }
+                }
+                button {
+                    onclick: move |_| controller.write().cancel_analysis(),
+                    "Cancel"
+                }
             }
         }
 